@@ -0,0 +1,129 @@
+//! Measures how fast packets can be written to a session while history is
+//! queried from it, as the ingest thread and `/history` requests would.
+//!
+//! Run with `cargo bench --bench store`
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use openmct_pico_pilot_ingest::{
+    store::{SessionData, SessionWriter},
+    telemetry::{TelemetryPacket, TelemetryValue},
+};
+
+/// Packets written in each run, 10 minutes of telemetry at 1 kHz
+const PACKETS: u64 = 600_000;
+/// The values in each packet, about as many as the flight computer sends
+const KEYS: usize = 24;
+/// Threads querying the whole history over and over while packets are written
+const READERS: usize = 4;
+
+fn main() {
+    let keys = (0..KEYS)
+        .map(|key| format!("bench.value{}", key))
+        .collect::<Vec<_>>();
+
+    println!(
+        "Writing {} packets of {} values with {} history readers",
+        PACKETS, KEYS, READERS
+    );
+
+    run(&keys, 0);
+    run(&keys, READERS);
+}
+
+fn run(keys: &[String], readers: usize) {
+    let data = Arc::new(SessionData::default());
+    let mut writer = SessionWriter::new(data.clone());
+
+    let done = Arc::new(AtomicBool::new(false));
+    let queries = Arc::new(AtomicU64::new(0));
+    let packets_read = Arc::new(AtomicU64::new(0));
+    let slowest_query = Arc::new(AtomicU64::new(0));
+
+    let reader_threads = (0..readers)
+        .map(|_| {
+            let data = data.clone();
+            let done = done.clone();
+            let queries = queries.clone();
+            let packets_read = packets_read.clone();
+            let slowest_query = slowest_query.clone();
+            let key = keys[0].clone();
+
+            thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    let started = Instant::now();
+
+                    let read = data
+                        .snapshot()
+                        .range(..)
+                        .filter(|packet| packet.values.contains_key(&key))
+                        .count();
+
+                    queries.fetch_add(1, Ordering::Relaxed);
+                    packets_read.fetch_add(read as u64, Ordering::Relaxed);
+                    slowest_query
+                        .fetch_max(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+
+                    if read == 0 {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut slowest_write = Duration::ZERO;
+    let started = Instant::now();
+
+    for packet in 0..PACKETS {
+        let packet = TelemetryPacket {
+            running_us: packet * 1000,
+            values: keys
+                .iter()
+                .map(|key| (key.clone(), TelemetryValue::Float(packet as f64)))
+                .collect(),
+            raw: BTreeMap::new(),
+        };
+
+        let write_started = Instant::now();
+        writer.insert(packet);
+        slowest_write = slowest_write.max(write_started.elapsed());
+    }
+    writer.flush();
+
+    let elapsed = started.elapsed();
+
+    done.store(true, Ordering::Relaxed);
+    for thread in reader_threads {
+        thread.join().expect("history reader panicked");
+    }
+
+    println!();
+    println!("{} readers:", readers);
+    println!(
+        "  wrote {} packets in {:.2?}, {:.0} packets/s, slowest write {:.2?}",
+        PACKETS,
+        elapsed,
+        PACKETS as f64 / elapsed.as_secs_f64(),
+        slowest_write
+    );
+
+    if readers > 0 {
+        println!(
+            "  ran {} history queries reading {} packets, slowest query {:.2?}",
+            queries.load(Ordering::Relaxed),
+            packets_read.load(Ordering::Relaxed),
+            Duration::from_micros(slowest_query.load(Ordering::Relaxed))
+        );
+    }
+
+    assert_eq!(data.snapshot().len() as u64, PACKETS);
+}
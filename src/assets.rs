@@ -0,0 +1,123 @@
+use std::{
+    borrow::Cow,
+    io,
+    path::{Component, Path},
+};
+
+#[cfg(not(feature = "embed-web"))]
+use {crate::config::config, std::time::UNIX_EPOCH};
+
+#[cfg(feature = "embed-web")]
+use include_dir::{include_dir, Dir};
+
+#[cfg(feature = "embed-web")]
+static WEB: Dir = include_dir!("web");
+
+/// A file of the web app, ready to be served
+pub struct Asset {
+    pub contents: Cow<'static, [u8]>,
+    pub mime: &'static str,
+    /// changes whenever the contents of the file change
+    pub etag: String,
+    pub cache_control: &'static str,
+}
+
+/// Loads a file of the web app by its path relative to the `web` directory,
+/// returning `None` if it does not exist.
+pub async fn load(path: &str) -> io::Result<Option<Asset>> {
+    let path = path.trim_start_matches('/');
+    let path = if path.is_empty() || path.ends_with('/') {
+        format!("{}index.html", path)
+    } else {
+        path.to_string()
+    };
+
+    // Never allow escaping the web directory
+    if !Path::new(&path)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Ok(None);
+    }
+
+    let (contents, etag) = match read(&path).await? {
+        Some(file) => file,
+        None => return Ok(None),
+    };
+
+    let extension = Path::new(&path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("");
+
+    Ok(Some(Asset {
+        contents,
+        mime: mime_type(extension),
+        etag,
+        // Open MCT is vendored and only changes when it is upgraded, while the
+        // app itself should be revalidated on every load
+        cache_control: if path.starts_with("openmct/") {
+            "public, max-age=86400"
+        } else {
+            "no-cache"
+        },
+    }))
+}
+
+#[cfg(feature = "embed-web")]
+async fn read(path: &str) -> io::Result<Option<(Cow<'static, [u8]>, String)>> {
+    Ok(WEB.get_file(path).map(|file| {
+        let contents = file.contents();
+
+        // FNV-1a, as there is no modification time to go off of
+        let hash = contents.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        });
+
+        (Cow::Borrowed(contents), format!("\"{:016x}\"", hash))
+    }))
+}
+
+#[cfg(not(feature = "embed-web"))]
+async fn read(path: &str) -> io::Result<Option<(Cow<'static, [u8]>, String)>> {
+    let path = config().paths.web.join(path);
+
+    let metadata = match async_std::fs::metadata(&path).await {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|modified| modified.as_millis())
+        .unwrap_or(0);
+
+    let contents = async_std::fs::read(&path).await?;
+
+    Ok(Some((
+        Cow::Owned(contents),
+        format!("\"{:x}-{:x}\"", metadata.len(), modified),
+    )))
+}
+
+fn mime_type(extension: &str) -> &'static str {
+    match extension {
+        "html" => "text/html;charset=utf-8",
+        "js" => "application/javascript;charset=utf-8",
+        "css" => "text/css;charset=utf-8",
+        "json" | "map" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "ts" | "txt" => "text/plain;charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,189 @@
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use async_std::{future::timeout, task};
+use color_eyre::eyre::{eyre, Context};
+use log::{info, warn};
+use openmct_pico_pilot_ingest::{
+    config::{Cli, QueuePolicy},
+    ingest::{ingest, IngestEvent, IngestLock, LinkStats, LINK_STATS},
+    queue,
+    recording::{RecordingHeader, RecordingWriter},
+    serial::get_serial_ports,
+    session::SessionMetadata,
+    setup,
+    source::{OpenedSource, SourceKind, SourceParameters},
+};
+use simplelog::TerminalMode;
+use structopt::StructOpt;
+
+/// How often statistics about the link are printed
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// Records telemetry from a flight computer without running the web server
+#[derive(Debug, StructOpt)]
+struct Args {
+    /// Kind of source to record from: serial, file, tcp, udp, stdin or relay
+    #[structopt(long, short, default_value = "serial")]
+    source: SourceKind,
+    /// Serial port of the device, or `simulator` [default: the first Pico found]
+    #[structopt(long, short)]
+    port: Option<String>,
+    /// File to read packets from, for file sources
+    #[structopt(long, parse(from_os_str))]
+    path: Option<PathBuf>,
+    /// Address to connect to for TCP and relay sources, or to listen on for UDP sources
+    #[structopt(long)]
+    address: Option<String>,
+    /// Wait for the device to connect to `address`, for TCP sources
+    #[structopt(long)]
+    listen: bool,
+    /// File to write the recording to [default: recording-<unix millis>.cbor]
+    #[structopt(long, short, parse(from_os_str))]
+    output: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    cli: Cli,
+}
+
+#[async_std::main]
+async fn main() -> color_eyre::Result<()> {
+    color_eyre::install()?;
+
+    let args = Args::from_args();
+
+    if setup(&args.cli, module_path!(), TerminalMode::Mixed)?.is_none() {
+        return Ok(());
+    }
+
+    let mut parameters = SourceParameters {
+        source: args.source,
+        port: args.port,
+        path: args.path,
+        address: args.address,
+        listen: args.listen,
+        ..SourceParameters::default()
+    };
+
+    if parameters.source == SourceKind::Serial && parameters.port.is_none() {
+        let pico = get_serial_ports()
+            .await?
+            .next()
+            .ok_or_else(|| eyre!("No Pico found, pass the device with --port"))?;
+
+        parameters.port = Some(pico.name);
+    }
+
+    let lock = IngestLock::acquire().ok_or_else(|| eyre!("Already recording a device"))?;
+
+    let OpenedSource {
+        source,
+        name: port_name,
+        product,
+        serial_number,
+    } = parameters.open().await?;
+
+    let session = SessionMetadata::new(port_name.clone(), product, serial_number);
+
+    info!("Connected to device {}", port_name);
+
+    let output = args
+        .output
+        .unwrap_or_else(|| PathBuf::from(format!("recording-{}.cbor", session.started)));
+
+    let recording = RecordingWriter::create(
+        &output,
+        &RecordingHeader::new(
+            session.port.clone(),
+            session
+                .product
+                .map(|product| product.description.to_string()),
+            session.serial_number.clone(),
+            session.started,
+        ),
+    )
+    .wrap_err_with(|| format!("Failed to create recording {}", output.display()))?;
+
+    info!("Recording to {}", output.display());
+
+    ctrlc::set_handler(|| STOP.store(true, Ordering::SeqCst))
+        .wrap_err("Failed to install Ctrl-C handler")?;
+
+    // Nothing reads the telemetry, only the occasional firmware and schema events
+    let (tx, rx) = queue::bounded(1, QueuePolicy::DropOldest);
+
+    let ingest_task = {
+        let port_name = port_name.clone();
+
+        task::spawn_blocking(move || ingest(lock, tx, port_name, session, source, Some(recording)))
+    };
+
+    let started = Instant::now();
+    let mut last_report = Instant::now();
+
+    while !STOP.load(Ordering::SeqCst) {
+        // Wake up regularly to notice Ctrl-C and print statistics, even when the
+        // device is silent
+        match timeout(Duration::from_millis(250), rx.recv()).await {
+            Ok(Ok(IngestEvent::Firmware(firmware))) => {
+                info!("Recording {} {}", firmware.name, firmware.version);
+            }
+            Ok(Ok(IngestEvent::Schema(report))) if !report.compatible => {
+                warn!("Packets do not match the telemetry dictionary");
+            }
+            Ok(Ok(_)) | Err(_) => {}
+            // The ingest thread stopped on its own, the device was disconnected
+            Ok(Err(_)) => break,
+        }
+
+        if last_report.elapsed() >= STATS_INTERVAL {
+            let stats = link_stats(&port_name).await;
+
+            info!(
+                "{:.0}s: {} packets ({:.1} p/s, {} bytes), {} malformed, {} dropped, {} reconnects",
+                started.elapsed().as_secs_f64(),
+                stats.packets,
+                stats.rate,
+                stats.bytes,
+                stats.malformed,
+                stats.dropped,
+                stats.reconnects
+            );
+
+            last_report = Instant::now();
+        }
+    }
+
+    info!("Stopping recording");
+
+    // Closing the channel stops the ingest thread, which flushes the recording
+    drop(rx);
+    ingest_task
+        .await
+        .wrap_err_with(|| format!("Failed to finish recording {}", output.display()))?;
+
+    let stats = link_stats(&port_name).await;
+    info!(
+        "Recorded {} packets to {} ({} malformed, {} dropped)",
+        stats.packets,
+        output.display(),
+        stats.malformed,
+        stats.dropped
+    );
+
+    Ok(())
+}
+
+async fn link_stats(port_name: &str) -> LinkStats {
+    LINK_STATS
+        .read()
+        .await
+        .get(port_name)
+        .cloned()
+        .unwrap_or_default()
+}
@@ -0,0 +1,381 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{eyre, Context};
+use log::warn;
+use openmct_pico_pilot_ingest::{
+    config::Cli,
+    firmware::FirmwareInfo,
+    ingest::{DecodedPacket, PacketDecoder},
+    recording::{RecordingReader, RecordingWriter},
+    schema::SchemaReport,
+    setup,
+    telemetry::{TelemetryDictionary, TelemetryPacket, TelemetryValue},
+};
+use serde_cbor::{error::Category, Value};
+use simplelog::TerminalMode;
+use structopt::StructOpt;
+
+/// Inspects and converts recordings made by the recorder or the server
+#[derive(Debug, StructOpt)]
+struct Args {
+    #[structopt(subcommand)]
+    command: Command,
+
+    #[structopt(flatten)]
+    cli: Cli,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+enum Command {
+    /// Print the duration, packet count, value ranges and errors of a recording
+    Summary {
+        #[structopt(flatten)]
+        input: Input,
+    },
+    /// Dump the decoded packets as JSON, one packet per line
+    Json {
+        #[structopt(flatten)]
+        input: Input,
+        #[structopt(flatten)]
+        output: Output,
+    },
+    /// Convert the decoded packets to CSV, one column per telemetry point
+    Csv {
+        #[structopt(flatten)]
+        input: Input,
+        #[structopt(flatten)]
+        output: Output,
+    },
+    /// Write the packets in a range to a new recording
+    Slice {
+        #[structopt(flatten)]
+        input: Input,
+        /// Recording to write
+        #[structopt(long, short, parse(from_os_str))]
+        output: PathBuf,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+struct Input {
+    /// Recording to read
+    #[structopt(parse(from_os_str))]
+    recording: PathBuf,
+    /// Skip packets before this `running_us`
+    #[structopt(long)]
+    from: Option<u64>,
+    /// Skip packets after this `running_us`
+    #[structopt(long)]
+    to: Option<u64>,
+}
+
+#[derive(Debug, StructOpt)]
+struct Output {
+    /// File to write to [default: stdout]
+    #[structopt(long, short, parse(from_os_str))]
+    output: Option<PathBuf>,
+}
+
+impl Output {
+    fn open(&self) -> io::Result<Box<dyn Write>> {
+        Ok(match &self.output {
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(BufWriter::new(io::stdout())),
+        })
+    }
+}
+
+fn main() -> color_eyre::Result<()> {
+    color_eyre::install()?;
+
+    let args = Args::from_args();
+
+    // Logs go to stderr so that they never mix with converted output
+    if setup(&args.cli, module_path!(), TerminalMode::Stderr)?.is_none() {
+        return Ok(());
+    }
+
+    match args.command {
+        Command::Summary { input } => summary(&input),
+        Command::Json { input, output } => {
+            let mut output = output.open()?;
+
+            replay(&input, |event, _| {
+                if let Replayed::Telemetry(packet, _) = event {
+                    serde_json::to_writer(&mut output, &packet)?;
+                    writeln!(output)?;
+                }
+
+                Ok(())
+            })?;
+
+            Ok(output.flush()?)
+        }
+        Command::Csv { input, output } => csv(&input, output.open()?),
+        Command::Slice { input, output } => slice(&input, &output),
+    }
+}
+
+/// A packet read back from a recording, along with the packet as it was recorded
+enum Replayed<'v> {
+    Telemetry(TelemetryPacket, &'v Value),
+    Firmware(FirmwareInfo, &'v Value),
+    /// a packet that could not be decoded
+    Invalid(&'v Value),
+}
+
+#[derive(Default)]
+struct ReplayStats {
+    malformed: u64,
+    truncated: bool,
+}
+
+/// Decodes every packet of a recording the same way the server does, passing the
+/// ones in range to `handle` along with the dictionary they were decoded with
+fn replay(
+    input: &Input,
+    mut handle: impl FnMut(Replayed, &'static TelemetryDictionary) -> color_eyre::Result<()>,
+) -> color_eyre::Result<ReplayStats> {
+    let recording = RecordingReader::open(&input.recording)
+        .wrap_err_with(|| format!("Failed to open {}", input.recording.display()))?;
+
+    let mut decoder = PacketDecoder::new(TelemetryDictionary::unidentified());
+    let mut stats = ReplayStats::default();
+    let mut last_error = None;
+
+    for packet in recording {
+        let value = match packet {
+            Ok(value) => value,
+            Err(e) => match e.classify() {
+                Category::Syntax | Category::Data if last_error != Some(e.offset()) => {
+                    warn!("Failed to parse packet at {}: {}", e.offset(), e);
+
+                    stats.malformed += 1;
+                    last_error = Some(e.offset());
+
+                    continue;
+                }
+                // A recording that was not finished cleanly ends part way
+                // through a packet
+                _ => {
+                    stats.truncated = true;
+
+                    break;
+                }
+            },
+        };
+
+        let replayed = match decoder.decode(&value) {
+            DecodedPacket::Telemetry(packet) => {
+                let in_range = input.from.map_or(true, |from| packet.running_us >= from)
+                    && input.to.map_or(true, |to| packet.running_us <= to);
+
+                if !in_range {
+                    continue;
+                }
+
+                Replayed::Telemetry(packet, &value)
+            }
+            DecodedPacket::Firmware(firmware) => Replayed::Firmware(firmware, &value),
+            DecodedPacket::InvalidFirmware(_) | DecodedPacket::MissingTimestamp => {
+                Replayed::Invalid(&value)
+            }
+        };
+
+        handle(replayed, decoder.dictionary())?;
+    }
+
+    Ok(stats)
+}
+
+fn summary(input: &Input) -> color_eyre::Result<()> {
+    let header = RecordingReader::open(&input.recording)
+        .wrap_err_with(|| format!("Failed to open {}", input.recording.display()))?
+        .header;
+
+    let mut packets = 0u64;
+    let mut invalid = 0u64;
+    let mut range: Option<(u64, u64)> = None;
+    let mut values = BTreeMap::<String, (f64, f64, u64)>::new();
+    let mut firmware = Vec::new();
+    let mut report = SchemaReport::default();
+
+    let stats = replay(input, |event, dictionary| {
+        match event {
+            Replayed::Telemetry(packet, value) => {
+                packets += 1;
+                report.check(dictionary, value);
+
+                let running_us = packet.running_us;
+                range = Some(match range {
+                    Some((first, last)) => (first.min(running_us), last.max(running_us)),
+                    None => (running_us, running_us),
+                });
+
+                for (key, value) in &packet.values {
+                    let value = match *value {
+                        TelemetryValue::Boolean(value) => value as u8 as f64,
+                        TelemetryValue::Integer(value) => value as f64,
+                        TelemetryValue::Float(value) => value,
+                    };
+
+                    let (min, max, count) =
+                        values
+                            .entry(key.clone())
+                            .or_insert((f64::INFINITY, f64::NEG_INFINITY, 0));
+
+                    *min = min.min(value);
+                    *max = max.max(value);
+                    *count += 1;
+                }
+            }
+            Replayed::Firmware(info, _) => {
+                // Packets so far were checked against the wrong dictionary
+                report = SchemaReport::default();
+
+                firmware.push(info);
+            }
+            Replayed::Invalid(value) => {
+                invalid += 1;
+                report.check(dictionary, value);
+            }
+        }
+
+        Ok(())
+    })?;
+
+    println!("Recording:  {}", input.recording.display());
+    println!(
+        "Device:     {} ({})",
+        header.port,
+        header.product.as_deref().unwrap_or("unknown product")
+    );
+    if let Some(serial_number) = &header.serial_number {
+        println!("Serial:     {}", serial_number);
+    }
+    println!("Started:    {} ms since the UNIX epoch", header.started);
+    for info in &firmware {
+        println!(
+            "Firmware:   {} {} ({}), schema version {}",
+            info.name, info.version, info.git_hash, info.schema_version
+        );
+    }
+    match range {
+        Some((first, last)) => println!(
+            "Duration:   {:.3} s ({} us to {} us)",
+            (last - first) as f64 / 1_000_000.0,
+            first,
+            last
+        ),
+        None => println!("Duration:   no packets"),
+    }
+    println!("Packets:    {}", packets);
+    println!("Malformed:  {}", stats.malformed);
+    println!("Undecoded:  {}", invalid);
+    if stats.truncated {
+        println!("Truncated:  the recording ends part way through a packet");
+    }
+    println!(
+        "Schema:     {}",
+        if report.compatible {
+            "compatible"
+        } else {
+            "incompatible"
+        }
+    );
+    for (key, issue) in &report.mismatched {
+        println!(
+            "            {} expected {}, found {} ({} times)",
+            key,
+            issue.expected.format(),
+            issue.found,
+            issue.count
+        );
+    }
+    for (key, count) in &report.missing {
+        println!("            {} missing from {} packets", key, count);
+    }
+
+    println!();
+    println!(
+        "{:<24} {:>14} {:>14} {:>10}",
+        "Key", "Min", "Max", "Samples"
+    );
+    for (key, (min, max, count)) in &values {
+        println!("{:<24} {:>14.6} {:>14.6} {:>10}", key, min, max, count);
+    }
+
+    Ok(())
+}
+
+fn csv(input: &Input, mut output: Box<dyn Write>) -> color_eyre::Result<()> {
+    // The columns are only known once every packet has been seen, as the
+    // dictionary changes when the firmware identifies itself
+    let mut keys = BTreeSet::new();
+
+    replay(input, |event, _| {
+        if let Replayed::Telemetry(packet, _) = event {
+            keys.extend(packet.values.into_iter().map(|(key, _)| key));
+        }
+
+        Ok(())
+    })?;
+
+    write!(output, "running_us")?;
+    for key in &keys {
+        write!(output, ",{}", key)?;
+    }
+    writeln!(output)?;
+
+    replay(input, |event, _| {
+        if let Replayed::Telemetry(packet, _) = event {
+            write!(output, "{}", packet.running_us)?;
+
+            for key in &keys {
+                match packet.values.get(key) {
+                    Some(TelemetryValue::Boolean(value)) => write!(output, ",{}", value)?,
+                    Some(TelemetryValue::Integer(value)) => write!(output, ",{}", value)?,
+                    Some(TelemetryValue::Float(value)) => write!(output, ",{}", value)?,
+                    None => write!(output, ",")?,
+                }
+            }
+
+            writeln!(output)?;
+        }
+
+        Ok(())
+    })?;
+
+    Ok(output.flush()?)
+}
+
+fn slice(input: &Input, output: &Path) -> color_eyre::Result<()> {
+    let header = RecordingReader::open(&input.recording)
+        .wrap_err_with(|| format!("Failed to open {}", input.recording.display()))?
+        .header;
+
+    if input.from.is_none() && input.to.is_none() {
+        return Err(eyre!("Give the range to slice with --from and/or --to"));
+    }
+
+    let mut writer = RecordingWriter::create(output, &header)
+        .wrap_err_with(|| format!("Failed to create {}", output.display()))?;
+
+    replay(input, |event, _| {
+        match event {
+            // Firmware identification is kept so the slice decodes the same way
+            Replayed::Telemetry(_, value) | Replayed::Firmware(_, value) => writer.write(value)?,
+            Replayed::Invalid(_) => {}
+        }
+
+        Ok(())
+    })?;
+
+    Ok(writer.finish()?)
+}
@@ -0,0 +1,114 @@
+use std::{collections::BTreeMap, fs, io};
+
+use lazy_static::lazy_static;
+use serde::Deserialize;
+
+use crate::config::config;
+
+lazy_static! {
+    /// Calibration definitions keyed by the telemetry key they apply to
+    pub static ref CALIBRATIONS: BTreeMap<String, CalibrationDefinition> =
+        load_calibrations().expect("Failed to load calibration definitions");
+}
+
+/// Converts the raw values sent by the firmware for a telemetry point into
+/// engineering units
+#[derive(Debug, Deserialize)]
+pub struct CalibrationDefinition {
+    /// the engineering units produced by the calibration, if they differ from
+    /// the units of the raw value
+    pub units: Option<String>,
+    pub calibration: Calibration,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Calibration {
+    /// `raw * scale + offset`
+    Linear { scale: f64, offset: f64 },
+    /// `c[0] + c[1] * raw + c[2] * raw^2 + ...`
+    Polynomial { coefficients: Vec<f64> },
+    /// linear interpolation between `[raw, engineering]` points, sorted by raw
+    /// value. Values outside of the table are clamped to its ends.
+    Table { points: Vec<(f64, f64)> },
+}
+
+impl Calibration {
+    pub fn apply(&self, raw: f64) -> f64 {
+        match self {
+            Calibration::Linear { scale, offset } => raw * scale + offset,
+            // Horner's method
+            Calibration::Polynomial { coefficients } => coefficients
+                .iter()
+                .rev()
+                .fold(0.0, |acc, coefficient| acc * raw + coefficient),
+            Calibration::Table { points } => {
+                let (first, last) = match (points.first(), points.last()) {
+                    (Some(first), Some(last)) => (first, last),
+                    _ => return raw,
+                };
+
+                if raw <= first.0 {
+                    return first.1;
+                }
+
+                if raw >= last.0 {
+                    return last.1;
+                }
+
+                points
+                    .windows(2)
+                    .find(|window| raw <= window[1].0)
+                    .map(|window| {
+                        let ((x0, y0), (x1, y1)) = (window[0], window[1]);
+
+                        y0 + (raw - x0) * (y1 - y0) / (x1 - x0)
+                    })
+                    .unwrap_or(last.1)
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            Calibration::Linear { .. } => Ok(()),
+            Calibration::Polynomial { coefficients } if coefficients.is_empty() => {
+                Err("polynomial has no coefficients".into())
+            }
+            Calibration::Polynomial { .. } => Ok(()),
+            Calibration::Table { points } if points.len() < 2 => {
+                Err("lookup table needs at least two points".into())
+            }
+            Calibration::Table { points } => {
+                if points.windows(2).all(|window| window[0].0 < window[1].0) {
+                    Ok(())
+                } else {
+                    Err("lookup table points must be strictly increasing in raw value".into())
+                }
+            }
+        }
+    }
+}
+
+fn load_calibrations() -> io::Result<BTreeMap<String, CalibrationDefinition>> {
+    let contents = match fs::read_to_string(&config().paths.calibration) {
+        Ok(contents) => contents,
+        // Running without calibrations is perfectly valid
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e),
+    };
+
+    let calibrations: BTreeMap<String, CalibrationDefinition> =
+        toml::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    for (key, definition) in &calibrations {
+        definition.calibration.validate().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid calibration for {}: {}", key, e),
+            )
+        })?;
+    }
+
+    Ok(calibrations)
+}
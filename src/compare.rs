@@ -0,0 +1,245 @@
+use std::{collections::BTreeMap, convert::TryFrom, fmt, ops::RangeInclusive};
+
+use serde::Serialize;
+
+use crate::{
+    session::SessionId,
+    store::Snapshot,
+    telemetry::{TelemetryDictionary, TelemetryValue, TELEMETRY_TIME_FIELD},
+};
+
+/// The values of a telemetry point over time, as `(running_us, value)` pairs
+/// on the timeline the sessions were aligned to
+pub type Series = Vec<(i64, f64)>;
+
+/// How the timelines of two sessions are lined up before they are compared
+#[derive(Debug)]
+pub enum Alignment {
+    /// microseconds added to the `running_us` of the second session
+    Offset(i64),
+    /// line up the first time a telemetry point takes a value, or first
+    /// changes from its initial value if no value is given
+    Event { key: String, value: Option<f64> },
+}
+
+#[derive(Debug)]
+pub enum CompareError {
+    /// the event the sessions are aligned by never happened in a session
+    MissingEvent { session: SessionId, key: String },
+    /// the offset moves packets of a session off of the timeline
+    OffsetOutOfRange { session: SessionId, offset: i64 },
+}
+
+impl fmt::Display for CompareError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompareError::MissingEvent { session, key } => write!(
+                f,
+                "the event on {} never happened in session {}",
+                key, session
+            ),
+            CompareError::OffsetOutOfRange { session, offset } => write!(
+                f,
+                "an offset of {} µs moves session {} off of the timeline",
+                offset, session
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CompareError {}
+
+/// Statistics of a telemetry point in a single session
+#[derive(Debug, Default, Serialize)]
+pub struct SeriesStats {
+    pub samples: usize,
+    pub mean: Option<f64>,
+    pub max: Option<f64>,
+}
+
+/// How a telemetry point differs between two sessions
+#[derive(Debug, Serialize)]
+pub struct KeyComparison {
+    pub a: SeriesStats,
+    pub b: SeriesStats,
+    /// the mean of the second session subtracted from the mean of the first
+    pub mean_difference: Option<f64>,
+    /// the max of the second session subtracted from the max of the first
+    pub max_difference: Option<f64>,
+    /// the root mean square of the difference between the sessions, taken at
+    /// every sample of the first session that the second session covers
+    pub rms_error: Option<f64>,
+    /// the samples the RMS error was taken over
+    pub paired: usize,
+}
+
+/// The comparison of two sessions after they were aligned
+#[derive(Debug, Serialize)]
+pub struct Comparison {
+    pub a: SessionId,
+    pub b: SessionId,
+    /// microseconds added to the `running_us` of the second session
+    pub offset_us: i64,
+    /// the key of the folder holding the difference between the sessions of
+    /// every telemetry point they have in common, which Open MCT can plot
+    pub differences: String,
+    pub keys: BTreeMap<String, KeyComparison>,
+}
+
+/// The value of a telemetry point as a number, with booleans as 0 or 1 so
+/// that events can be on them
+fn event_value(value: TelemetryValue) -> f64 {
+    match value {
+        TelemetryValue::Boolean(value) => value as u8 as f64,
+        TelemetryValue::Integer(value) => value as f64,
+        TelemetryValue::Float(value) => value,
+    }
+}
+
+/// The `running_us` at which an event happened in a session
+pub fn event_time(data: &Snapshot, key: &str, value: Option<f64>) -> Option<u64> {
+    let mut values = data.range(..).filter_map(|packet| {
+        packet
+            .values
+            .get(key)
+            .map(|&value| (packet.running_us, event_value(value)))
+    });
+
+    match value {
+        Some(value) => values
+            .find(|&(_, sample)| sample == value)
+            .map(|(running_us, _)| running_us),
+        None => {
+            let (_, initial) = values.next()?;
+
+            values
+                .find(|&(_, sample)| sample != initial)
+                .map(|(running_us, _)| running_us)
+        }
+    }
+}
+
+/// The telemetry points of both dictionaries
+pub fn common_keys(a: &TelemetryDictionary, b: &TelemetryDictionary) -> Vec<String> {
+    let b = b.composition();
+
+    a.composition()
+        .into_iter()
+        .map(|identifier| identifier.key)
+        .filter(|&key| key != TELEMETRY_TIME_FIELD.name)
+        .filter(|&key| b.iter().any(|other| other.key == key))
+        .map(str::to_string)
+        .collect()
+}
+
+/// The numeric values of telemetry points in `session`, moved by `offset` and
+/// limited to `range` of the aligned timeline. Only the packets within the
+/// range are read.
+pub fn series(
+    data: &Snapshot,
+    session: SessionId,
+    keys: &[String],
+    offset: i64,
+    range: RangeInclusive<i64>,
+) -> Result<BTreeMap<String, Series>, CompareError> {
+    let aligned = |running_us: u64| {
+        i64::try_from(running_us)
+            .ok()
+            .and_then(|running_us| running_us.checked_add(offset))
+            .ok_or(CompareError::OffsetOutOfRange { session, offset })
+    };
+
+    // Checking the ends of the session covers every packet in between
+    for running_us in data.first_us().into_iter().chain(data.last_us()) {
+        aligned(running_us)?;
+    }
+
+    let mut series = keys
+        .iter()
+        .map(|key| (key.clone(), Series::new()))
+        .collect::<BTreeMap<_, _>>();
+
+    // The range on the session's own timeline, which may be empty
+    let start = (*range.start() as i128 - offset as i128).max(0);
+    let end = (*range.end() as i128 - offset as i128).min(u64::MAX as i128);
+    if start > end {
+        return Ok(series);
+    }
+
+    for packet in data.range(start as u64..=end as u64) {
+        let time = aligned(packet.running_us)?;
+
+        for (key, samples) in &mut series {
+            if let Some(value) = packet.values.get(key).and_then(|value| value.as_f64()) {
+                samples.push((time, value));
+            }
+        }
+    }
+
+    Ok(series)
+}
+
+/// The value of a series at a time, interpolated between the samples either
+/// side of it. `None` outside of the series.
+fn sample_at(series: &Series, time: i64) -> Option<f64> {
+    match series.binary_search_by_key(&time, |&(time, _)| time) {
+        Ok(index) => Some(series[index].1),
+        Err(index) if index == 0 || index == series.len() => None,
+        Err(index) => {
+            let (before_time, before) = series[index - 1];
+            let (after_time, after) = series[index];
+
+            let fraction = (time - before_time) as f64 / (after_time - before_time) as f64;
+
+            Some(before + (after - before) * fraction)
+        }
+    }
+}
+
+/// The difference between two series at every sample of the first that the
+/// second covers
+pub fn difference(a: &Series, b: &Series) -> Series {
+    a.iter()
+        .filter_map(|&(time, value)| Some((time, value - sample_at(b, time)?)))
+        .collect()
+}
+
+fn stats(series: &Series) -> SeriesStats {
+    if series.is_empty() {
+        return SeriesStats::default();
+    }
+
+    SeriesStats {
+        samples: series.len(),
+        mean: Some(series.iter().map(|&(_, value)| value).sum::<f64>() / series.len() as f64),
+        max: series
+            .iter()
+            .map(|&(_, value)| value)
+            .fold(None, |max: Option<f64>, value| {
+                Some(max.map_or(value, |max| max.max(value)))
+            }),
+    }
+}
+
+pub fn compare(a: &Series, b: &Series) -> KeyComparison {
+    let difference = difference(a, b);
+    let (a, b) = (stats(a), stats(b));
+
+    let rms_error = (!difference.is_empty()).then(|| {
+        let squares = difference
+            .iter()
+            .map(|&(_, difference)| difference * difference)
+            .sum::<f64>();
+
+        (squares / difference.len() as f64).sqrt()
+    });
+
+    KeyComparison {
+        mean_difference: a.mean.zip(b.mean).map(|(a, b)| a - b),
+        max_difference: a.max.zip(b.max).map(|(a, b)| a - b),
+        rms_error,
+        paired: difference.len(),
+        a,
+        b,
+    }
+}
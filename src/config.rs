@@ -0,0 +1,603 @@
+use std::{
+    fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use color_eyre::eyre::{eyre, Context};
+use log::LevelFilter;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+/// The configuration file loaded if no other is given
+const DEFAULT_CONFIG_FILE: &str = "pico-mct.toml";
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// The active configuration, or the defaults if none has been loaded
+pub fn config() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+/// Server configuration, layered from defaults, a config file, environment
+/// variables and command line flags, with later layers taking precedence.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub logging: LoggingConfig,
+    pub device: DeviceConfig,
+    pub sources: SourcesConfig,
+    pub paths: PathsConfig,
+    pub simulator: SimulatorConfig,
+    pub relay: RelayConfig,
+    pub retention: RetentionConfig,
+    pub stream: StreamConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// the address the server listens on
+    pub listen: String,
+    /// the Open MCT namespace the server's objects live in
+    pub namespace: String,
+    /// the largest data file accepted by `/import`, in bytes
+    pub max_import_bytes: u64,
+    pub cors: CorsConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// the origin allowed to make requests to the server, or `*` for any
+    pub origin: String,
+    /// the methods allowed in cross origin requests
+    pub methods: Vec<String>,
+    pub credentials: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// the log level of the server itself
+    pub level: String,
+    /// the log level of the libraries the server uses
+    pub dependency_level: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceConfig {
+    /// the read timeout used for devices that do not specify one
+    pub timeout_ms: u64,
+    /// the baud rate used for devices that do not specify one. The Pico SDK's
+    /// USB CDC ignores it.
+    pub baud: u32,
+}
+
+/// The sources clients may connect the server to through `/devices/connect`,
+/// besides serial ports and the simulator. Anyone who can reach the server can
+/// ask it to connect, so the rest are refused unless enabled here.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourcesConfig {
+    /// the directory file sources may be read from, which relative paths are
+    /// resolved in. File sources are refused if it is not given.
+    pub recordings: Option<PathBuf>,
+    /// whether packets piped into the server may be read
+    pub stdin: bool,
+    /// the addresses TCP and relay sources may connect to, as `host:port` or a
+    /// port on any host
+    pub connect: Vec<String>,
+    /// the addresses TCP sources may listen on and UDP sources may receive
+    /// on, as `host:port` or a port on any interface
+    pub bind: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathsConfig {
+    pub calibration: PathBuf,
+    pub derived: PathBuf,
+    /// the mappings used to import data files
+    pub mappings: PathBuf,
+    /// the directory objects created by users are persisted in
+    pub objects: PathBuf,
+    /// the directory telemetry that no longer fits in memory is spilled to
+    pub spill: PathBuf,
+    /// the directory the web app is served from, unless it is embedded
+    pub web: PathBuf,
+}
+
+/// The simulated flight computer listed alongside real devices
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulatorConfig {
+    pub enabled: bool,
+    /// packets generated per second
+    pub rate_hz: f64,
+    /// the standard deviation of the noise added to measurements, relative to
+    /// their range
+    pub noise: f64,
+    /// the chance of each packet being dropped
+    pub dropout: f64,
+    /// the average number of seconds between the USB cable being unplugged,
+    /// or 0 to keep it plugged in
+    pub usb_event_interval_s: f64,
+    /// makes the simulation repeatable
+    pub seed: Option<u64>,
+}
+
+/// Forwarding of live telemetry between servers, such as from the laptop at the
+/// pad to a ground station. The relay is not encrypted, so it is only meant for
+/// trusted networks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayConfig {
+    /// the address to serve the telemetry ingested by this server on, for
+    /// downstream servers to connect to
+    pub listen: Option<String>,
+    /// a secret downstream servers must send to be relayed to, and which is
+    /// sent to the upstream server. Without it, anyone who can reach the
+    /// relay's address is relayed to.
+    pub token: Option<String>,
+    /// the relay address of an upstream server, listed as the `relay` device
+    pub upstream: Option<String>,
+    /// how long to keep trying to reconnect to the upstream server before
+    /// giving up
+    pub reconnect_timeout_s: f64,
+}
+
+/// Limits on the telemetry of each session kept in memory, none of which are
+/// applied if not given. If any is, sessions are kept entirely on disk once
+/// they finish or are imported, when telemetry past the limits is spilled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// the longest span of telemetry kept, back from the latest packet
+    pub max_duration_s: Option<f64>,
+    /// the most packets kept
+    pub max_samples: Option<usize>,
+    /// the most memory used by the kept packets, as estimated
+    pub max_bytes: Option<u64>,
+    /// what happens to telemetry past the limits
+    pub overflow: Overflow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    /// forget the telemetry
+    Discard,
+    /// move the telemetry to a file in the spill directory, where it can still
+    /// be queried
+    Spill,
+}
+
+/// The queues live telemetry waits in for each connected client, so a client
+/// that cannot keep up does not make the server buffer its telemetry forever
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StreamConfig {
+    /// the most telemetry packets queued for a client
+    pub queue: usize,
+    /// how room is made for new telemetry once a client's queue is full
+    pub policy: QueuePolicy,
+    /// how long a device keeps being ingested after its client's connection
+    /// drops, for the client to reconnect and resume its stream
+    pub resume_timeout_s: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueuePolicy {
+    /// drop the oldest queued packet
+    DropOldest,
+    /// drop every other queued packet, keeping the span of the queued
+    /// telemetry at a lower rate
+    Decimate,
+    /// merge the queued packets into one with the latest value of every
+    /// telemetry point, keeping the packets of each session and firmware apart
+    Coalesce,
+}
+
+impl FromStr for QueuePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(QueuePolicy::DropOldest),
+            "decimate" => Ok(QueuePolicy::Decimate),
+            "coalesce" => Ok(QueuePolicy::Coalesce),
+            _ => Err(format!(
+                "unknown queue policy {}, expected drop-oldest, decimate or coalesce",
+                s
+            )),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server: ServerConfig::default(),
+            logging: LoggingConfig::default(),
+            device: DeviceConfig::default(),
+            sources: SourcesConfig::default(),
+            paths: PathsConfig::default(),
+            simulator: SimulatorConfig::default(),
+            relay: RelayConfig::default(),
+            retention: RetentionConfig::default(),
+            stream: StreamConfig::default(),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: "0.0.0.0:13705".into(),
+            namespace: "dusterthefirst.pico-pilot".into(),
+            max_import_bytes: 256 * 1024 * 1024,
+            cors: CorsConfig::default(),
+        }
+    }
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            origin: "*".into(),
+            methods: vec![
+                "GET".into(),
+                "POST".into(),
+                "PUT".into(),
+                "PATCH".into(),
+                "DELETE".into(),
+            ],
+            credentials: false,
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "trace".into(),
+            dependency_level: "info".into(),
+        }
+    }
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        DeviceConfig {
+            timeout_ms: 1000,
+            baud: 0,
+        }
+    }
+}
+
+impl Default for PathsConfig {
+    fn default() -> Self {
+        PathsConfig {
+            calibration: "calibration.toml".into(),
+            derived: "derived.toml".into(),
+            mappings: "mappings.toml".into(),
+            objects: "objects".into(),
+            spill: "spill".into(),
+            web: "web".into(),
+        }
+    }
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        SimulatorConfig {
+            enabled: true,
+            rate_hz: 100.0,
+            noise: 0.005,
+            dropout: 0.0,
+            usb_event_interval_s: 60.0,
+            seed: None,
+        }
+    }
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        RelayConfig {
+            listen: None,
+            token: None,
+            upstream: None,
+            reconnect_timeout_s: 60.0,
+        }
+    }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            max_duration_s: None,
+            max_samples: None,
+            max_bytes: None,
+            overflow: Overflow::Spill,
+        }
+    }
+}
+
+impl RetentionConfig {
+    /// Whether any limit applies
+    pub fn is_limited(&self) -> bool {
+        self.max_duration_s.is_some() || self.max_samples.is_some() || self.max_bytes.is_some()
+    }
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        StreamConfig {
+            queue: 4096,
+            policy: QueuePolicy::DropOldest,
+            resume_timeout_s: 10.0,
+        }
+    }
+}
+
+/// Telemetry ingest server for the Pico Pilot flight computer
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub struct Cli {
+    /// Configuration file to load [default: pico-mct.toml, if it exists]
+    #[structopt(long, short, env = "PICO_MCT_CONFIG", parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Print the effective configuration and exit
+    #[structopt(long)]
+    pub print_config: bool,
+
+    /// Address to listen on
+    #[structopt(long, env = "PICO_MCT_LISTEN")]
+    listen: Option<String>,
+    /// Open MCT namespace of the served objects
+    #[structopt(long, env = "PICO_MCT_NAMESPACE")]
+    namespace: Option<String>,
+    /// Origin allowed to make cross origin requests
+    #[structopt(long, env = "PICO_MCT_CORS_ORIGIN")]
+    cors_origin: Option<String>,
+    /// Log level of the server
+    #[structopt(long, env = "PICO_MCT_LOG_LEVEL")]
+    log_level: Option<String>,
+    /// Log level of the libraries used by the server
+    #[structopt(long, env = "PICO_MCT_DEPENDENCY_LOG_LEVEL")]
+    dependency_log_level: Option<String>,
+    /// Default device read timeout in milliseconds
+    #[structopt(long, env = "PICO_MCT_DEVICE_TIMEOUT")]
+    device_timeout: Option<u64>,
+    /// Default device baud rate
+    #[structopt(long, env = "PICO_MCT_DEVICE_BAUD")]
+    device_baud: Option<u32>,
+    /// Calibration definitions file
+    #[structopt(long, env = "PICO_MCT_CALIBRATION", parse(from_os_str))]
+    calibration: Option<PathBuf>,
+    /// Derived telemetry definitions file
+    #[structopt(long, env = "PICO_MCT_DERIVED", parse(from_os_str))]
+    derived: Option<PathBuf>,
+    /// Import mappings file
+    #[structopt(long, env = "PICO_MCT_MAPPINGS", parse(from_os_str))]
+    mappings: Option<PathBuf>,
+    /// Directory user objects are persisted in
+    #[structopt(long, env = "PICO_MCT_OBJECTS", parse(from_os_str))]
+    objects: Option<PathBuf>,
+    /// Directory telemetry past the retention limits is spilled to
+    #[structopt(long, env = "PICO_MCT_SPILL", parse(from_os_str))]
+    spill: Option<PathBuf>,
+    /// Directory the web app is served from
+    #[structopt(long, env = "PICO_MCT_WEB", parse(from_os_str))]
+    web: Option<PathBuf>,
+    /// Address to relay ingested telemetry to downstream servers on
+    #[structopt(long, env = "PICO_MCT_RELAY_LISTEN")]
+    relay_listen: Option<String>,
+    /// Relay address of the upstream server to mirror
+    #[structopt(long, env = "PICO_MCT_RELAY_UPSTREAM")]
+    relay_upstream: Option<String>,
+    /// Longest span of live telemetry kept in memory, in seconds
+    #[structopt(long, env = "PICO_MCT_RETENTION_DURATION")]
+    retention_duration: Option<f64>,
+    /// Most packets of live telemetry kept in memory
+    #[structopt(long, env = "PICO_MCT_RETENTION_SAMPLES")]
+    retention_samples: Option<usize>,
+    /// Most bytes of live telemetry kept in memory
+    #[structopt(long, env = "PICO_MCT_RETENTION_BYTES")]
+    retention_bytes: Option<u64>,
+    /// Most live telemetry packets queued for each client
+    #[structopt(long, env = "PICO_MCT_STREAM_QUEUE")]
+    stream_queue: Option<usize>,
+    /// How a full client queue makes room: drop-oldest, decimate or coalesce
+    #[structopt(long, env = "PICO_MCT_STREAM_POLICY")]
+    stream_policy: Option<QueuePolicy>,
+}
+
+impl Cli {
+    /// Builds the configuration from the config file, then applies the
+    /// environment variables and flags on top of it
+    pub fn load(&self) -> color_eyre::Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::from_file(path)?,
+            None => match Config::from_file(Path::new(DEFAULT_CONFIG_FILE)) {
+                Err(e) if is_not_found(&e) => Config::default(),
+                result => result?,
+            },
+        };
+
+        macro_rules! apply {
+            ($($flag:ident => $($field:ident).+),* $(,)?) => {
+                $(if let Some(value) = &self.$flag {
+                    config.$($field).+ = value.clone();
+                })*
+            };
+        }
+
+        apply! {
+            listen => server.listen,
+            namespace => server.namespace,
+            cors_origin => server.cors.origin,
+            log_level => logging.level,
+            dependency_log_level => logging.dependency_level,
+            device_timeout => device.timeout_ms,
+            device_baud => device.baud,
+            calibration => paths.calibration,
+            derived => paths.derived,
+            mappings => paths.mappings,
+            objects => paths.objects,
+            spill => paths.spill,
+            web => paths.web,
+            stream_queue => stream.queue,
+            stream_policy => stream.policy,
+        }
+
+        if let Some(listen) = &self.relay_listen {
+            config.relay.listen = Some(listen.clone());
+        }
+        if let Some(upstream) = &self.relay_upstream {
+            config.relay.upstream = Some(upstream.clone());
+        }
+        if let Some(duration) = self.retention_duration {
+            config.retention.max_duration_s = Some(duration);
+        }
+        if let Some(samples) = self.retention_samples {
+            config.retention.max_samples = Some(samples);
+        }
+        if let Some(bytes) = self.retention_bytes {
+            config.retention.max_bytes = Some(bytes);
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+}
+
+fn is_not_found(error: &color_eyre::Report) -> bool {
+    error
+        .downcast_ref::<io::Error>()
+        .map_or(false, |e| e.kind() == io::ErrorKind::NotFound)
+}
+
+impl Config {
+    fn from_file(path: &Path) -> color_eyre::Result<Config> {
+        let contents = fs::read_to_string(path)?;
+
+        toml::from_str(&contents)
+            .wrap_err_with(|| format!("Failed to parse configuration file {}", path.display()))
+    }
+
+    fn validate(&self) -> color_eyre::Result<()> {
+        SocketAddr::from_str(&self.server.listen)
+            .wrap_err_with(|| format!("Invalid listen address {}", self.server.listen))?;
+
+        if self.server.namespace.is_empty() || self.server.namespace.contains(':') {
+            return Err(eyre!(
+                "Invalid namespace {:?}, it must be non-empty and not contain `:`",
+                self.server.namespace
+            ));
+        }
+
+        if self.server.max_import_bytes == 0 {
+            return Err(eyre!("The import size limit must be greater than zero"));
+        }
+
+        if self.server.cors.origin.is_empty() {
+            return Err(eyre!("The CORS origin must not be empty"));
+        }
+
+        if self.server.cors.methods.is_empty() {
+            return Err(eyre!("At least one CORS method must be allowed"));
+        }
+
+        self.log_level()?;
+        self.dependency_log_level()?;
+
+        if self.device.timeout_ms == 0 {
+            return Err(eyre!("The device timeout must be greater than zero"));
+        }
+
+        let simulator = &self.simulator;
+        if !(simulator.rate_hz > 0.0 && simulator.rate_hz <= 10_000.0) {
+            return Err(eyre!("The simulator rate must be between 0 and 10000 Hz"));
+        }
+
+        if simulator.noise.is_nan() || simulator.noise < 0.0 {
+            return Err(eyre!("The simulator noise must not be negative"));
+        }
+
+        if !(0.0..1.0).contains(&simulator.dropout) {
+            return Err(eyre!("The simulator dropout must be between 0 and 1"));
+        }
+
+        if simulator.usb_event_interval_s.is_nan() || simulator.usb_event_interval_s < 0.0 {
+            return Err(eyre!(
+                "The simulator USB event interval must not be negative"
+            ));
+        }
+
+        if let Some(listen) = &self.relay.listen {
+            SocketAddr::from_str(listen)
+                .wrap_err_with(|| format!("Invalid relay listen address {}", listen))?;
+        }
+
+        if self.relay.token.as_deref() == Some("") {
+            return Err(eyre!("The relay token must not be empty"));
+        }
+
+        if self.relay.upstream.as_deref() == Some("") {
+            return Err(eyre!("The relay upstream address must not be empty"));
+        }
+
+        if self.relay.reconnect_timeout_s.is_nan() || self.relay.reconnect_timeout_s < 0.0 {
+            return Err(eyre!("The relay reconnect timeout must not be negative"));
+        }
+
+        let retention = &self.retention;
+        if let Some(duration) = retention.max_duration_s {
+            if !(duration > 0.0 && duration.is_finite()) {
+                return Err(eyre!("The retention duration must be greater than zero"));
+            }
+        }
+
+        if retention.max_samples == Some(0) || retention.max_bytes == Some(0) {
+            return Err(eyre!("The retention limits must be greater than zero"));
+        }
+
+        if self.stream.queue == 0 {
+            return Err(eyre!("The stream queue must hold at least one packet"));
+        }
+
+        if self.stream.resume_timeout_s.is_nan() || self.stream.resume_timeout_s < 0.0 {
+            return Err(eyre!("The stream resume timeout must not be negative"));
+        }
+
+        Ok(())
+    }
+
+    /// Installs this configuration as the active one. Must be called before any
+    /// configuration dependent state is initialized.
+    pub fn install(self) -> &'static Config {
+        if CONFIG.set(self).is_err() {
+            panic!("configuration was installed after it was already used");
+        }
+
+        config()
+    }
+
+    pub fn log_level(&self) -> color_eyre::Result<LevelFilter> {
+        LevelFilter::from_str(&self.logging.level)
+            .map_err(|_| eyre!("Invalid log level {}", self.logging.level))
+    }
+
+    pub fn dependency_log_level(&self) -> color_eyre::Result<LevelFilter> {
+        LevelFilter::from_str(&self.logging.dependency_level)
+            .map_err(|_| eyre!("Invalid log level {}", self.logging.dependency_level))
+    }
+}
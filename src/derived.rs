@@ -0,0 +1,133 @@
+use std::{collections::BTreeSet, fs, io};
+
+use lazy_static::lazy_static;
+use serde::Deserialize;
+
+use crate::{
+    config::config,
+    telemetry::{Identifier, TelemetryDictionary, TelemetryPacket, TelemetryValue},
+};
+
+use self::expression::{Expression, ExpressionState};
+
+pub mod expression;
+
+lazy_static! {
+    /// Derived telemetry definitions, in the order they are evaluated
+    pub static ref DERIVED_DEFINITIONS: Vec<DerivedDefinition> =
+        load_derived().expect("Failed to load derived telemetry definitions");
+}
+
+#[derive(Debug, Deserialize)]
+struct DerivedFile {
+    #[serde(default)]
+    derived: Vec<DerivedDefinition>,
+}
+
+/// A telemetry point computed by the server from other telemetry points
+#[derive(Debug, Deserialize)]
+pub struct DerivedDefinition {
+    /// the telemetry key the derived value is published as
+    pub key: String,
+    /// a human readable name for the derived value
+    pub name: String,
+    /// the expression computing the value, see [`Expression`]
+    pub expression: Expression,
+    pub units: Option<String>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+/// Evaluates the derived telemetry available in a dictionary, keeping the state
+/// of derivatives and averages between packets
+pub struct DerivedTelemetry {
+    measurements: Vec<(&'static DerivedDefinition, ExpressionState)>,
+}
+
+impl DerivedTelemetry {
+    pub fn new(dictionary: &TelemetryDictionary) -> Self {
+        DerivedTelemetry {
+            measurements: DERIVED_DEFINITIONS
+                .iter()
+                .filter(|definition| {
+                    dictionary
+                        .metadata(Identifier::from_key(&definition.key))
+                        .is_some()
+                })
+                .map(|definition| (definition, definition.expression.new_state()))
+                .collect(),
+        }
+    }
+
+    /// Adds the derived values to a packet. Derived values that cannot be computed
+    /// for this packet are left out of it.
+    pub fn evaluate(&mut self, packet: &mut TelemetryPacket) {
+        for (definition, state) in &mut self.measurements {
+            let value = {
+                let values = &packet.values;
+
+                definition
+                    .expression
+                    .evaluate(state, packet.running_us, &|key: &str| {
+                        values.get(key).map(|value| match *value {
+                            TelemetryValue::Boolean(value) => value as u8 as f64,
+                            TelemetryValue::Integer(value) => value as f64,
+                            TelemetryValue::Float(value) => value,
+                        })
+                    })
+            };
+
+            if let Some(value) = value.filter(|value| value.is_finite()) {
+                packet
+                    .values
+                    .insert(definition.key.clone(), TelemetryValue::Float(value));
+            }
+        }
+    }
+}
+
+/// Checks if every key a derived definition reads is available, given the keys
+/// of a dictionary and of the derived definitions evaluated before it
+pub fn dependencies_met(definition: &DerivedDefinition, available: &BTreeSet<&str>) -> bool {
+    definition
+        .expression
+        .variables()
+        .iter()
+        .all(|key| available.contains(key))
+}
+
+fn load_derived() -> io::Result<Vec<DerivedDefinition>> {
+    let contents = match fs::read_to_string(&config().paths.derived) {
+        Ok(contents) => contents,
+        // Running without derived telemetry is perfectly valid
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let DerivedFile { derived } =
+        toml::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let mut keys = BTreeSet::new();
+
+    for definition in &derived {
+        if !keys.insert(definition.key.as_str()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("derived telemetry {} is defined twice", definition.key),
+            ));
+        }
+
+        if definition
+            .expression
+            .variables()
+            .contains(&definition.key.as_str())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("derived telemetry {} depends on itself", definition.key),
+            ));
+        }
+    }
+
+    Ok(derived)
+}
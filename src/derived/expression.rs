@@ -0,0 +1,698 @@
+use std::{collections::VecDeque, convert::TryFrom, fmt, iter::Peekable, str::Chars};
+
+use serde::Deserialize;
+
+/// An arithmetic expression over telemetry keys, such as
+/// `sqrt(tvc.x ^ 2 + tvc.z ^ 2)` or `derivative(proc.temp)`.
+///
+/// Expressions may contain numbers, the constants `pi` and `e`, telemetry keys,
+/// the operators `+ - * / % ^`, parentheses and calls to math functions.
+/// `derivative(x)` (per second) and `moving_average(x, samples)` keep state
+/// between packets, which lives in an [`ExpressionState`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Expression {
+    source: String,
+    root: Node,
+    slots: Vec<Slot>,
+}
+
+/// The state of the stateful functions of an [`Expression`]
+#[derive(Debug, Clone)]
+pub struct ExpressionState(Vec<Slot>);
+
+#[derive(Debug)]
+pub struct ExpressionError(String);
+
+impl fmt::Display for ExpressionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ExpressionError {}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Constant(f64),
+    Variable(String),
+    Unary(fn(f64) -> f64, Box<Node>),
+    Binary(fn(f64, f64) -> f64, Box<Node>, Box<Node>),
+    Derivative(Box<Node>, usize),
+    MovingAverage(Box<Node>, usize),
+}
+
+#[derive(Debug, Clone)]
+enum Slot {
+    Derivative {
+        previous: Option<(u64, f64)>,
+    },
+    MovingAverage {
+        window: usize,
+        samples: VecDeque<f64>,
+    },
+}
+
+impl Expression {
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn new_state(&self) -> ExpressionState {
+        ExpressionState(self.slots.clone())
+    }
+
+    /// Every telemetry key the expression reads
+    pub fn variables(&self) -> Vec<&str> {
+        fn visit<'a>(node: &'a Node, variables: &mut Vec<&'a str>) {
+            match node {
+                Node::Constant(_) => {}
+                Node::Variable(name) => variables.push(name),
+                Node::Unary(_, argument)
+                | Node::Derivative(argument, _)
+                | Node::MovingAverage(argument, _) => visit(argument, variables),
+                Node::Binary(_, left, right) => {
+                    visit(left, variables);
+                    visit(right, variables);
+                }
+            }
+        }
+
+        let mut variables = Vec::new();
+        visit(&self.root, &mut variables);
+
+        variables
+    }
+
+    /// Evaluates the expression for a packet at `running_us`, looking up
+    /// telemetry keys with `lookup`. Returns `None` if any key is unavailable or
+    /// a stateful function does not have enough history yet.
+    pub fn evaluate(
+        &self,
+        state: &mut ExpressionState,
+        running_us: u64,
+        lookup: &dyn Fn(&str) -> Option<f64>,
+    ) -> Option<f64> {
+        evaluate(&self.root, &mut state.0, running_us, lookup)
+    }
+}
+
+fn evaluate(
+    node: &Node,
+    slots: &mut [Slot],
+    running_us: u64,
+    lookup: &dyn Fn(&str) -> Option<f64>,
+) -> Option<f64> {
+    match node {
+        Node::Constant(value) => Some(*value),
+        Node::Variable(name) => lookup(name),
+        Node::Unary(function, argument) => {
+            Some(function(evaluate(argument, slots, running_us, lookup)?))
+        }
+        Node::Binary(function, left, right) => {
+            let left = evaluate(left, slots, running_us, lookup);
+            let right = evaluate(right, slots, running_us, lookup);
+
+            Some(function(left?, right?))
+        }
+        Node::Derivative(argument, slot) => {
+            let value = evaluate(argument, slots, running_us, lookup)?;
+
+            match &mut slots[*slot] {
+                Slot::Derivative { previous } => {
+                    let rate = match *previous {
+                        Some((previous_us, previous_value)) if running_us > previous_us => {
+                            let seconds = (running_us - previous_us) as f64 / 1_000_000.0;
+
+                            Some((value - previous_value) / seconds)
+                        }
+                        _ => None,
+                    };
+
+                    *previous = Some((running_us, value));
+
+                    rate
+                }
+                Slot::MovingAverage { .. } => {
+                    unreachable!("derivative given a moving average slot")
+                }
+            }
+        }
+        Node::MovingAverage(argument, slot) => {
+            let value = evaluate(argument, slots, running_us, lookup)?;
+
+            match &mut slots[*slot] {
+                Slot::MovingAverage { window, samples } => {
+                    if samples.len() == *window {
+                        samples.pop_front();
+                    }
+
+                    samples.push_back(value);
+
+                    Some(samples.iter().sum::<f64>() / samples.len() as f64)
+                }
+                Slot::Derivative { .. } => unreachable!("moving average given a derivative slot"),
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for Expression {
+    type Error = ExpressionError;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        let mut parser = Parser {
+            tokens: tokenize(&source)?.into_iter().peekable(),
+            slots: Vec::new(),
+        };
+
+        let root = parser.expression()?;
+
+        if let Some(token) = parser.tokens.next() {
+            return Err(ExpressionError(format!(
+                "unexpected {} after end of expression",
+                token
+            )));
+        }
+
+        Ok(Expression {
+            source,
+            root,
+            slots: parser.slots,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Identifier(String),
+    Operator(char),
+    OpenParen,
+    CloseParen,
+    Comma,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(number) => write!(f, "number {}", number),
+            Token::Identifier(name) => write!(f, "`{}`", name),
+            Token::Operator(operator) => write!(f, "`{}`", operator),
+            Token::OpenParen => f.write_str("`(`"),
+            Token::CloseParen => f.write_str("`)`"),
+            Token::Comma => f.write_str("`,`"),
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, ExpressionError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '0'..='9' | '.' => tokens.push(Token::Number(number(&mut chars)?)),
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut name = String::new();
+
+                while let Some(&c) = chars.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+                        name.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+
+                tokens.push(Token::Identifier(name));
+            }
+            '+' | '-' | '*' | '/' | '%' | '^' => {
+                tokens.push(Token::Operator(c));
+                chars.next();
+            }
+            '(' => {
+                tokens.push(Token::OpenParen);
+                chars.next();
+            }
+            ')' => {
+                tokens.push(Token::CloseParen);
+                chars.next();
+            }
+            ',' => {
+                tokens.push(Token::Comma);
+                chars.next();
+            }
+            c => return Err(ExpressionError(format!("unexpected character `{}`", c))),
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn number(chars: &mut Peekable<Chars>) -> Result<f64, ExpressionError> {
+    let mut literal = String::new();
+
+    while let Some(&c) = chars.peek() {
+        let exponent_sign =
+            (c == '-' || c == '+') && literal.ends_with(|last: char| last == 'e' || last == 'E');
+
+        if c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || exponent_sign {
+            literal.push(c);
+            chars.next();
+        } else {
+            break;
+        }
+    }
+
+    literal
+        .parse()
+        .map_err(|_| ExpressionError(format!("invalid number `{}`", literal)))
+}
+
+struct Parser {
+    tokens: Peekable<std::vec::IntoIter<Token>>,
+    slots: Vec<Slot>,
+}
+
+impl Parser {
+    fn expect(&mut self, expected: Token) -> Result<(), ExpressionError> {
+        match self.tokens.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(ExpressionError(format!(
+                "expected {}, found {}",
+                expected, token
+            ))),
+            None => Err(ExpressionError(format!(
+                "expected {}, found end of expression",
+                expected
+            ))),
+        }
+    }
+
+    /// expression := term (('+' | '-') term)*
+    fn expression(&mut self) -> Result<Node, ExpressionError> {
+        let mut node = self.term()?;
+
+        loop {
+            let function: fn(f64, f64) -> f64 = match self.tokens.peek() {
+                Some(Token::Operator('+')) => |a, b| a + b,
+                Some(Token::Operator('-')) => |a, b| a - b,
+                _ => return Ok(node),
+            };
+
+            self.tokens.next();
+            node = Node::Binary(function, Box::new(node), Box::new(self.term()?));
+        }
+    }
+
+    /// term := unary (('*' | '/' | '%') unary)*
+    fn term(&mut self) -> Result<Node, ExpressionError> {
+        let mut node = self.unary()?;
+
+        loop {
+            let function: fn(f64, f64) -> f64 = match self.tokens.peek() {
+                Some(Token::Operator('*')) => |a, b| a * b,
+                Some(Token::Operator('/')) => |a, b| a / b,
+                Some(Token::Operator('%')) => |a, b| a % b,
+                _ => return Ok(node),
+            };
+
+            self.tokens.next();
+            node = Node::Binary(function, Box::new(node), Box::new(self.unary()?));
+        }
+    }
+
+    /// unary := '-' unary | power
+    fn unary(&mut self) -> Result<Node, ExpressionError> {
+        if let Some(Token::Operator('-')) = self.tokens.peek() {
+            self.tokens.next();
+
+            return Ok(Node::Unary(|a| -a, Box::new(self.unary()?)));
+        }
+
+        self.power()
+    }
+
+    /// power := atom ('^' unary)?
+    fn power(&mut self) -> Result<Node, ExpressionError> {
+        let base = self.atom()?;
+
+        if let Some(Token::Operator('^')) = self.tokens.peek() {
+            self.tokens.next();
+
+            return Ok(Node::Binary(
+                f64::powf,
+                Box::new(base),
+                Box::new(self.unary()?),
+            ));
+        }
+
+        Ok(base)
+    }
+
+    /// atom := number | constant | key | function '(' arguments ')' | '(' expression ')'
+    fn atom(&mut self) -> Result<Node, ExpressionError> {
+        match self.tokens.next() {
+            Some(Token::Number(number)) => Ok(Node::Constant(number)),
+            Some(Token::OpenParen) => {
+                let node = self.expression()?;
+                self.expect(Token::CloseParen)?;
+
+                Ok(node)
+            }
+            Some(Token::Identifier(name)) => {
+                if let Some(Token::OpenParen) = self.tokens.peek() {
+                    self.tokens.next();
+
+                    return self.call(&name);
+                }
+
+                Ok(match name.as_str() {
+                    "pi" => Node::Constant(std::f64::consts::PI),
+                    "e" => Node::Constant(std::f64::consts::E),
+                    _ => Node::Variable(name),
+                })
+            }
+            Some(token) => Err(ExpressionError(format!("unexpected {}", token))),
+            None => Err(ExpressionError("unexpected end of expression".into())),
+        }
+    }
+
+    fn call(&mut self, name: &str) -> Result<Node, ExpressionError> {
+        let mut arguments = Vec::new();
+
+        if let Some(Token::CloseParen) = self.tokens.peek() {
+            self.tokens.next();
+        } else {
+            loop {
+                arguments.push(self.expression()?);
+
+                match self.tokens.next() {
+                    Some(Token::Comma) => continue,
+                    Some(Token::CloseParen) => break,
+                    Some(token) => {
+                        return Err(ExpressionError(format!(
+                            "expected `,` or `)`, found {}",
+                            token
+                        )))
+                    }
+                    None => return Err(ExpressionError(format!("unclosed call to `{}`", name))),
+                }
+            }
+        }
+
+        if let Some(function) = unary_function(name) {
+            if arguments.len() != 1 {
+                return Err(arity_error(name, 1, arguments.len()));
+            }
+
+            return Ok(Node::Unary(function, Box::new(arguments.remove(0))));
+        }
+
+        if let Some(function) = binary_function(name) {
+            if arguments.len() != 2 {
+                return Err(arity_error(name, 2, arguments.len()));
+            }
+
+            let right = arguments.remove(1);
+            let left = arguments.remove(0);
+
+            return Ok(Node::Binary(function, Box::new(left), Box::new(right)));
+        }
+
+        match name {
+            "derivative" => {
+                if arguments.len() != 1 {
+                    return Err(arity_error(name, 1, arguments.len()));
+                }
+
+                self.slots.push(Slot::Derivative { previous: None });
+
+                Ok(Node::Derivative(
+                    Box::new(arguments.remove(0)),
+                    self.slots.len() - 1,
+                ))
+            }
+            "moving_average" => {
+                if arguments.len() != 2 {
+                    return Err(arity_error(name, 2, arguments.len()));
+                }
+
+                let window = match arguments[1] {
+                    Node::Constant(window) if window >= 1.0 && window.fract() == 0.0 => {
+                        window as usize
+                    }
+                    _ => {
+                        return Err(ExpressionError(
+                            "the window of `moving_average` must be a positive whole number".into(),
+                        ))
+                    }
+                };
+
+                self.slots.push(Slot::MovingAverage {
+                    window,
+                    samples: VecDeque::with_capacity(window),
+                });
+
+                Ok(Node::MovingAverage(
+                    Box::new(arguments.remove(0)),
+                    self.slots.len() - 1,
+                ))
+            }
+            _ => Err(ExpressionError(format!("unknown function `{}`", name))),
+        }
+    }
+}
+
+fn arity_error(name: &str, expected: usize, found: usize) -> ExpressionError {
+    ExpressionError(format!(
+        "`{}` takes {} argument(s), found {}",
+        name, expected, found
+    ))
+}
+
+fn unary_function(name: &str) -> Option<fn(f64) -> f64> {
+    let function: fn(f64) -> f64 = match name {
+        "abs" => f64::abs,
+        "sqrt" => f64::sqrt,
+        "exp" => f64::exp,
+        "ln" => f64::ln,
+        "log10" => f64::log10,
+        "sin" => f64::sin,
+        "cos" => f64::cos,
+        "tan" => f64::tan,
+        "asin" => f64::asin,
+        "acos" => f64::acos,
+        "atan" => f64::atan,
+        "floor" => f64::floor,
+        "ceil" => f64::ceil,
+        "round" => f64::round,
+        "deg" => f64::to_degrees,
+        "rad" => f64::to_radians,
+        _ => return None,
+    };
+
+    Some(function)
+}
+
+fn binary_function(name: &str) -> Option<fn(f64, f64) -> f64> {
+    let function: fn(f64, f64) -> f64 = match name {
+        "atan2" => f64::atan2,
+        "hypot" => f64::hypot,
+        "min" => f64::min,
+        "max" => f64::max,
+        "pow" => f64::powf,
+        _ => return None,
+    };
+
+    Some(function)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::{Expression, ExpressionState};
+
+    fn parse(source: &str) -> Expression {
+        Expression::try_from(source.to_string()).expect("expression should parse")
+    }
+
+    fn error(source: &str) -> String {
+        Expression::try_from(source.to_string())
+            .expect_err("expression should not parse")
+            .to_string()
+    }
+
+    fn constant(source: &str) -> f64 {
+        let expression = parse(source);
+
+        expression
+            .evaluate(&mut expression.new_state(), 0, &|_| None)
+            .expect("expression should evaluate")
+    }
+
+    /// Evaluates an expression reading `x` for a packet at `running_us`
+    fn step(
+        expression: &Expression,
+        state: &mut ExpressionState,
+        running_us: u64,
+        x: f64,
+    ) -> Option<f64> {
+        expression.evaluate(state, running_us, &|key| (key == "x").then(|| x))
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(constant("2 + 3 * 4"), 14.0);
+        assert_eq!(constant("(2 + 3) * 4"), 20.0);
+        assert_eq!(constant("2 * 3 ^ 2"), 18.0);
+        assert_eq!(constant("10 - 6 % 4"), 8.0);
+        assert_eq!(constant("1 + 2 * 3 - 4 / 2"), 5.0);
+    }
+
+    #[test]
+    fn associativity() {
+        assert_eq!(constant("10 - 4 - 3"), 3.0);
+        assert_eq!(constant("64 / 4 / 2"), 8.0);
+        assert_eq!(constant("2 ^ 3 ^ 2"), 512.0);
+    }
+
+    #[test]
+    fn unary_minus() {
+        assert_eq!(constant("-3"), -3.0);
+        assert_eq!(constant("--3"), 3.0);
+        assert_eq!(constant("2 - -3"), 5.0);
+        assert_eq!(constant("-2 ^ 2"), -4.0);
+        assert_eq!(constant("2 ^ -1"), 0.5);
+        assert_eq!(constant("-(1 + 2) * 2"), -6.0);
+    }
+
+    #[test]
+    fn numbers_constants_and_functions() {
+        assert_eq!(constant("1.5e3"), 1500.0);
+        assert_eq!(constant("2E-2"), 0.02);
+        assert_eq!(constant(".5"), 0.5);
+        assert_eq!(constant("pi"), std::f64::consts::PI);
+        assert_eq!(constant("e"), std::f64::consts::E);
+        assert_eq!(constant("hypot(3, 4)"), 5.0);
+        assert_eq!(constant("max(min(1, 2), abs(-7))"), 7.0);
+        assert_eq!(constant("sqrt(3 ^ 2 + 4 ^ 2)"), 5.0);
+    }
+
+    #[test]
+    fn division_by_zero_and_nan() {
+        assert_eq!(constant("1 / 0"), f64::INFINITY);
+        assert_eq!(constant("-1 / 0"), f64::NEG_INFINITY);
+        assert!(constant("0 / 0").is_nan());
+        assert!(constant("5 % 0").is_nan());
+        assert!(constant("sqrt(-1)").is_nan());
+        assert!(constant("ln(-1) + 1").is_nan());
+    }
+
+    #[test]
+    fn variables() {
+        let expression = parse("sqrt(tvc.x ^ 2 + tvc.z ^ 2) + derivative(proc.temp)");
+
+        assert_eq!(expression.variables(), ["tvc.x", "tvc.z", "proc.temp"]);
+
+        let mut state = expression.new_state();
+        let lookup = |key: &str| match key {
+            "tvc.x" => Some(3.0),
+            "tvc.z" => Some(4.0),
+            _ => None,
+        };
+
+        // A missing key makes the whole expression unavailable
+        assert_eq!(expression.evaluate(&mut state, 0, &lookup), None);
+    }
+
+    #[test]
+    fn derivative_state() {
+        let expression = parse("derivative(x)");
+        let mut state = expression.new_state();
+
+        // There is no rate until there are two samples
+        assert_eq!(step(&expression, &mut state, 1_000_000, 10.0), None);
+        assert_eq!(step(&expression, &mut state, 1_500_000, 12.0), Some(4.0));
+        assert_eq!(step(&expression, &mut state, 2_500_000, 11.0), Some(-1.0));
+
+        // A sample at the same time, or going back in time, has no rate, but
+        // later rates are taken from it
+        assert_eq!(step(&expression, &mut state, 2_500_000, 20.0), None);
+        assert_eq!(step(&expression, &mut state, 2_000_000, 20.0), None);
+        assert_eq!(step(&expression, &mut state, 3_000_000, 21.0), Some(1.0));
+
+        // Every state starts over
+        let mut fresh = expression.new_state();
+        assert_eq!(step(&expression, &mut fresh, 4_000_000, 0.0), None);
+    }
+
+    #[test]
+    fn derivative_skips_missing_samples() {
+        let expression = parse("derivative(x)");
+        let mut state = expression.new_state();
+
+        assert_eq!(step(&expression, &mut state, 0, 1.0), None);
+        assert_eq!(expression.evaluate(&mut state, 500_000, &|_| None), None);
+        assert_eq!(step(&expression, &mut state, 1_000_000, 3.0), Some(2.0));
+    }
+
+    #[test]
+    fn moving_average_state() {
+        let expression = parse("moving_average(x, 3)");
+        let mut state = expression.new_state();
+
+        assert_eq!(step(&expression, &mut state, 0, 3.0), Some(3.0));
+        assert_eq!(step(&expression, &mut state, 1, 6.0), Some(4.5));
+        assert_eq!(step(&expression, &mut state, 2, 9.0), Some(6.0));
+        // The oldest sample leaves the window
+        assert_eq!(step(&expression, &mut state, 3, 12.0), Some(9.0));
+    }
+
+    #[test]
+    fn separate_calls_keep_separate_state() {
+        let expression = parse("moving_average(x, 2) - moving_average(x, 1)");
+        let mut state = expression.new_state();
+
+        assert_eq!(step(&expression, &mut state, 0, 2.0), Some(0.0));
+        assert_eq!(step(&expression, &mut state, 1, 4.0), Some(-1.0));
+    }
+
+    #[test]
+    fn malformed_definitions() {
+        assert_eq!(error(""), "unexpected end of expression");
+        assert_eq!(error("1 +"), "unexpected end of expression");
+        assert_eq!(error("1 $ 2"), "unexpected character `$`");
+        assert_eq!(error("1..2"), "invalid number `1..2`");
+        assert_eq!(error("(1 + 2"), "expected `)`, found end of expression");
+        assert_eq!(error("1 + 2)"), "unexpected `)` after end of expression");
+        assert_eq!(error("1 2"), "unexpected number 2 after end of expression");
+        assert_eq!(error("* 2"), "unexpected `*`");
+        assert_eq!(error("sqrt(1"), "unclosed call to `sqrt`");
+        assert_eq!(error("sqrt(1 2)"), "expected `,` or `)`, found number 2");
+        assert_eq!(error("sqrt(1, 2)"), "`sqrt` takes 1 argument(s), found 2");
+        assert_eq!(error("atan2(1)"), "`atan2` takes 2 argument(s), found 1");
+        assert_eq!(
+            error("derivative()"),
+            "`derivative` takes 1 argument(s), found 0"
+        );
+        assert_eq!(error("nope(1)"), "unknown function `nope`");
+        assert_eq!(
+            error("moving_average(x, 0)"),
+            "the window of `moving_average` must be a positive whole number"
+        );
+        assert_eq!(
+            error("moving_average(x, 2.5)"),
+            "the window of `moving_average` must be a positive whole number"
+        );
+        assert_eq!(
+            error("moving_average(x, y)"),
+            "the window of `moving_average` must be a positive whole number"
+        );
+    }
+}
@@ -0,0 +1,52 @@
+use serde::{Deserialize, Serialize};
+use serde_cbor::Value;
+use ts_rs::{export, TS};
+
+/// The key of the map that firmware identifies itself with, both in its boot
+/// packet and in response to an [`identify_request`]
+const FIRMWARE_KEY: &str = "firmware";
+
+/// Identifies the build of the firmware running on a device
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+pub struct FirmwareInfo {
+    /// the name of the firmware image
+    pub name: String,
+    /// the semantic version of the firmware
+    pub version: String,
+    /// the git commit the firmware was built from
+    pub git_hash: String,
+    /// the version of the telemetry packet layout the firmware sends
+    pub schema_version: u32,
+}
+
+export! {
+    (declare) FirmwareInfo => "./web/types/generated/firmware.d.ts"
+}
+
+impl FirmwareInfo {
+    /// Extracts the firmware identification from a packet, if the packet is one.
+    ///
+    /// Identification packets are maps of the form `{ "firmware": { ... } }`.
+    pub fn from_packet(packet: &Value) -> Option<Result<FirmwareInfo, serde_cbor::Error>> {
+        match packet {
+            Value::Map(fields) => fields
+                .get(&Value::Text(FIRMWARE_KEY.to_string()))
+                .map(|info| serde_cbor::value::from_value(info.clone())),
+            _ => None,
+        }
+    }
+}
+
+/// The request sent to a device after connecting, asking it to send its
+/// identification packet again in case the boot packet was missed.
+pub fn identify_request() -> Vec<u8> {
+    #[derive(Serialize)]
+    struct IdentifyRequest {
+        request: &'static str,
+    }
+
+    serde_cbor::to_vec(&IdentifyRequest {
+        request: "identify",
+    })
+    .expect("Failed to serialize identify request, this should not be able to happen")
+}
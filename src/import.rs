@@ -0,0 +1,436 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, fs, io,
+};
+
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use serde_cbor::Value;
+
+use crate::{
+    config::config,
+    firmware::FirmwareInfo,
+    ingest::{DecodedPacket, PacketDecoder},
+    schema::SchemaReport,
+    telemetry::{
+        FieldType, Identifier, PacketField, TelemetryDictionary, TelemetryPacket,
+        TELEMETRY_TIME_FIELD,
+    },
+};
+
+/// The most problems listed when an import is rejected
+const MAX_PROBLEMS: usize = 20;
+
+lazy_static! {
+    /// Import mappings keyed by their name
+    pub static ref IMPORT_MAPPINGS: BTreeMap<String, ImportMapping> =
+        load_mappings().expect("Failed to load import mappings");
+}
+
+#[derive(Debug, Deserialize)]
+struct MappingsFile {
+    #[serde(default)]
+    mappings: BTreeMap<String, ImportMapping>,
+}
+
+/// Describes how the columns of a data file captured by other means, such as an
+/// SD card log, map to telemetry
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImportMapping {
+    pub format: ImportFormat,
+    /// the column holding the time of each row
+    pub time: String,
+    /// microseconds per unit of the time column
+    #[serde(default = "default_time_scale")]
+    pub time_scale: f64,
+    /// the schema version of the dictionary the values are validated against,
+    /// the latest if not given. CBOR files that identify the firmware that
+    /// wrote them are read with the dictionary for its schema version instead.
+    pub schema_version: Option<u32>,
+    /// telemetry keys, keyed by the column they are read from. Values are read
+    /// as the firmware would send them, before calibration.
+    pub columns: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+    /// comma separated values with a header row
+    Csv,
+    /// a sequence of CBOR maps, like the packets sent by the firmware
+    Cbor,
+}
+
+fn default_time_scale() -> f64 {
+    1.0
+}
+
+impl ImportMapping {
+    pub fn dictionary(&self) -> &'static TelemetryDictionary {
+        self.schema_version
+            .and_then(TelemetryDictionary::for_schema)
+            .unwrap_or_else(TelemetryDictionary::latest)
+    }
+
+    /// The packet field each column is written to
+    fn fields(
+        &self,
+        dictionary: &'static TelemetryDictionary,
+    ) -> Vec<(&str, PacketField<'static>)> {
+        self.columns
+            .iter()
+            .filter_map(|(column, key)| {
+                let field = dictionary.metadata(Identifier::from_key(key))?.field()?;
+
+                Some((column.as_str(), *field))
+            })
+            .collect()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !(self.time_scale > 0.0 && self.time_scale.is_finite()) {
+            return Err("the time scale must be greater than zero".into());
+        }
+
+        if let Some(schema_version) = self.schema_version {
+            if TelemetryDictionary::for_schema(schema_version).is_none() {
+                return Err(format!(
+                    "no dictionary for schema version {}",
+                    schema_version
+                ));
+            }
+        }
+
+        let dictionary = self.dictionary();
+        let mut keys = BTreeSet::new();
+
+        for (column, key) in &self.columns {
+            if column == &self.time {
+                return Err(format!(
+                    "the time column {} is also mapped to {}",
+                    column, key
+                ));
+            }
+
+            if !keys.insert(key) {
+                return Err(format!("{} is mapped from more than one column", key));
+            }
+
+            match dictionary.metadata(Identifier::from_key(key)) {
+                Some(object) if object.field().is_some() => {}
+                Some(_) => return Err(format!("{} is derived, so it can not be imported", key)),
+                None => return Err(format!("{} is not in the telemetry dictionary", key)),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn load_mappings() -> io::Result<BTreeMap<String, ImportMapping>> {
+    let contents = match fs::read_to_string(&config().paths.mappings) {
+        Ok(contents) => contents,
+        // Running without import mappings is perfectly valid
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e),
+    };
+
+    let MappingsFile { mappings } =
+        toml::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    for (name, mapping) in &mappings {
+        mapping.validate().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid import mapping {}: {}", name, e),
+            )
+        })?;
+    }
+
+    Ok(mappings)
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    /// the file could not be read in the mapping's format
+    Unreadable(String),
+    /// rows of the file do not match the telemetry dictionary
+    Invalid { rows: u64, problems: Vec<String> },
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Unreadable(reason) => write!(f, "unreadable file: {}", reason),
+            ImportError::Invalid { rows, problems } => write!(
+                f,
+                "{} rows do not match the telemetry dictionary: {}",
+                rows,
+                problems.join("; ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+/// The telemetry read from an imported file
+#[derive(Debug)]
+pub struct ImportedData {
+    pub packets: BTreeMap<u64, TelemetryPacket>,
+    /// the firmware the file identified as written by, if it did
+    pub firmware: Option<FirmwareInfo>,
+    /// the dictionary the packets were decoded with
+    pub dictionary: &'static TelemetryDictionary,
+    pub report: SchemaReport,
+}
+
+/// Reads a data file using a mapping, turning every row into a packet as if it
+/// was sent by the firmware and decoding it the same way
+pub fn import(mapping: &ImportMapping, data: &[u8]) -> Result<ImportedData, ImportError> {
+    let mut dictionary = mapping.dictionary();
+    let mut fields = mapping.fields(dictionary);
+
+    let (entries, kind) = match mapping.format {
+        ImportFormat::Csv => (csv_rows(data)?, "row"),
+        ImportFormat::Cbor => (cbor_rows(data)?, "entry"),
+    };
+
+    let mut decoder = PacketDecoder::new(dictionary);
+    let mut firmware = None;
+    let mut report = SchemaReport::default();
+    let mut packets = BTreeMap::new();
+    let mut invalid_rows = 0;
+    let mut problems = Vec::new();
+
+    for (index, entry) in entries.into_iter().enumerate() {
+        // CSV rows are numbered from the first after the header, CBOR entries
+        // from the first in the file, identification entries included
+        let number = index + 1;
+
+        let result = match entry {
+            Entry::Row(row) => row_packet(mapping, &fields, row),
+            // The firmware identifies itself at the start of its log, and the
+            // rows after it are laid out as its schema version says
+            Entry::Firmware(Ok(info)) => {
+                dictionary = TelemetryDictionary::for_schema(info.schema_version)
+                    .unwrap_or_else(TelemetryDictionary::latest);
+                fields = mapping.fields(dictionary);
+                decoder = PacketDecoder::new(dictionary);
+                firmware = Some(info);
+
+                continue;
+            }
+            Entry::Firmware(Err(e)) => Err(format!("invalid firmware identification: {}", e)),
+        };
+
+        let packet = match result {
+            Ok(packet) => packet,
+            Err(problem) => {
+                invalid_rows += 1;
+                if problems.len() < MAX_PROBLEMS {
+                    problems.push(format!("{} {}: {}", kind, number, problem));
+                }
+
+                continue;
+            }
+        };
+
+        report.check(dictionary, &packet);
+
+        if let DecodedPacket::Telemetry(packet) = decoder.decode(&packet) {
+            packets.insert(packet.running_us, packet);
+        }
+    }
+
+    if invalid_rows > 0 {
+        return Err(ImportError::Invalid {
+            rows: invalid_rows,
+            problems,
+        });
+    }
+
+    if packets.is_empty() {
+        return Err(ImportError::Unreadable("the file has no rows".into()));
+    }
+
+    Ok(ImportedData {
+        packets,
+        firmware,
+        dictionary,
+        report,
+    })
+}
+
+/// The cells of a row keyed by their column, read as CBOR values if the file is
+/// CBOR, or left as text if it is CSV
+type Row = BTreeMap<String, Cell>;
+
+/// An entry of an imported file
+#[derive(Debug)]
+enum Entry {
+    Row(Row),
+    /// the identification the firmware writes at the start of its log, which is
+    /// not telemetry
+    Firmware(Result<FirmwareInfo, serde_cbor::Error>),
+}
+
+#[derive(Debug)]
+enum Cell {
+    Text(String),
+    Value(Value),
+}
+
+fn csv_rows(data: &[u8]) -> Result<Vec<Entry>, ImportError> {
+    let text = std::str::from_utf8(data)
+        .map_err(|e| ImportError::Unreadable(format!("not UTF-8 text: {}", e)))?;
+
+    let mut lines = text
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.trim().is_empty());
+
+    let header = match lines.next() {
+        Some(header) => csv_fields(header),
+        None => return Err(ImportError::Unreadable("the file is empty".into())),
+    };
+
+    Ok(lines
+        .map(|line| {
+            Entry::Row(
+                header
+                    .iter()
+                    .cloned()
+                    .zip(csv_fields(line).into_iter().map(Cell::Text))
+                    .collect(),
+            )
+        })
+        .collect())
+}
+
+/// Splits a line of CSV into its fields, unquoting quoted fields
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(char) = chars.next() {
+        match char {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            char => field.push(char),
+        }
+    }
+    fields.push(field);
+
+    fields
+        .into_iter()
+        .map(|field| field.trim().to_string())
+        .collect()
+}
+
+fn cbor_rows(data: &[u8]) -> Result<Vec<Entry>, ImportError> {
+    serde_cbor::Deserializer::from_slice(data)
+        .into_iter::<Value>()
+        .enumerate()
+        .map(|(index, value)| match value.map(cbor_entry) {
+            Ok(Some(entry)) => Ok(entry),
+            Ok(None) => Err(ImportError::Unreadable(format!(
+                "entry {} is not a map",
+                index + 1
+            ))),
+            Err(e) => Err(ImportError::Unreadable(format!(
+                "failed to parse entry {}: {}",
+                index + 1,
+                e
+            ))),
+        })
+        .collect()
+}
+
+fn cbor_entry(value: Value) -> Option<Entry> {
+    if let Some(firmware) = FirmwareInfo::from_packet(&value) {
+        return Some(Entry::Firmware(firmware));
+    }
+
+    match value {
+        Value::Map(fields) => Some(Entry::Row(
+            fields
+                .into_iter()
+                .filter_map(|(key, value)| match key {
+                    Value::Text(key) => Some((key, Cell::Value(value))),
+                    _ => None,
+                })
+                .collect(),
+        )),
+        _ => None,
+    }
+}
+
+/// Builds the packet the firmware would have sent for a row
+fn row_packet(
+    mapping: &ImportMapping,
+    fields: &[(&str, PacketField<'static>)],
+    mut row: Row,
+) -> Result<Value, String> {
+    let time = match row.remove(&mapping.time) {
+        Some(Cell::Text(text)) => text
+            .parse::<f64>()
+            .map_err(|_| format!("the time {:?} is not a number", text))?,
+        Some(Cell::Value(Value::Integer(time))) => time as f64,
+        Some(Cell::Value(Value::Float(time))) => time,
+        Some(Cell::Value(_)) => return Err("the time is not a number".into()),
+        None => return Err(format!("the time column {} is missing", mapping.time)),
+    };
+
+    let running_us = (time * mapping.time_scale).round();
+    if !(running_us >= 0.0 && running_us.is_finite()) {
+        return Err(format!("the time {} is negative", time));
+    }
+
+    let mut packet = BTreeMap::new();
+    packet.insert(
+        Value::Text(TELEMETRY_TIME_FIELD.name.to_string()),
+        Value::Integer(running_us as i128),
+    );
+
+    for (column, field) in fields {
+        let value = match row.remove(*column) {
+            Some(Cell::Text(text)) if text.is_empty() => continue,
+            Some(Cell::Text(text)) => parse_cell(&text, field.ty)
+                .ok_or_else(|| format!("{} {:?} is not {}", column, text, field.ty.format()))?,
+            Some(Cell::Value(value)) if field.ty.accepts(&value) => value,
+            Some(Cell::Value(_)) => return Err(format!("{} is not {}", column, field.ty.format())),
+            None => continue,
+        };
+
+        packet.insert(Value::Text(field.name.to_string()), value);
+    }
+
+    Ok(Value::Map(packet))
+}
+
+fn parse_cell(text: &str, ty: FieldType) -> Option<Value> {
+    let boolean = || match text {
+        "true" | "TRUE" | "True" => Some(Value::Bool(true)),
+        "false" | "FALSE" | "False" => Some(Value::Bool(false)),
+        _ => None,
+    };
+
+    match ty {
+        FieldType::Float => text.parse().ok().map(Value::Float),
+        FieldType::Integer => text.parse().ok().map(Value::Integer),
+        FieldType::Boolean => boolean().or_else(|| match text {
+            "1" => Some(Value::Bool(true)),
+            "0" => Some(Value::Bool(false)),
+            _ => None,
+        }),
+        FieldType::Enumeration => text.parse().ok().map(Value::Integer).or_else(boolean),
+    }
+}
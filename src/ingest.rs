@@ -1,105 +1,460 @@
 use std::{
     collections::BTreeMap,
-    io::{self, BufReader},
-    sync::Arc,
+    io,
+    sync::atomic::{AtomicBool, Ordering},
     time::{Duration, Instant},
 };
 
 use async_std::{sync::RwLock, task};
-use crossfire::mpsc::TxUnbounded;
 use lazy_static::lazy_static;
 use log::{debug, error, info, trace, warn};
-use serde_cbor::error::Category;
-use serialport::SerialPort;
+use serde::Serialize;
+use serde_cbor::Value;
+use ts_rs::{export, TS};
 
-use crate::telemetry::TelemetryPacket;
+use crate::{
+    derived::DerivedTelemetry,
+    firmware::{identify_request, FirmwareInfo},
+    queue::EventSender,
+    recording::RecordingWriter,
+    relay::RelayPublisher,
+    schema::{SchemaReport, SCHEMA_REPORT},
+    session::{unix_millis, SessionId, SessionMetadata, SESSION_CATALOG},
+    source::{PacketError, Source},
+    telemetry::{TelemetryDictionary, TelemetryPacket},
+};
 
 lazy_static! {
-    pub static ref TIMESCALE_DATA: Arc<RwLock<BTreeMap<u64, TelemetryPacket>>> =
-        Arc::new(RwLock::new(BTreeMap::new()));
+    /// Statistics of the links to every source ingested from, keyed by source name
+    pub static ref LINK_STATS: RwLock<BTreeMap<String, LinkStats>> =
+        RwLock::new(BTreeMap::new());
+}
+
+/// Set while a source is being ingested, see [`IngestLock`]
+static INGESTING: AtomicBool = AtomicBool::new(false);
+
+/// How often the link statistics are published
+const LINK_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Statistics describing the health of the link to a source
+#[derive(Debug, Default, Clone, Serialize, TS)]
+pub struct LinkStats {
+    /// the name of the source
+    pub source: String,
+    /// bytes received from the source, if it counts them
+    pub bytes: u64,
+    /// packets decoded and stored
+    pub packets: u64,
+    /// packets that could not be parsed as CBOR
+    pub malformed: u64,
+    /// packets that were parsed, but could not be decoded
+    pub dropped: u64,
+    /// the number of times the source reconnected after losing its link
+    pub reconnects: u64,
+    /// packets per second over the last reporting interval
+    pub rate: f64,
+    /// the time, in milliseconds since the UNIX epoch, the source was connected at
+    pub connected: u64,
+    /// the time, in milliseconds since the UNIX epoch, the last packet was received at
+    pub last_packet: Option<u64>,
+}
+
+export! {
+    (declare) LinkStats => "./web/types/generated/link.d.ts"
+}
+
+/// Events produced by the ingest thread for the connected client
+#[derive(Debug)]
+pub enum IngestEvent {
+    /// the packets that follow are stored in this session
+    Session(SessionId),
+    Telemetry(TelemetryPacket),
+    Schema(SchemaReport),
+    Firmware(FirmwareInfo),
+    Link(LinkStats),
+}
+
+/// Held for as long as a source is ingested. The schema report, the live session
+/// and the relay are shared by the whole server, so only one source can be
+/// ingested at a time.
+#[derive(Debug)]
+pub struct IngestLock(());
+
+impl IngestLock {
+    /// Takes the lock, or `None` if another source is already being ingested
+    pub fn acquire() -> Option<Self> {
+        INGESTING
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_| IngestLock(()))
+    }
+}
+
+impl Drop for IngestLock {
+    fn drop(&mut self) {
+        INGESTING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Turns the packets sent by a device into telemetry, switching telemetry
+/// dictionaries as the device identifies its firmware
+pub struct PacketDecoder {
+    dictionary: &'static TelemetryDictionary,
+    derived: DerivedTelemetry,
+}
+
+/// The result of decoding a single packet
+#[derive(Debug)]
+pub enum DecodedPacket {
+    Telemetry(TelemetryPacket),
+    Firmware(FirmwareInfo),
+    InvalidFirmware(serde_cbor::Error),
+    MissingTimestamp,
 }
 
+impl PacketDecoder {
+    pub fn new(dictionary: &'static TelemetryDictionary) -> Self {
+        PacketDecoder {
+            dictionary,
+            derived: DerivedTelemetry::new(dictionary),
+        }
+    }
+
+    /// The dictionary packets are currently decoded with
+    pub fn dictionary(&self) -> &'static TelemetryDictionary {
+        self.dictionary
+    }
+
+    pub fn decode(&mut self, value: &Value) -> DecodedPacket {
+        match FirmwareInfo::from_packet(value) {
+            Some(Ok(firmware)) => {
+                self.dictionary = TelemetryDictionary::for_schema(firmware.schema_version)
+                    .unwrap_or_else(TelemetryDictionary::latest);
+                self.derived = DerivedTelemetry::new(self.dictionary);
+
+                DecodedPacket::Firmware(firmware)
+            }
+            Some(Err(e)) => DecodedPacket::InvalidFirmware(e),
+            None => match self.dictionary.decode(value) {
+                Some(mut packet) => {
+                    self.derived.evaluate(&mut packet);
+
+                    DecodedPacket::Telemetry(packet)
+                }
+                None => DecodedPacket::MissingTimestamp,
+            },
+        }
+    }
+}
+
+/// Reads packets from a source into a new session in the catalog until the
+/// source closes or `tx` is closed, writing every packet it sends to the
+/// recording if one is given. The lock is held until the source is closed.
 pub fn ingest(
-    tx: TxUnbounded<TelemetryPacket>,
-    serial_port: Box<dyn SerialPort>,
+    _lock: IngestLock,
+    tx: EventSender,
+    name: String,
+    session: SessionMetadata,
+    mut source: Box<dyn Source>,
+    mut recording: Option<RecordingWriter>,
 ) -> io::Result<()> {
-    // TODO: ENSURE ONLY ONE INGEST TASK AT A TIME
-    task::block_on(TIMESCALE_DATA.write()).clear();
+    *task::block_on(SCHEMA_REPORT.write()) = SchemaReport::default();
+
+    let mut stats = LinkStats {
+        source: name.clone(),
+        connected: unix_millis(),
+        ..LinkStats::default()
+    };
 
-    let mut serial_port = BufReader::new(serial_port);
+    let mut decoder = PacketDecoder::new(session.dictionary);
 
-    let mut packets =
-        serde_cbor::Deserializer::from_reader(&mut serial_port).into_iter::<TelemetryPacket>();
+    let relay = RelayPublisher::new();
+    let (mut session_id, mut data) = task::block_on(SESSION_CATALOG.write()).begin(session.clone());
+    relay.begin_session(session_id, &session);
+    // A client that has already gone away is noticed by the sends below
+    let _ = tx.send(IngestEvent::Session(session_id));
+    // The link's error counts when the current session started
+    let mut errors_before_session = (0, 0);
+    let mut packets_before_session = 0;
+
+    // Ask the firmware to identify itself in case its boot packet was already sent
+    if let Err(e) = source.request(&identify_request()) {
+        warn!("Failed to request firmware identification: {}", e);
+    }
 
     let mut last_report = Instant::now();
-    let mut packets_accumulator = 0;
-    const SAMPLING_DURATION: Duration = Duration::from_secs(10);
+    let mut packets_at_last_report = 0;
 
     let mut seeking = true;
 
-    for packet in &mut packets {
-        match packet {
-            Ok(packet) => {
-                if seeking {
-                    debug!("Found first packet");
-                    seeking = false;
-                }
+    loop {
+        let next = source.next_packet();
 
-                // Store the data in a timescale "db"
-                task::block_on(TIMESCALE_DATA.write()).insert(packet.running_us, packet);
+        // Packets left pending when the link goes quiet are written out once a
+        // read times out, rather than waiting on the next packet
+        data.flush_if_due();
 
-                if tx.send(packet).is_err() {
-                    debug!("Transmit channel closed, shutting down");
+        if let Some(session) = source.take_session() {
+            let mut catalog = task::block_on(SESSION_CATALOG.write());
 
-                    break;
+            match catalog.get_mut(session_id) {
+                Some(current) if current.info.metadata.started == session.started => {
+                    current.info.metadata.ended = session.ended;
                 }
+                _ => {
+                    info!("Source started a new session on {}", session.port);
 
-                packets_accumulator += 1;
-                if Instant::now().duration_since(last_report) > SAMPLING_DURATION {
-                    trace!(
-                        "Reading at {} p/s",
-                        packets_accumulator as f64 / SAMPLING_DURATION.as_secs_f64()
-                    );
+                    *task::block_on(SCHEMA_REPORT.write()) = SchemaReport::default();
+                    decoder = PacketDecoder::new(session.dictionary);
 
-                    packets_accumulator = 0;
-                    last_report = Instant::now();
+                    catalog.end(session_id, unix_millis());
+
+                    // Such as the session begun before a relay source
+                    // received the upstream's, which would be left empty
+                    if stats.packets == packets_before_session {
+                        let _ = catalog.remove(session_id);
+                    }
+
+                    let (id, writer) = catalog.begin(session.clone());
+                    relay.begin_session(id, &session);
+                    session_id = id;
+                    data = writer;
+                    errors_before_session = (stats.malformed, stats.dropped);
+                    packets_before_session = stats.packets;
+
+                    if tx.send(IngestEvent::Session(session_id)).is_err() {
+                        debug!("Transmit channel closed, shutting down");
+
+                        break;
+                    }
                 }
             }
-            Err(e) => {
+        }
+
+        // Reported after every read, not only after telemetry, as a link sending
+        // only malformed packets or nothing at all is when its statistics matter
+        if last_report.elapsed() >= LINK_REPORT_INTERVAL {
+            stats.bytes = source.bytes_received();
+            stats.reconnects = source.reconnects();
+            stats.rate = (stats.packets - packets_at_last_report) as f64
+                / last_report.elapsed().as_secs_f64();
+
+            trace!("Reading at {} p/s from {}", stats.rate, name);
+
+            packets_at_last_report = stats.packets;
+            last_report = Instant::now();
+
+            task::block_on(LINK_STATS.write()).insert(name.clone(), stats.clone());
+            record_errors(session_id, &stats, errors_before_session);
+
+            if tx.send(IngestEvent::Link(stats.clone())).is_err() {
+                debug!("Transmit channel closed, shutting down");
+
+                break;
+            }
+        }
+
+        match next {
+            Ok(value) => {
                 if seeking {
-                    continue;
+                    // Anything other than a map is line noise from before the first packet
+                    if !matches!(value, Value::Map(_)) {
+                        continue;
+                    }
+
+                    debug!("Found first packet");
+                    seeking = false;
                 }
 
-                if e.is_scratch_too_small() {
-                    error!("Scratch buffer was too small to hold incoming packet, skipping this packet.");
-                    warn!("If this persists, the scratch buffer may need to be resized, or malformed packets may be being received");
+                if let Some(writer) = &mut recording {
+                    if let Err(e) = writer.write(&value) {
+                        error!("Failed to write to the recording, closing device: {}", e);
 
-                    continue;
+                        break;
+                    }
                 }
 
-                match e.classify() {
-                    Category::Io => {
-                        error!("Encountered I/O error, closing device");
-                        break;
+                relay.publish(&value);
+
+                let packet = match decoder.decode(&value) {
+                    DecodedPacket::Firmware(firmware) => {
+                        info!(
+                            "Device identified as {} {} ({}), schema version {}",
+                            firmware.name,
+                            firmware.version,
+                            firmware.git_hash,
+                            firmware.schema_version
+                        );
+
+                        if let Some(session) =
+                            task::block_on(SESSION_CATALOG.write()).get_mut(session_id)
+                        {
+                            session.info.metadata.identify(firmware.clone());
+                        }
+
+                        if decoder.dictionary().schema_version != firmware.schema_version {
+                            warn!(
+                                "No telemetry dictionary for schema version {}, falling back to version {}",
+                                firmware.schema_version, decoder.dictionary().schema_version
+                            );
+                        }
+
+                        // Packets so far were checked against the wrong dictionary
+                        *task::block_on(SCHEMA_REPORT.write()) = SchemaReport::default();
+
+                        if tx.send(IngestEvent::Firmware(firmware)).is_err() {
+                            debug!("Transmit channel closed, shutting down");
+
+                            break;
+                        }
+
+                        continue;
                     }
-                    Category::Syntax | Category::Data => {
+                    DecodedPacket::InvalidFirmware(e) => {
                         warn!(
-                            "Failed to parse packet at {}. Skipping... : {}",
-                            e.offset(),
+                            "Failed to parse firmware identification. Skipping... : {}",
                             e
                         );
+
+                        continue;
+                    }
+                    DecodedPacket::Telemetry(packet) => Some(packet),
+                    DecodedPacket::MissingTimestamp => None,
+                };
+
+                let report = {
+                    let mut report = task::block_on(SCHEMA_REPORT.write());
+
+                    if report.check(decoder.dictionary(), &value) {
+                        Some(report.clone())
+                    } else {
+                        None
                     }
-                    Category::Eof => {
-                        info!("Reached EOF, closing device");
+                };
+
+                if let Some(report) = report {
+                    if !report.compatible {
+                        warn!(
+                            "Packets do not match the telemetry dictionary: {:?}",
+                            report
+                        );
+                    }
+
+                    if tx.send(IngestEvent::Schema(report)).is_err() {
+                        debug!("Transmit channel closed, shutting down");
+
+                        break;
+                    }
+                }
+
+                let packet = match packet {
+                    Some(packet) => packet,
+                    None => {
+                        warn!("Packet is missing its timestamp. Skipping...");
+                        stats.dropped += 1;
+
+                        continue;
+                    }
+                };
+
+                // Such as after the firmware restarts, once the session has
+                // spilled packets to disk that it can not be merged with
+                if data.goes_back(packet.running_us) {
+                    info!("Telemetry went back before the packets spilled to disk, starting a new session");
+
+                    let mut catalog = task::block_on(SESSION_CATALOG.write());
+                    let mut metadata = catalog
+                        .get_mut(session_id)
+                        .map_or_else(|| session.clone(), |current| current.info.metadata.clone());
+                    metadata.started = unix_millis();
+                    metadata.ended = None;
+
+                    catalog.end(session_id, unix_millis());
+
+                    let (id, writer) = catalog.begin(metadata.clone());
+                    drop(catalog);
+
+                    relay.begin_session(id, &metadata);
+                    session_id = id;
+                    data = writer;
+                    errors_before_session = (stats.malformed, stats.dropped);
+                    packets_before_session = stats.packets;
+
+                    if tx.send(IngestEvent::Session(session_id)).is_err() {
+                        debug!("Transmit channel closed, shutting down");
 
                         break;
                     }
                 }
+
+                data.insert(packet.clone());
+                stats.packets += 1;
+                stats.last_packet = Some(unix_millis());
+
+                if tx.send(IngestEvent::Telemetry(packet)).is_err() {
+                    debug!("Transmit channel closed, shutting down");
+
+                    break;
+                }
+            }
+            Err(PacketError::Malformed(e)) => {
+                if seeking {
+                    continue;
+                }
+
+                if e.is_scratch_too_small() {
+                    error!("Scratch buffer was too small to hold incoming packet, skipping this packet.");
+                    warn!("If this persists, the scratch buffer may need to be resized, or malformed packets may be being received");
+                } else {
+                    warn!(
+                        "Failed to parse packet at {}. Skipping... : {}",
+                        e.offset(),
+                        e
+                    );
+                }
+
+                stats.malformed += 1;
+            }
+            Err(PacketError::Idle) => {
+                // Nothing else notices the client leaving while the link is quiet
+                if tx.is_closed() {
+                    debug!("Transmit channel closed, shutting down");
+
+                    break;
+                }
+            }
+            Err(PacketError::Closed(reason)) => {
+                info!("Source closed ({}), closing device", reason);
+
+                break;
             }
         }
     }
 
+    stats.bytes = source.bytes_received();
+    stats.reconnects = source.reconnects();
+    stats.rate = 0.0;
+    record_errors(session_id, &stats, errors_before_session);
+    task::block_on(LINK_STATS.write()).insert(name, stats);
+
+    data.flush();
+    task::block_on(SESSION_CATALOG.write()).end(session_id, unix_millis());
+    relay.end_session(unix_millis());
+
+    if let Some(writer) = recording {
+        writer.finish()?;
+    }
+
     trace!("Ingest thread shut down");
 
     Ok(())
 }
+
+/// Copies the error counts of the link since the session started into the
+/// session's catalog entry
+fn record_errors(session_id: SessionId, stats: &LinkStats, before: (u64, u64)) {
+    if let Some(session) = task::block_on(SESSION_CATALOG.write()).get_mut(session_id) {
+        session.info.malformed = stats.malformed - before.0;
+        session.info.dropped = stats.dropped - before.1;
+    }
+}
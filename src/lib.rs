@@ -0,0 +1,129 @@
+use std::collections::BTreeSet;
+
+use calibration::CALIBRATIONS;
+use color_eyre::eyre::{eyre, Context};
+use config::{Cli, Config};
+use derived::DERIVED_DEFINITIONS;
+use import::IMPORT_MAPPINGS;
+use lazy_static::initialize;
+use log::warn;
+use simplelog::{ColorChoice, CombinedLogger, ConfigBuilder, TermLogger, TerminalMode};
+use telemetry::{Identifier, TELEMETRY_DICTIONARIES};
+
+pub mod assets;
+pub mod calibration;
+pub mod compare;
+pub mod config;
+pub mod derived;
+pub mod firmware;
+pub mod import;
+pub mod ingest;
+pub mod objects;
+pub mod persistence;
+pub mod queue;
+pub mod recording;
+pub mod relay;
+pub mod routes;
+pub mod schema;
+pub mod serial;
+pub mod session;
+pub mod simulator;
+pub mod source;
+pub mod store;
+pub mod telemetry;
+
+pub type State = ();
+
+/// Loads the configuration and sets up logging and the telemetry dictionaries
+/// shared by all of the binaries. Returns `None` if the program should exit
+/// after printing the configuration.
+pub fn setup(
+    cli: &Cli,
+    binary: &'static str,
+    terminal: TerminalMode,
+) -> color_eyre::Result<Option<&'static Config>> {
+    let config = cli.load().wrap_err("Invalid configuration")?;
+
+    if cli.print_config {
+        print!(
+            "{}",
+            toml::to_string_pretty(&config).wrap_err("Failed to serialize configuration")?
+        );
+
+        return Ok(None);
+    }
+
+    let config = config.install();
+
+    // FIXME: here to catch panics early
+    initialize(&TELEMETRY_DICTIONARIES);
+    initialize(&IMPORT_MAPPINGS);
+
+    // Telemetry sent by the device, which is all that can be calibrated and
+    // which derived telemetry must not overwrite
+    let native_keys = TELEMETRY_DICTIONARIES
+        .iter()
+        .flat_map(|dictionary| &dictionary.values)
+        .filter(|object| object.field().is_some())
+        .map(|object| object.identifier().key)
+        .collect::<BTreeSet<_>>();
+
+    for definition in DERIVED_DEFINITIONS.iter() {
+        if native_keys.contains(definition.key.as_str()) {
+            return Err(eyre!(
+                "Derived telemetry {} has the same key as telemetry sent by the device",
+                definition.key
+            ));
+        }
+    }
+
+    for key in CALIBRATIONS.keys() {
+        if !native_keys.contains(key.as_str()) {
+            return Err(eyre!(
+                "Calibration for {} does not match any telemetry sent by the device",
+                key
+            ));
+        }
+    }
+
+    CombinedLogger::init(vec![
+        TermLogger::new(
+            config.log_level()?,
+            ConfigBuilder::new()
+                .add_filter_allow_str(module_path!())
+                .add_filter_allow_str(binary)
+                .build(),
+            terminal,
+            ColorChoice::Auto,
+        ),
+        TermLogger::new(
+            config.dependency_log_level()?,
+            ConfigBuilder::new()
+                .add_filter_ignore_str(module_path!())
+                .add_filter_ignore_str(binary)
+                .set_target_level(simplelog::LevelFilter::Error)
+                .build(),
+            terminal,
+            ColorChoice::Auto,
+        ),
+    ])
+    .wrap_err("Failed to initialize logger")?;
+
+    for definition in DERIVED_DEFINITIONS.iter() {
+        let available = TELEMETRY_DICTIONARIES.iter().any(|dictionary| {
+            dictionary
+                .metadata(Identifier::from_key(&definition.key))
+                .is_some()
+        });
+
+        if !available {
+            warn!(
+                "Derived telemetry {} ({}) reads telemetry that no dictionary provides",
+                definition.key,
+                definition.expression.source()
+            );
+        }
+    }
+
+    Ok(Some(config))
+}
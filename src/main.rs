@@ -1,71 +1,87 @@
-use color_eyre::eyre::Context;
-use lazy_static::initialize;
-use simplelog::{
-    ColorChoice, CombinedLogger, ConfigBuilder, LevelFilter, TermLogger, TerminalMode,
-};
-use telemetry::TELEMETRY_VALUES;
+use color_eyre::eyre::{eyre, Context};
+use openmct_pico_pilot_ingest::{config::Cli, relay, routes, setup};
+use simplelog::TerminalMode;
+use structopt::StructOpt;
 use tide::{http::headers::HeaderValue, security::CorsMiddleware, sse};
 
-mod ingest;
-mod routes;
-mod serial;
-mod telemetry;
-
-type State = ();
-
 #[async_std::main]
 async fn main() -> color_eyre::Result<()> {
     color_eyre::install()?;
 
-    // FIXME: here to catch panics early
-    initialize(&TELEMETRY_VALUES);
-
-    CombinedLogger::init(vec![
-        TermLogger::new(
-            LevelFilter::Trace,
-            ConfigBuilder::new()
-                .add_filter_allow_str(module_path!())
-                .build(),
-            TerminalMode::Mixed,
-            ColorChoice::Auto,
-        ),
-        TermLogger::new(
-            LevelFilter::Info,
-            ConfigBuilder::new()
-                .add_filter_ignore_str(module_path!())
-                .set_target_level(LevelFilter::Error)
-                .build(),
-            TerminalMode::Mixed,
-            ColorChoice::Auto,
-        ),
-    ])
-    .wrap_err("Failed to initialize logger")?;
+    let config = match setup(&Cli::from_args(), module_path!(), TerminalMode::Mixed)? {
+        Some(config) => config,
+        None => return Ok(()),
+    };
+
+    if let Some(address) = &config.relay.listen {
+        relay::serve(address)
+            .wrap_err_with(|| format!("Failed to relay telemetry on {}", address))?;
+    }
 
     let mut app = tide::new();
 
+    let cors = &config.server.cors;
     app.with(
         CorsMiddleware::new()
-            .allow_methods("GET".parse::<HeaderValue>().unwrap())
-            .allow_origin("*")
-            .allow_credentials(false),
+            .allow_methods(
+                cors.methods
+                    .join(", ")
+                    .parse::<HeaderValue>()
+                    .map_err(|e| eyre!("Invalid CORS methods: {}", e))?,
+            )
+            .allow_origin(cors.origin.as_str())
+            .allow_credentials(cors.credentials),
     );
 
     app.at("/history/:key").get(routes::history::get_datum);
+    app.at("/compare").get(routes::compare::compare_sessions);
+    app.at("/compare/difference/:key")
+        .get(routes::compare::get_difference);
+    app.at("/export").get(routes::export::export);
+    app.at("/import")
+        .get(routes::import::list_mappings)
+        .post(routes::import::import_file);
     app.at("/measurements")
         .get(routes::measurements::all_measurements);
     app.at("/measurements/:key")
         .get(routes::measurements::get_measurement);
 
+    app.at("/metrics").get(routes::metrics::get_metrics);
+
+    app.at("/objects").get(routes::objects::get_root);
+    app.at("/objects/:key").get(routes::objects::get_object);
+
+    app.at("/persistence/:id")
+        .get(routes::persistence::get_object)
+        .post(routes::persistence::create_object)
+        .put(routes::persistence::update_object)
+        .delete(routes::persistence::delete_object);
+
+    app.at("/schema").get(routes::schema::get_report);
+
+    app.at("/sessions").get(routes::sessions::list_sessions);
+    app.at("/sessions/selected")
+        .get(routes::sessions::get_selected)
+        .put(routes::sessions::select_session);
+    app.at("/sessions/:id")
+        .get(routes::sessions::get_session)
+        .patch(routes::sessions::update_session)
+        .delete(routes::sessions::delete_session);
+
     app.at("/devices").get(routes::devices::list_devices);
+    app.at("/devices/session").get(routes::devices::get_session);
+    app.at("/devices/links").get(routes::devices::get_links);
     app.at("/devices/connect")
         .get(sse::endpoint(routes::devices::device_connect));
+    app.at("/devices/disconnect")
+        .post(routes::devices::device_disconnect);
 
     app.at("/health").get(|_| async move { Ok("ok") });
 
     app.at("/").all(routes::default);
     app.at("/*").all(routes::default);
 
-    app.listen("0.0.0.0:13705")
+    app.listen(config.server.listen.as_str())
         .await
         .wrap_err("Failed to start telemetry server")?;
 
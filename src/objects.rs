@@ -0,0 +1,288 @@
+use const_format::concatcp;
+use lazy_static::lazy_static;
+
+use crate::{
+    session::SessionId,
+    telemetry::{DomainObject, Identifier, TelemetryDictionary},
+};
+
+/// The key of the root folder of the object tree
+pub const ROOT_KEY: &str = "avionics";
+/// The key of the folder holding the telemetry of the flight computer
+pub const DEVICE_KEY: &str = "flight-computer";
+/// The key of the folder holding a folder for every session
+pub const SESSIONS_KEY: &str = "sessions";
+/// The prefix of the keys of objects scoped to a single session
+const SESSION_PREFIX: &str = "session-";
+/// The prefix of the keys of objects holding the difference between two
+/// sessions
+const DIFFERENCE_PREFIX: &str = "difference-";
+
+lazy_static! {
+    /// The location of the folders within the root folder
+    static ref ROOT_LOCATION: String = Identifier::from_key(ROOT_KEY).to_string();
+    static ref SESSION_LOCATION: String = Identifier::from_key(SESSIONS_KEY).to_string();
+    static ref SUBSYSTEM_LOCATION: String = Identifier::from_key(DEVICE_KEY).to_string();
+    /// The namespaced identifiers of the subsystem folders, in the order of [`Subsystem::ALL`]
+    static ref TELEMETRY_LOCATIONS: Vec<String> = Subsystem::ALL
+        .iter()
+        .map(|subsystem| Identifier::from_key(subsystem.key()).to_string())
+        .collect();
+}
+
+/// The subsystems of the vehicle that telemetry points are grouped into, based
+/// on the prefix of their key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    Tvc,
+    Power,
+    Processor,
+    Link,
+    Flight,
+    Other,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 6] = [
+        Subsystem::Tvc,
+        Subsystem::Power,
+        Subsystem::Processor,
+        Subsystem::Link,
+        Subsystem::Flight,
+        Subsystem::Other,
+    ];
+
+    pub fn of_key(key: &str) -> Self {
+        match key.split('.').next() {
+            Some("tvc") => Subsystem::Tvc,
+            Some("voltage") | Some("power") => Subsystem::Power,
+            Some("proc") => Subsystem::Processor,
+            Some("usb") | Some("link") => Subsystem::Link,
+            Some("flight") | Some("pyro") => Subsystem::Flight,
+            _ => Subsystem::Other,
+        }
+    }
+
+    pub fn from_folder_key(key: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|subsystem| subsystem.key() == key)
+    }
+
+    /// The key of this subsystem's folder
+    pub const fn key(self) -> &'static str {
+        match self {
+            Subsystem::Tvc => concatcp!(DEVICE_KEY, ".tvc"),
+            Subsystem::Power => concatcp!(DEVICE_KEY, ".power"),
+            Subsystem::Processor => concatcp!(DEVICE_KEY, ".processor"),
+            Subsystem::Link => concatcp!(DEVICE_KEY, ".link"),
+            Subsystem::Flight => concatcp!(DEVICE_KEY, ".flight"),
+            Subsystem::Other => concatcp!(DEVICE_KEY, ".other"),
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Subsystem::Tvc => "Thrust Vector Control",
+            Subsystem::Power => "Power",
+            Subsystem::Processor => "Processor",
+            Subsystem::Link => "Link",
+            Subsystem::Flight => "Flight",
+            Subsystem::Other => "Other",
+        }
+    }
+
+    /// The location of telemetry points in this subsystem, the namespaced
+    /// identifier of its folder
+    pub fn location(self) -> &'static str {
+        let index = Self::ALL
+            .iter()
+            .position(|subsystem| *subsystem == self)
+            .expect("every subsystem is listed in Subsystem::ALL");
+
+        &TELEMETRY_LOCATIONS[index]
+    }
+}
+
+pub fn root_folder() -> DomainObject<'static> {
+    DomainObject::folder(
+        ROOT_KEY,
+        "Pico Pilot",
+        "ROOT",
+        vec![
+            Identifier::from_key(DEVICE_KEY),
+            Identifier::from_key(SESSIONS_KEY),
+        ],
+    )
+}
+
+/// The subsystems a dictionary has telemetry points in
+fn subsystems(dictionary: &TelemetryDictionary) -> impl Iterator<Item = Subsystem> + '_ {
+    Subsystem::ALL.iter().copied().filter(move |subsystem| {
+        dictionary
+            .values
+            .iter()
+            .any(|object| Subsystem::of_key(object.identifier().key) == *subsystem)
+    })
+}
+
+pub fn device_folder<'a>(name: &'a str, dictionary: &TelemetryDictionary) -> DomainObject<'a> {
+    DomainObject::folder(
+        DEVICE_KEY,
+        name,
+        &ROOT_LOCATION,
+        subsystems(dictionary)
+            .map(|subsystem| Identifier::from_key(subsystem.key()))
+            .collect(),
+    )
+}
+
+pub fn subsystem_folder(
+    subsystem: Subsystem,
+    dictionary: &'static TelemetryDictionary,
+) -> DomainObject<'static> {
+    DomainObject::folder(
+        subsystem.key(),
+        subsystem.name(),
+        &SUBSYSTEM_LOCATION,
+        dictionary
+            .values
+            .iter()
+            .map(|object| object.identifier())
+            .filter(|identifier| Subsystem::of_key(identifier.key) == subsystem)
+            .map(|identifier| Identifier::from_key(identifier.key))
+            .collect(),
+    )
+}
+
+/// The key of an object scoped to a session, or of the session's own folder if
+/// `key` is `None`
+pub fn session_key(id: SessionId, key: Option<&str>) -> String {
+    match key {
+        Some(key) => format!("{}{}.{}", SESSION_PREFIX, id, key),
+        None => format!("{}{}", SESSION_PREFIX, id),
+    }
+}
+
+/// Splits a key scoped to a session into the session and the key of the
+/// object within it, which is `None` for the session's own folder
+pub fn parse_session_key(key: &str) -> Option<(SessionId, Option<&str>)> {
+    let mut parts = key.strip_prefix(SESSION_PREFIX)?.splitn(2, '.');
+    let id = parts.next()?.parse().ok()?;
+
+    Some((id, parts.next()))
+}
+
+/// The folder holding every session, `sessions` being the keys of their folders
+pub fn sessions_folder(sessions: &[String]) -> DomainObject<'_> {
+    DomainObject::folder(
+        SESSIONS_KEY,
+        "Sessions",
+        &ROOT_LOCATION,
+        sessions
+            .iter()
+            .map(|key| Identifier::from_key(key))
+            .collect(),
+    )
+}
+
+/// The keys of the subsystem folders within the folder of a session
+pub fn session_composition(id: SessionId, dictionary: &TelemetryDictionary) -> Vec<String> {
+    subsystems(dictionary)
+        .map(|subsystem| session_key(id, Some(subsystem.key())))
+        .collect()
+}
+
+/// The folder of a single session, which mirrors the device folder but only
+/// holds the telemetry of that session
+pub fn session_folder<'a>(
+    key: &'a str,
+    name: &'a str,
+    composition: &'a [String],
+) -> DomainObject<'a> {
+    DomainObject::folder(
+        key,
+        name,
+        &SESSION_LOCATION,
+        composition
+            .iter()
+            .map(|key| Identifier::from_key(key))
+            .collect(),
+    )
+}
+
+/// The keys of the telemetry points within a subsystem folder of a session
+pub fn session_subsystem_composition(
+    id: SessionId,
+    subsystem: Subsystem,
+    dictionary: &TelemetryDictionary,
+) -> Vec<String> {
+    dictionary
+        .values
+        .iter()
+        .map(|object| object.identifier().key)
+        .filter(|key| Subsystem::of_key(key) == subsystem)
+        .map(|key| session_key(id, Some(key)))
+        .collect()
+}
+
+pub fn session_subsystem_folder<'a>(
+    key: &'a str,
+    subsystem: Subsystem,
+    location: &'a str,
+    composition: &'a [String],
+) -> DomainObject<'a> {
+    DomainObject::folder(
+        key,
+        subsystem.name(),
+        location,
+        composition
+            .iter()
+            .map(|key| Identifier::from_key(key))
+            .collect(),
+    )
+}
+
+/// The key of the difference between two sessions of a telemetry point, with
+/// `offset` added to the `running_us` of session `b`, or of the folder holding
+/// every such difference if `key` is `None`
+pub fn difference_key(a: SessionId, b: SessionId, offset: i64, key: Option<&str>) -> String {
+    match key {
+        Some(key) => format!("{}{}-{}@{}.{}", DIFFERENCE_PREFIX, a, b, offset, key),
+        None => format!("{}{}-{}@{}", DIFFERENCE_PREFIX, a, b, offset),
+    }
+}
+
+/// Splits the key of a difference object into the sessions, the offset of
+/// session `b` and the key of the telemetry point, which is `None` for the
+/// folder
+pub fn parse_difference_key(key: &str) -> Option<(SessionId, SessionId, i64, Option<&str>)> {
+    let mut parts = key.strip_prefix(DIFFERENCE_PREFIX)?.splitn(2, '.');
+    let mut alignment = parts.next()?.splitn(2, '@');
+    let mut sessions = alignment.next()?.splitn(2, '-');
+
+    let a = sessions.next()?.parse().ok()?;
+    let b = sessions.next()?.parse().ok()?;
+    let offset = alignment.next()?.parse().ok()?;
+
+    Some((a, b, offset, parts.next()))
+}
+
+/// The folder holding the difference between two sessions of every telemetry
+/// point they have in common
+pub fn difference_folder<'a>(
+    key: &'a str,
+    name: &'a str,
+    composition: &'a [String],
+) -> DomainObject<'a> {
+    DomainObject::folder(
+        key,
+        name,
+        &SESSION_LOCATION,
+        composition
+            .iter()
+            .map(|key| Identifier::from_key(key))
+            .collect(),
+    )
+}
@@ -0,0 +1,176 @@
+use std::{fmt, io, path::PathBuf};
+
+use async_std::{fs, sync::Mutex};
+use lazy_static::lazy_static;
+use serde_json::{Map, Value};
+
+use crate::config::config;
+
+/// The property of a stored object holding its revision, matching the CouchDB
+/// convention Open MCT already understands
+pub const REVISION_KEY: &str = "_rev";
+
+lazy_static! {
+    pub static ref OBJECT_STORE: ObjectStore = ObjectStore::new(config().paths.objects.clone());
+}
+
+/// Persists the domain objects created by Open MCT users (layouts, plots, folders
+/// in My Items, ...) as one JSON file per object. Every write bumps the object's
+/// revision, and writes based on an outdated revision are rejected.
+pub struct ObjectStore {
+    directory: PathBuf,
+    /// Serializes writes so that revision checks and the writes they guard are atomic
+    write_lock: Mutex<()>,
+}
+
+#[derive(Debug)]
+pub enum PersistenceError {
+    NotFound,
+    AlreadyExists,
+    /// the object was changed since the revision the client based its write on
+    Conflict(Value),
+    Invalid(&'static str),
+    Io(io::Error),
+}
+
+impl fmt::Display for PersistenceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PersistenceError::NotFound => f.write_str("object does not exist"),
+            PersistenceError::AlreadyExists => f.write_str("object already exists"),
+            PersistenceError::Conflict(_) => {
+                f.write_str("object was modified since the given revision")
+            }
+            PersistenceError::Invalid(reason) => write!(f, "invalid object: {}", reason),
+            PersistenceError::Io(e) => write!(f, "failed to access object storage: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PersistenceError {}
+
+impl From<io::Error> for PersistenceError {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::NotFound {
+            PersistenceError::NotFound
+        } else {
+            PersistenceError::Io(e)
+        }
+    }
+}
+
+impl ObjectStore {
+    pub fn new(directory: PathBuf) -> Self {
+        ObjectStore {
+            directory,
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    /// The file an object is stored in, escaping everything but a safe set of
+    /// characters so that ids can not escape the storage directory
+    fn path(&self, id: &str) -> PathBuf {
+        let mut file_name = String::with_capacity(id.len() + 5);
+
+        for byte in id.bytes() {
+            match byte {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => {
+                    file_name.push(byte as char)
+                }
+                byte => file_name.push_str(&format!("%{:02X}", byte)),
+            }
+        }
+
+        file_name.push_str(".json");
+
+        self.directory.join(file_name)
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Value, PersistenceError> {
+        let contents = fs::read(self.path(id)).await?;
+
+        serde_json::from_slice(&contents)
+            .map_err(|e| PersistenceError::Io(io::Error::new(io::ErrorKind::InvalidData, e)))
+    }
+
+    /// Stores a new object at revision 1
+    pub async fn create(&self, id: &str, object: Value) -> Result<Value, PersistenceError> {
+        let mut object = into_object(object)?;
+
+        let _guard = self.write_lock.lock().await;
+
+        match self.get(id).await {
+            Ok(_) => return Err(PersistenceError::AlreadyExists),
+            Err(PersistenceError::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+
+        object.insert(REVISION_KEY.into(), Value::from(1));
+
+        self.write(id, Value::Object(object)).await
+    }
+
+    /// Replaces an object, as long as the revision it carries is the latest one
+    pub async fn update(&self, id: &str, object: Value) -> Result<Value, PersistenceError> {
+        let mut object = into_object(object)?;
+
+        let _guard = self.write_lock.lock().await;
+
+        let current = self.get(id).await?;
+        let current_revision = revision(&current);
+
+        if object.get(REVISION_KEY).and_then(Value::as_u64) != current_revision {
+            return Err(PersistenceError::Conflict(current));
+        }
+
+        object.insert(
+            REVISION_KEY.into(),
+            Value::from(current_revision.unwrap_or(0) + 1),
+        );
+
+        self.write(id, Value::Object(object)).await
+    }
+
+    /// Deletes an object, checking its revision if one is given
+    pub async fn delete(&self, id: &str, expected: Option<u64>) -> Result<(), PersistenceError> {
+        let _guard = self.write_lock.lock().await;
+
+        let current = self.get(id).await?;
+
+        if expected.is_some() && expected != revision(&current) {
+            return Err(PersistenceError::Conflict(current));
+        }
+
+        fs::remove_file(self.path(id)).await?;
+
+        Ok(())
+    }
+
+    async fn write(&self, id: &str, object: Value) -> Result<Value, PersistenceError> {
+        fs::create_dir_all(&self.directory).await?;
+
+        let path = self.path(id);
+        let temporary = path.with_extension("json.tmp");
+
+        let contents = serde_json::to_vec_pretty(&object)
+            .map_err(|e| PersistenceError::Io(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+
+        // Write then rename so that a crash never leaves a half written object
+        fs::write(&temporary, contents).await?;
+        fs::rename(&temporary, &path).await?;
+
+        Ok(object)
+    }
+}
+
+fn into_object(object: Value) -> Result<Map<String, Value>, PersistenceError> {
+    match object {
+        Value::Object(object) if object.contains_key("identifier") => Ok(object),
+        Value::Object(_) => Err(PersistenceError::Invalid("missing identifier")),
+        _ => Err(PersistenceError::Invalid("not a JSON object")),
+    }
+}
+
+pub fn revision(object: &Value) -> Option<u64> {
+    object.get(REVISION_KEY).and_then(Value::as_u64)
+}
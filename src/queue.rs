@@ -0,0 +1,498 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard, Weak},
+    task::{Context, Poll, Waker},
+};
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+use ts_rs::{export, TS};
+
+use crate::{config::QueuePolicy, ingest::IngestEvent, telemetry::TelemetryPacket};
+
+lazy_static! {
+    /// The queue of every client, to report the telemetry waiting in them
+    static ref QUEUES: Mutex<Vec<Weak<Mutex<Queue>>>> = Mutex::new(Vec::new());
+}
+
+/// How a client's queue has kept up with the telemetry sent to it
+#[derive(Debug, Default, Clone, Serialize, TS)]
+pub struct QueueStats {
+    /// the most telemetry packets the queue holds
+    pub capacity: usize,
+    /// telemetry packets waiting to be sent
+    pub queued: usize,
+    /// telemetry packets dropped to make room for newer ones
+    pub dropped: u64,
+    /// telemetry packets merged into newer ones to make room
+    pub coalesced: u64,
+}
+
+export! {
+    (declare) QueueStats => "./web/types/generated/queue.d.ts"
+}
+
+/// The queue was closed by the other side
+#[derive(Debug)]
+pub struct Closed;
+
+/// Creates a queue of ingest events for a single client, holding at most
+/// `capacity` telemetry packets. Other events are rare and only the latest of
+/// the link statistics and schema reports is kept, so they are never dropped.
+pub fn bounded(capacity: usize, policy: QueuePolicy) -> (EventSender, EventReceiver) {
+    let shared = Arc::new(Mutex::new(Queue {
+        events: VecDeque::new(),
+        policy,
+        stats: QueueStats {
+            capacity: capacity.max(1),
+            ..QueueStats::default()
+        },
+        waker: None,
+        sender_closed: false,
+        receiver_closed: false,
+    }));
+
+    let mut queues = lock_queues();
+    queues.retain(|queue| queue.strong_count() > 0);
+    queues.push(Arc::downgrade(&shared));
+    drop(queues);
+
+    (
+        EventSender {
+            queue: shared.clone(),
+        },
+        EventReceiver { queue: shared },
+    )
+}
+
+struct Queue {
+    events: VecDeque<IngestEvent>,
+    policy: QueuePolicy,
+    stats: QueueStats,
+    /// the receiver waiting for an event
+    waker: Option<Waker>,
+    sender_closed: bool,
+    receiver_closed: bool,
+}
+
+impl Queue {
+    fn push(&mut self, event: IngestEvent) {
+        match event {
+            IngestEvent::Telemetry(packet) => {
+                let packet = if self.stats.queued >= self.stats.capacity {
+                    self.make_room(packet)
+                } else {
+                    packet
+                };
+
+                self.stats.queued += 1;
+                self.events.push_back(IngestEvent::Telemetry(packet));
+            }
+            IngestEvent::Link(stats) => {
+                self.events
+                    .retain(|event| !matches!(event, IngestEvent::Link(_)));
+                self.events.push_back(IngestEvent::Link(stats));
+            }
+            IngestEvent::Schema(report) => {
+                self.events
+                    .retain(|event| !matches!(event, IngestEvent::Schema(_)));
+                self.events.push_back(IngestEvent::Schema(report));
+            }
+            event => self.events.push_back(event),
+        }
+    }
+
+    /// Frees up room in a full queue according to the policy, returning the
+    /// packet to queue in place of `packet`
+    fn make_room(&mut self, packet: TelemetryPacket) -> TelemetryPacket {
+        match self.policy {
+            QueuePolicy::DropOldest => {
+                if let Some(index) = self
+                    .events
+                    .iter()
+                    .position(|event| matches!(event, IngestEvent::Telemetry(_)))
+                {
+                    self.events.remove(index);
+                    self.stats.queued -= 1;
+                    self.stats.dropped += 1;
+                }
+
+                packet
+            }
+            QueuePolicy::Decimate => {
+                // Drop the newest queued packet and every other one before it,
+                // so the new packet carries on the same pattern
+                let mut remaining = self.stats.queued;
+
+                self.events.retain(|event| match event {
+                    IngestEvent::Telemetry(_) => {
+                        remaining -= 1;
+
+                        remaining % 2 == 1
+                    }
+                    _ => true,
+                });
+
+                let kept = self.stats.queued / 2;
+                self.stats.dropped += (self.stats.queued - kept) as u64;
+                self.stats.queued = kept;
+
+                packet
+            }
+            QueuePolicy::Coalesce => {
+                // Packets are only merged with those of the same session and
+                // firmware, each run of them between those events into its
+                // newest packet, the last run into the new packet
+                let mut events = VecDeque::with_capacity(self.events.len());
+                let mut merged: Option<TelemetryPacket> = None;
+                let mut kept = 0;
+
+                for event in self.events.drain(..) {
+                    match event {
+                        IngestEvent::Telemetry(packet) => {
+                            merged = Some(match merged.take() {
+                                Some(merged) => coalesce(merged, &packet),
+                                None => packet,
+                            });
+                        }
+                        IngestEvent::Session(_) | IngestEvent::Firmware(_) => {
+                            if let Some(merged) = merged.take() {
+                                events.push_back(IngestEvent::Telemetry(merged));
+                                kept += 1;
+                            }
+
+                            events.push_back(event);
+                        }
+                        event => events.push_back(event),
+                    }
+                }
+
+                self.events = events;
+                self.stats.coalesced += (self.stats.queued - kept) as u64;
+                self.stats.queued = kept;
+
+                match merged {
+                    Some(merged) => coalesce(merged, &packet),
+                    None => packet,
+                }
+            }
+        }
+    }
+}
+
+/// Merges a packet into an older one, keeping the latest value of every
+/// telemetry point and the time of the newer packet
+fn coalesce(mut older: TelemetryPacket, newer: &TelemetryPacket) -> TelemetryPacket {
+    older.running_us = newer.running_us;
+    older.values.extend(
+        newer
+            .values
+            .iter()
+            .map(|(key, &value)| (key.clone(), value)),
+    );
+    older
+        .raw
+        .extend(newer.raw.iter().map(|(key, &value)| (key.clone(), value)));
+
+    older
+}
+
+fn lock(queue: &Mutex<Queue>) -> MutexGuard<'_, Queue> {
+    // The queue is left consistent between statements, so a panic on the other
+    // side cannot leave it half updated
+    queue
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn lock_queues() -> MutexGuard<'static, Vec<Weak<Mutex<Queue>>>> {
+    QUEUES
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// The statistics of the queue of every client that is still connected, or
+/// whose stream is waiting to be resumed
+pub fn usage() -> Vec<QueueStats> {
+    lock_queues()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .map(|queue| lock(&queue).stats.clone())
+        .collect()
+}
+
+/// The ingest thread's side of a client's queue. Sending never blocks.
+pub struct EventSender {
+    queue: Arc<Mutex<Queue>>,
+}
+
+impl EventSender {
+    /// Queues an event for the client, making room if the queue is full. Fails
+    /// once the client has gone away.
+    pub fn send(&self, event: IngestEvent) -> Result<(), Closed> {
+        let waker = {
+            let mut queue = lock(&self.queue);
+
+            if queue.receiver_closed {
+                return Err(Closed);
+            }
+
+            queue.push(event);
+            queue.waker.take()
+        };
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+
+        Ok(())
+    }
+
+    /// Whether the client has gone away
+    pub fn is_closed(&self) -> bool {
+        lock(&self.queue).receiver_closed
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        let waker = {
+            let mut queue = lock(&self.queue);
+
+            queue.sender_closed = true;
+            queue.waker.take()
+        };
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// The client's side of its queue
+pub struct EventReceiver {
+    queue: Arc<Mutex<Queue>>,
+}
+
+impl EventReceiver {
+    /// Waits for the next event, failing once the queue is empty and the ingest
+    /// thread has stopped
+    pub fn recv(&self) -> Recv<'_> {
+        Recv { receiver: self }
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        lock(&self.queue).stats.clone()
+    }
+}
+
+impl Drop for EventReceiver {
+    fn drop(&mut self) {
+        let mut queue = lock(&self.queue);
+
+        queue.receiver_closed = true;
+        queue.events.clear();
+    }
+}
+
+/// The future returned by [`EventReceiver::recv`]
+pub struct Recv<'r> {
+    receiver: &'r EventReceiver,
+}
+
+impl Future for Recv<'_> {
+    type Output = Result<IngestEvent, Closed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut queue = lock(&self.receiver.queue);
+
+        match queue.events.pop_front() {
+            Some(event) => {
+                if let IngestEvent::Telemetry(_) = event {
+                    queue.stats.queued -= 1;
+                }
+
+                Poll::Ready(Ok(event))
+            }
+            None if queue.sender_closed => Poll::Ready(Err(Closed)),
+            None => {
+                queue.waker = Some(cx.waker().clone());
+
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{bounded, EventReceiver, EventSender};
+    use crate::{
+        config::QueuePolicy,
+        ingest::{IngestEvent, LinkStats},
+        schema::SchemaReport,
+        telemetry::{TelemetryPacket, TelemetryValue},
+    };
+
+    fn telemetry(running_us: u64) -> IngestEvent {
+        let mut values = BTreeMap::new();
+        values.insert(
+            format!("x{}", running_us % 2),
+            TelemetryValue::Integer(running_us as i64),
+        );
+
+        IngestEvent::Telemetry(TelemetryPacket {
+            running_us,
+            values,
+            raw: BTreeMap::new(),
+        })
+    }
+
+    fn send(tx: &EventSender, running_us: impl IntoIterator<Item = u64>) {
+        for running_us in running_us {
+            tx.send(telemetry(running_us))
+                .expect("queue should be open");
+        }
+    }
+
+    /// The queued events, with the `running_us` of telemetry, the session id
+    /// plus 1000 of sessions, and every other event as `None`
+    fn drain(tx: EventSender, rx: &EventReceiver) -> Vec<Option<u64>> {
+        drop(tx);
+
+        let mut events = Vec::new();
+        while let Ok(event) = async_std::task::block_on(rx.recv()) {
+            events.push(match event {
+                IngestEvent::Telemetry(packet) => Some(packet.running_us),
+                IngestEvent::Session(id) => Some(id + 1000),
+                _ => None,
+            });
+        }
+
+        events
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let (tx, rx) = bounded(4, QueuePolicy::DropOldest);
+
+        send(&tx, 0..10);
+
+        let stats = rx.stats();
+        assert_eq!(stats.queued, 4);
+        assert_eq!(stats.dropped, 6);
+        assert_eq!(stats.coalesced, 0);
+        assert_eq!(drain(tx, &rx), vec![Some(6), Some(7), Some(8), Some(9)]);
+        assert_eq!(rx.stats().queued, 0);
+    }
+
+    #[test]
+    fn decimate() {
+        let (tx, rx) = bounded(4, QueuePolicy::Decimate);
+
+        // The queue of 0..4 is halved to 0 and 2 before 4 is queued
+        send(&tx, 0..5);
+        assert_eq!(rx.stats().queued, 3);
+        assert_eq!(rx.stats().dropped, 2);
+
+        send(&tx, 5..7);
+        assert_eq!(rx.stats().dropped, 4);
+
+        let stats = rx.stats();
+        assert_eq!(stats.queued, 3);
+        assert_eq!(drain(tx, &rx), vec![Some(0), Some(4), Some(6)]);
+    }
+
+    #[test]
+    fn decimate_odd_queues() {
+        let (tx, rx) = bounded(5, QueuePolicy::Decimate);
+
+        // The newest of 0..5 and every other one before it are dropped
+        send(&tx, 0..6);
+
+        let stats = rx.stats();
+        assert_eq!(stats.queued, 3);
+        assert_eq!(stats.dropped, 3);
+        assert_eq!(drain(tx, &rx), vec![Some(1), Some(3), Some(5)]);
+    }
+
+    #[test]
+    fn coalesce() {
+        let (tx, rx) = bounded(3, QueuePolicy::Coalesce);
+
+        send(&tx, 0..4);
+
+        let stats = rx.stats();
+        assert_eq!(stats.queued, 1);
+        assert_eq!(stats.coalesced, 3);
+        assert_eq!(stats.dropped, 0);
+
+        drop(tx);
+        match async_std::task::block_on(rx.recv()) {
+            Ok(IngestEvent::Telemetry(packet)) => {
+                assert_eq!(packet.running_us, 3);
+                assert_eq!(packet.values["x0"], TelemetryValue::Integer(2));
+                assert_eq!(packet.values["x1"], TelemetryValue::Integer(3));
+            }
+            _ => panic!("the queue should hold the coalesced packet"),
+        }
+    }
+
+    #[test]
+    fn coalesce_keeps_sessions_apart() {
+        let (tx, rx) = bounded(4, QueuePolicy::Coalesce);
+
+        send(&tx, 0..2);
+        tx.send(IngestEvent::Session(1)).unwrap();
+        send(&tx, 10..12);
+        tx.send(IngestEvent::Link(LinkStats::default())).unwrap();
+        send(&tx, 12..13);
+
+        let stats = rx.stats();
+        assert_eq!(stats.queued, 2);
+        assert_eq!(stats.coalesced, 3);
+
+        // Only packets after the session started are merged into the new one,
+        // which is queued after the link statistics
+        assert_eq!(drain(tx, &rx), vec![Some(1), Some(1001), None, Some(12)]);
+    }
+
+    #[test]
+    fn only_the_latest_link_and_schema_reports_are_kept() {
+        let (tx, rx) = bounded(4, QueuePolicy::DropOldest);
+
+        tx.send(IngestEvent::Link(LinkStats::default())).unwrap();
+        tx.send(IngestEvent::Schema(SchemaReport::default()))
+            .unwrap();
+        send(&tx, 0..1);
+        tx.send(IngestEvent::Link(LinkStats {
+            packets: 1,
+            ..LinkStats::default()
+        }))
+        .unwrap();
+        tx.send(IngestEvent::Schema(SchemaReport::default()))
+            .unwrap();
+        tx.send(IngestEvent::Session(2)).unwrap();
+
+        drop(tx);
+
+        let mut events = Vec::new();
+        while let Ok(event) = async_std::task::block_on(rx.recv()) {
+            events.push(event);
+        }
+
+        assert!(matches!(
+            events.as_slice(),
+            [
+                IngestEvent::Telemetry(_),
+                IngestEvent::Link(LinkStats { packets: 1, .. }),
+                IngestEvent::Schema(_),
+                IngestEvent::Session(2),
+            ]
+        ));
+    }
+}
@@ -0,0 +1,126 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_cbor::{de::IoRead, StreamDeserializer, Value};
+
+/// Identifies a file as a recording
+pub const RECORDING_FORMAT: &str = "pico-mct-recording";
+/// The version of the recording format written by this build
+pub const RECORDING_VERSION: u32 = 1;
+
+/// The first value of a recording, describing the session it was recorded from.
+///
+/// A recording is a CBOR sequence of this header followed by every packet the
+/// device sent, exactly as it was received, so that it can be decoded again with
+/// any telemetry dictionary.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordingHeader {
+    pub format: String,
+    pub version: u32,
+    /// the name of the port the device was connected to
+    pub port: String,
+    /// the description of the USB product, if it was recognized
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+    /// the time, in milliseconds since the UNIX epoch, at which the recording started
+    pub started: u64,
+}
+
+impl RecordingHeader {
+    pub fn new(
+        port: String,
+        product: Option<String>,
+        serial_number: Option<String>,
+        started: u64,
+    ) -> Self {
+        RecordingHeader {
+            format: RECORDING_FORMAT.into(),
+            version: RECORDING_VERSION,
+            port,
+            product,
+            serial_number,
+            started,
+        }
+    }
+}
+
+/// Appends packets to a recording file
+pub struct RecordingWriter {
+    writer: BufWriter<File>,
+}
+
+impl RecordingWriter {
+    pub fn create(path: &Path, header: &RecordingHeader) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        serde_cbor::to_writer(&mut writer, header).map_err(into_io_error)?;
+
+        Ok(RecordingWriter { writer })
+    }
+
+    pub fn write(&mut self, packet: &Value) -> io::Result<()> {
+        serde_cbor::to_writer(&mut self.writer, packet).map_err(into_io_error)
+    }
+
+    /// Flushes the recording to disk
+    pub fn finish(self) -> io::Result<()> {
+        let file = self.writer.into_inner().map_err(|e| e.into_error())?;
+
+        file.sync_all()
+    }
+}
+
+/// Reads the packets of a recording file, in the order they were received
+pub struct RecordingReader {
+    pub header: RecordingHeader,
+    packets: StreamDeserializer<'static, IoRead<BufReader<File>>, Value>,
+}
+
+impl RecordingReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut packets = serde_cbor::Deserializer::from_reader(BufReader::new(File::open(path)?))
+            .into_iter::<Value>();
+
+        let header = packets
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "recording is empty"))?
+            .and_then(serde_cbor::value::from_value::<RecordingHeader>)
+            .map_err(into_io_error)?;
+
+        if header.format != RECORDING_FORMAT {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file is not a recording",
+            ));
+        }
+
+        if header.version > RECORDING_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported recording version {}", header.version),
+            ));
+        }
+
+        Ok(RecordingReader { header, packets })
+    }
+}
+
+impl Iterator for RecordingReader {
+    type Item = Result<Value, serde_cbor::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.packets.next()
+    }
+}
+
+fn into_io_error(e: serde_cbor::Error) -> io::Error {
+    if e.is_io() {
+        io::Error::new(io::ErrorKind::Other, e)
+    } else {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
@@ -0,0 +1,671 @@
+use std::{
+    collections::VecDeque,
+    io::{self, BufReader, BufWriter, Write},
+    mem,
+    net::{TcpListener, TcpStream},
+    ops::Bound,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError, SyncSender, TrySendError},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use async_std::task;
+use lazy_static::lazy_static;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_cbor::{de::IoRead, StreamDeserializer, Value};
+
+use crate::{
+    config::config,
+    firmware::FirmwareInfo,
+    serial::PICO_USB_PID_MAP,
+    session::{SessionId, SessionMetadata, SESSION_CATALOG},
+    simulator::SIMULATOR_PRODUCT,
+    source::{CountingReader, PacketError, Source},
+    telemetry::TELEMETRY_TIME_FIELD,
+};
+
+/// The port name the upstream server is listed under in `/devices`
+pub const RELAY_PORT: &str = "relay";
+
+/// How often an idle relay lets downstream servers know it is still there
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a downstream server waits for a message before reconnecting
+const RELAY_TIMEOUT: Duration = Duration::from_secs(5);
+/// The longest a downstream server waits between attempts to reconnect
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// The most recent packets kept to backfill downstream servers exactly as they
+/// were received. Older packets are read back from the session's history.
+const RELAY_BACKLOG: usize = 4096;
+/// The most messages queued for a downstream server. One that falls further
+/// behind is disconnected, and backfills what it missed once it reconnects.
+const CLIENT_QUEUE: usize = RELAY_BACKLOG;
+/// The most changes queued for the thread updating the hub, which only falls
+/// behind while downstream servers are being registered
+const HUB_QUEUE: usize = 4096;
+
+lazy_static! {
+    static ref RELAY_HUB: Mutex<RelayHub> = Mutex::new(RelayHub::default());
+    /// Feeds the thread updating the hub, set once the relay is serving
+    static ref HUB_EVENTS: Mutex<Option<SyncSender<HubEvent>>> = Mutex::new(None);
+}
+
+/// The session relayed packets belong to, as sent to downstream servers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelaySession {
+    pub port: String,
+    /// the description of the device's product
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+    pub started: u64,
+    pub ended: Option<u64>,
+}
+
+/// Sent by a downstream server when it connects, so that it is only sent the
+/// packets it is missing
+#[derive(Debug, Serialize, Deserialize)]
+struct RelayRequest {
+    /// the token shared by the servers, if the relay requires one
+    #[serde(default)]
+    token: Option<String>,
+    /// the start of the session the downstream server has packets of
+    session: Option<u64>,
+    /// the sequence number of the first packet it is missing
+    from: u64,
+    /// the `running_us` of the last packet it has, to backfill from the
+    /// session's history if `from` is no longer in the backlog
+    after_us: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum RelayMessage {
+    /// a new session started, or the current one ended
+    Session(RelaySession),
+    /// a packet exactly as it was received from the device
+    Packet {
+        sequence: u64,
+        packet: Value,
+    },
+    /// a packet older than the backlog, encoded again from the session's
+    /// history, which is not numbered
+    Backfilled {
+        packet: Value,
+    },
+    Heartbeat,
+}
+
+/// The latest packets of the current session, kept to backfill downstream
+/// servers
+#[derive(Default)]
+struct RelayHub {
+    session: Option<RelaySession>,
+    /// the session the packets are stored in
+    session_id: Option<SessionId>,
+    /// the latest [`RELAY_BACKLOG`] packets, along with their `running_us`
+    backlog: VecDeque<(Option<u64>, Value)>,
+    /// the sequence number of the first packet in the backlog
+    first_sequence: u64,
+    /// the latest firmware identification of the session, which is kept as
+    /// the packets after it cannot be decoded without it
+    identification: Option<(u64, Value)>,
+    clients: Vec<SyncSender<RelayMessage>>,
+}
+
+/// Changes to the hub, sent by the ingest thread so that relaying never makes
+/// it wait
+enum HubEvent {
+    Begin(SessionId, RelaySession),
+    Packet(Value),
+    End(u64),
+}
+
+impl RelayHub {
+    fn broadcast(&mut self, message: RelayMessage) {
+        self.clients
+            .retain(|client| match client.try_send(message.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("Downstream server fell behind the relay, disconnecting it");
+
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+
+    fn next_sequence(&self) -> u64 {
+        self.first_sequence + self.backlog.len() as u64
+    }
+
+    fn apply(&mut self, event: HubEvent) {
+        match event {
+            HubEvent::Begin(id, session) => {
+                self.backlog.clear();
+                self.first_sequence = 0;
+                self.identification = None;
+                self.session_id = Some(id);
+                self.session = Some(session.clone());
+                self.broadcast(RelayMessage::Session(session));
+            }
+            HubEvent::Packet(packet) => {
+                let sequence = self.next_sequence();
+
+                if FirmwareInfo::from_packet(&packet).is_some() {
+                    self.identification = Some((sequence, packet.clone()));
+                }
+
+                if self.backlog.len() == RELAY_BACKLOG {
+                    self.backlog.pop_front();
+                    self.first_sequence += 1;
+                }
+
+                self.backlog.push_back((packet_us(&packet), packet.clone()));
+                self.broadcast(RelayMessage::Packet { sequence, packet });
+            }
+            HubEvent::End(ended) => {
+                if let Some(session) = &mut self.session {
+                    session.ended = Some(ended);
+
+                    let session = session.clone();
+                    self.broadcast(RelayMessage::Session(session));
+                }
+            }
+        }
+    }
+}
+
+/// How much the relay is holding on to
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct RelayUsage {
+    /// packets kept to backfill downstream servers
+    pub backlog: usize,
+    /// downstream servers connected
+    pub clients: usize,
+}
+
+/// How much the relay is holding on to, all zero if the server is not relaying
+pub fn usage() -> RelayUsage {
+    let hub = lock_hub();
+
+    RelayUsage {
+        backlog: hub.backlog.len(),
+        clients: hub.clients.len(),
+    }
+}
+
+fn lock_hub() -> MutexGuard<'static, RelayHub> {
+    // A panicking client thread never holds the lock mid update
+    RELAY_HUB
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// The `running_us` of a raw packet, if it has one
+fn packet_us(packet: &Value) -> Option<u64> {
+    match packet {
+        Value::Map(fields) => match fields.get(&Value::Text(TELEMETRY_TIME_FIELD.name.into())) {
+            Some(Value::Integer(running_us)) if *running_us >= 0 => Some(*running_us as u64),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+impl From<&SessionMetadata> for RelaySession {
+    fn from(session: &SessionMetadata) -> Self {
+        RelaySession {
+            port: session.port.clone(),
+            product: session
+                .product
+                .map(|product| product.description.to_string()),
+            serial_number: session.serial_number.clone(),
+            started: session.started,
+            ended: session.ended,
+        }
+    }
+}
+
+impl RelaySession {
+    fn metadata(self) -> SessionMetadata {
+        // The product is only known by its description, which the known
+        // products can be matched against
+        let product = self.product.and_then(|description| {
+            PICO_USB_PID_MAP
+                .values()
+                .chain(Some(&SIMULATOR_PRODUCT))
+                .find(|product| product.description == description)
+                .copied()
+        });
+
+        SessionMetadata {
+            started: self.started,
+            ended: self.ended,
+            ..SessionMetadata::new(self.port, product, self.serial_number)
+        }
+    }
+}
+
+/// The ingest thread's handle on the relay. The relay is updated on a thread of
+/// its own, which the methods only wait on if it falls [`HUB_QUEUE`] changes
+/// behind.
+pub struct RelayPublisher {
+    /// `None` if the server is not relaying
+    events: Option<SyncSender<HubEvent>>,
+}
+
+impl RelayPublisher {
+    pub fn new() -> Self {
+        RelayPublisher {
+            events: HUB_EVENTS
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .clone(),
+        }
+    }
+
+    fn send(&self, event: HubEvent) {
+        if let Some(events) = &self.events {
+            // The hub thread only stops with the server
+            let _ = events.send(event);
+        }
+    }
+
+    /// Starts relaying a new session, stored as `id`, to downstream servers
+    pub fn begin_session(&self, id: SessionId, session: &SessionMetadata) {
+        if self.events.is_some() {
+            self.send(HubEvent::Begin(id, RelaySession::from(session)));
+        }
+    }
+
+    /// Relays a packet of the current session to downstream servers
+    pub fn publish(&self, packet: &Value) {
+        if self.events.is_some() {
+            self.send(HubEvent::Packet(packet.clone()));
+        }
+    }
+
+    /// Lets downstream servers know the current session ended
+    pub fn end_session(&self, ended: u64) {
+        self.send(HubEvent::End(ended));
+    }
+}
+
+impl Default for RelayPublisher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Listens for downstream servers on `address`, relaying every packet ingested
+/// by this server to them
+pub fn serve(address: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+
+    info!("Relaying telemetry on {}", address);
+
+    let (events, rx) = mpsc::sync_channel(HUB_QUEUE);
+    *HUB_EVENTS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(events);
+
+    thread::spawn(move || {
+        for event in rx {
+            lock_hub().apply(event);
+        }
+    });
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Failed to accept relay connection: {}", e);
+
+                    continue;
+                }
+            };
+
+            let peer = stream
+                .peer_addr()
+                .map_or_else(|_| "unknown".to_string(), |address| address.to_string());
+
+            info!("Relaying telemetry to {}", peer);
+
+            thread::spawn(move || match serve_client(stream) {
+                Ok(()) => info!("Stopped relaying telemetry to {}", peer),
+                Err(e) => info!("Stopped relaying telemetry to {}: {}", peer, e),
+            });
+        }
+    });
+
+    Ok(())
+}
+
+fn serve_client(stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(RELAY_TIMEOUT))?;
+    stream.set_write_timeout(Some(RELAY_TIMEOUT))?;
+
+    let request = RelayRequest::deserialize(&mut serde_cbor::Deserializer::from_reader(&stream))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    if config().relay.token.is_some() && request.token != config().relay.token {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "wrong relay token",
+        ));
+    }
+
+    let mut writer = BufWriter::new(&stream);
+
+    let (tx, rx) = mpsc::sync_channel(CLIENT_QUEUE);
+
+    // Registering for new packets while holding the lock means none are missed
+    // between the backfill and the live stream
+    let (session, backfill) = {
+        let mut hub = lock_hub();
+        hub.clients.push(tx);
+
+        let (from, after_us) = match (&hub.session, request.session) {
+            (Some(session), Some(started)) if session.started == started => {
+                (request.from, request.after_us)
+            }
+            _ => (0, None),
+        };
+
+        (hub.session.clone(), Backfill::new(&hub, from, after_us))
+    };
+
+    if let Some(session) = session {
+        send(&mut writer, &RelayMessage::Session(session))?;
+    }
+    backfill.send(&mut writer)?;
+    writer.flush()?;
+
+    loop {
+        let message = match rx.recv_timeout(HEARTBEAT_INTERVAL) {
+            Ok(message) => message,
+            Err(RecvTimeoutError::Timeout) => RelayMessage::Heartbeat,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+
+        send(&mut writer, &message)?;
+        writer.flush()?;
+    }
+}
+
+/// The packets a downstream server is missing, as of when it connected
+struct Backfill {
+    /// the session to read packets older than the backlog from, along with
+    /// the range of their `running_us`, if any are missing
+    stored: Option<(SessionId, (Bound<u64>, Bound<u64>))>,
+    identification: Option<Value>,
+    /// the sequence number of the first packet of `packets`
+    from: u64,
+    packets: Vec<Value>,
+}
+
+impl Backfill {
+    fn new(hub: &RelayHub, from: u64, after_us: Option<u64>) -> Self {
+        if from >= hub.first_sequence {
+            return Backfill {
+                stored: None,
+                identification: None,
+                from,
+                packets: hub
+                    .backlog
+                    .iter()
+                    .skip((from - hub.first_sequence) as usize)
+                    .map(|(_, packet)| packet.clone())
+                    .collect(),
+            };
+        }
+
+        // The packets before the backlog come from the session's history
+        let before_us = hub
+            .backlog
+            .iter()
+            .find_map(|(running_us, _)| *running_us)
+            .map_or(Bound::Unbounded, Bound::Excluded);
+        let after_us = after_us.map_or(Bound::Unbounded, Bound::Excluded);
+
+        Backfill {
+            stored: hub.session_id.map(|id| (id, (after_us, before_us))),
+            identification: hub
+                .identification
+                .as_ref()
+                .filter(|(sequence, _)| *sequence >= from && *sequence < hub.first_sequence)
+                .map(|(_, packet)| packet.clone()),
+            from: hub.first_sequence,
+            packets: hub
+                .backlog
+                .iter()
+                .map(|(_, packet)| packet.clone())
+                .collect(),
+        }
+    }
+
+    fn send(self, writer: &mut impl Write) -> io::Result<()> {
+        if let Some(packet) = self.identification {
+            send(writer, &RelayMessage::Backfilled { packet })?;
+        }
+
+        if let Some((id, range)) = self.stored {
+            let (dictionary, data) = {
+                let catalog = task::block_on(SESSION_CATALOG.read());
+
+                match catalog.get(id) {
+                    Some(entry) => (entry.info.metadata.dictionary, entry.data.snapshot()),
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!("session {} was removed", id),
+                        ))
+                    }
+                }
+            };
+
+            let mut stored = 0;
+            for packet in data.range(range) {
+                let packet = dictionary.encode(&packet);
+                send(writer, &RelayMessage::Backfilled { packet })?;
+
+                stored += 1;
+            }
+
+            debug!("Backfilled {} packets from session {}", stored, id);
+        }
+
+        debug!(
+            "Backfilling {} packets from {}",
+            self.packets.len(),
+            self.from
+        );
+
+        for (sequence, packet) in (self.from..).zip(self.packets) {
+            send(writer, &RelayMessage::Packet { sequence, packet })?;
+        }
+
+        Ok(())
+    }
+}
+
+fn send(writer: &mut impl Write, message: &impl Serialize) -> io::Result<()> {
+    serde_cbor::to_writer(writer, message).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+/// Mirrors the sessions of an upstream server, reconnecting and backfilling the
+/// packets missed whenever the connection is lost
+pub struct RelaySource {
+    address: String,
+    messages:
+        StreamDeserializer<'static, IoRead<BufReader<CountingReader<TcpStream>>>, RelayMessage>,
+    bytes: Arc<AtomicU64>,
+    session: Option<RelaySession>,
+    /// a session the ingest thread has not taken yet
+    pending: Option<RelaySession>,
+    next_sequence: u64,
+    /// the `running_us` of the latest packet received
+    last_us: Option<u64>,
+    /// whether packets were just backfilled from the upstream's history, after
+    /// which the numbered packets carry on from the upstream's backlog
+    backfilled: bool,
+    reconnects: u64,
+}
+
+impl RelaySource {
+    pub fn connect(address: String) -> io::Result<Self> {
+        let bytes = Arc::new(AtomicU64::new(0));
+        let messages = connect(
+            &address,
+            &bytes,
+            RelayRequest {
+                token: config().relay.token.clone(),
+                session: None,
+                from: 0,
+                after_us: None,
+            },
+        )?;
+
+        Ok(RelaySource {
+            address,
+            messages,
+            bytes,
+            session: None,
+            pending: None,
+            next_sequence: 0,
+            last_us: None,
+            backfilled: false,
+            reconnects: 0,
+        })
+    }
+
+    fn reconnect(&mut self, reason: String) -> Result<(), PacketError> {
+        warn!(
+            "Lost connection to relay {} ({}), reconnecting",
+            self.address, reason
+        );
+
+        let started = Instant::now();
+        let timeout = Duration::from_secs_f64(config().relay.reconnect_timeout_s);
+        let mut delay = Duration::from_millis(250);
+
+        loop {
+            thread::sleep(delay);
+
+            let request = RelayRequest {
+                token: config().relay.token.clone(),
+                session: self.session.as_ref().map(|session| session.started),
+                from: self.next_sequence,
+                after_us: self.last_us,
+            };
+
+            match connect(&self.address, &self.bytes, request) {
+                Ok(messages) => {
+                    info!("Reconnected to relay {}", self.address);
+
+                    self.messages = messages;
+                    self.reconnects += 1;
+
+                    return Ok(());
+                }
+                Err(e) if started.elapsed() < timeout => {
+                    debug!("Failed to reconnect to relay {}: {}", self.address, e);
+
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                }
+                Err(e) => {
+                    return Err(PacketError::Closed(format!(
+                        "gave up reconnecting to relay {}: {}",
+                        self.address, e
+                    )))
+                }
+            }
+        }
+    }
+}
+
+fn connect(
+    address: &str,
+    bytes: &Arc<AtomicU64>,
+    request: RelayRequest,
+) -> io::Result<
+    StreamDeserializer<'static, IoRead<BufReader<CountingReader<TcpStream>>>, RelayMessage>,
+> {
+    let stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(RELAY_TIMEOUT))?;
+
+    send(&mut &stream, &request)?;
+
+    let reader = CountingReader {
+        inner: stream,
+        bytes: bytes.clone(),
+    };
+
+    Ok(serde_cbor::Deserializer::from_reader(BufReader::new(reader)).into_iter())
+}
+
+impl Source for RelaySource {
+    fn next_packet(&mut self) -> Result<Value, PacketError> {
+        loop {
+            match self.messages.next() {
+                Some(Ok(RelayMessage::Packet { sequence, packet })) => {
+                    let backfilled = mem::take(&mut self.backfilled);
+
+                    // Already received before the connection was lost
+                    if sequence < self.next_sequence {
+                        continue;
+                    }
+
+                    if sequence > self.next_sequence && !backfilled {
+                        error!(
+                            "Relay {} skipped {} packets",
+                            self.address,
+                            sequence - self.next_sequence
+                        );
+                    }
+
+                    self.next_sequence = sequence + 1;
+                    self.last_us = packet_us(&packet).or(self.last_us);
+
+                    return Ok(packet);
+                }
+                Some(Ok(RelayMessage::Backfilled { packet })) => {
+                    self.backfilled = true;
+                    self.last_us = packet_us(&packet).or(self.last_us);
+
+                    return Ok(packet);
+                }
+                Some(Ok(RelayMessage::Session(session))) => {
+                    let started = self.session.as_ref().map(|session| session.started);
+
+                    if started != Some(session.started) {
+                        self.next_sequence = 0;
+                        self.last_us = None;
+                    }
+
+                    self.session = Some(session.clone());
+                    self.pending = Some(session);
+                }
+                Some(Ok(RelayMessage::Heartbeat)) => {}
+                Some(Err(e)) => self.reconnect(e.to_string())?,
+                None => self.reconnect("connection closed".into())?,
+            }
+        }
+    }
+
+    fn bytes_received(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+
+    fn take_session(&mut self) -> Option<SessionMetadata> {
+        self.pending.take().map(RelaySession::metadata)
+    }
+}
@@ -1,13 +1,61 @@
-use tide::{Request, Response, StatusCode};
+use std::str::FromStr;
 
-use crate::State;
+use log::error;
+use tide::{
+    http::{Method, Mime},
+    Body, Request, Response, StatusCode,
+};
 
+use crate::{assets, State};
+
+pub mod compare;
 pub mod devices;
+pub mod export;
 pub mod history;
+pub mod import;
 pub mod measurements;
+pub mod metrics;
+pub mod objects;
+pub mod persistence;
+pub mod schema;
+pub mod sessions;
 
-pub async fn default(_: Request<State>) -> tide::Result<Response> {
-    Ok(Response::builder(StatusCode::NotFound)
-        .body("404: Endpoint does not exist")
+/// Serves the web app for any path not handled by another endpoint
+pub async fn default(req: Request<State>) -> tide::Result<Response> {
+    if req.method() != Method::Get {
+        return Ok(not_found());
+    }
+
+    let asset = match assets::load(req.url().path()).await {
+        Ok(Some(asset)) => asset,
+        Ok(None) => return Ok(not_found()),
+        Err(e) => {
+            error!("Failed to load {}: {}", req.url().path(), e);
+
+            return Ok(Response::new(StatusCode::InternalServerError));
+        }
+    };
+
+    let unchanged = req
+        .header("If-None-Match")
+        .map_or(false, |etag| etag.as_str() == asset.etag);
+
+    let response = if unchanged {
+        Response::builder(StatusCode::NotModified)
+    } else {
+        Response::builder(StatusCode::Ok)
+            .body(Body::from_bytes(asset.contents.into_owned()))
+            .content_type(Mime::from_str(asset.mime)?)
+    };
+
+    Ok(response
+        .header("ETag", asset.etag)
+        .header("Cache-Control", asset.cache_control)
         .build())
 }
+
+fn not_found() -> Response {
+    Response::builder(StatusCode::NotFound)
+        .body("404: Endpoint does not exist")
+        .build()
+}
@@ -0,0 +1,216 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use async_std::task;
+use serde::Deserialize;
+use serde_json::json;
+use tide::{Body, Request, StatusCode};
+
+use crate::{
+    compare::{
+        common_keys, compare, difference, event_time, series, Alignment, CompareError, Comparison,
+        Series,
+    },
+    objects::{difference_key, parse_difference_key},
+    session::{CatalogError, SessionId, SESSION_CATALOG},
+    store::SessionData,
+    telemetry::TelemetryDictionary,
+    State,
+};
+
+#[derive(Debug, Deserialize)]
+struct CompareQuery {
+    a: SessionId,
+    b: SessionId,
+    /// comma separated telemetry keys to compare, every key both sessions
+    /// have if not given
+    keys: Option<String>,
+    /// microseconds added to the `running_us` of session `b`
+    offset: Option<i64>,
+    /// the key of the telemetry point the sessions are aligned by
+    event: Option<String>,
+    /// the value of the event, its first change if not given
+    value: Option<f64>,
+    /// the range of the aligned timeline compared, in session `a`'s `running_us`
+    start: Option<f64>,
+    end: Option<f64>,
+}
+
+/// The range of a difference object's history, as Open MCT requests it
+#[derive(Debug, Deserialize)]
+struct DifferenceQuery {
+    start: Option<f64>,
+    end: Option<f64>,
+}
+
+/// Two sessions to be compared, along with their aligned timelines
+struct Compared {
+    offset: i64,
+    a: BTreeMap<String, Series>,
+    b: BTreeMap<String, Series>,
+}
+
+/// Aligns two sessions and compares the statistics of their telemetry
+pub async fn compare_sessions(req: Request<State>) -> tide::Result<Body> {
+    let query: CompareQuery = req.query()?;
+    let keys = query.keys.clone();
+
+    let compared = aligned(&query, |a, b| match &keys {
+        Some(keys) => keys
+            .split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(str::to_string)
+            .collect(),
+        None => common_keys(a, b),
+    })
+    .await?;
+
+    let keys = compared
+        .a
+        .iter()
+        .map(|(key, a)| (key.clone(), compare(a, &compared.b[key])))
+        .collect();
+
+    Body::from_json(&Comparison {
+        a: query.a,
+        b: query.b,
+        offset_us: compared.offset,
+        differences: difference_key(query.a, query.b, compared.offset, None),
+        keys,
+    })
+}
+
+/// The difference between a telemetry point of two aligned sessions, on the
+/// timeline of session `a`, as telemetry datums that Open MCT can plot. `key`
+/// is either a telemetry point of both sessions given by the query, or a
+/// difference object from `/objects`, which names the sessions itself.
+pub async fn get_difference(req: Request<State>) -> tide::Result<Body> {
+    let id = req.param("key")?.to_string();
+
+    let (query, key) = match parse_difference_key(&id) {
+        Some((a, b, offset, Some(key))) => {
+            let DifferenceQuery { start, end } = req.query()?;
+            let query = CompareQuery {
+                a,
+                b,
+                keys: None,
+                offset: Some(offset),
+                event: None,
+                value: None,
+                start,
+                end,
+            };
+
+            (query, key.to_string())
+        }
+        _ => (req.query()?, id.clone()),
+    };
+
+    let compared = aligned(&query, |_, _| vec![key.clone()]).await?;
+
+    let data = difference(&compared.a[&key], &compared.b[&key])
+        .into_iter()
+        .map(|(running_us, value)| {
+            json!({
+                "id": id,
+                "value": value,
+                "running_us": running_us,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Body::from_json(&data)
+}
+
+/// Looks up both sessions, checks that `keys` are telemetry points of both and
+/// reads their series, with session `b` moved onto the timeline of session `a`
+async fn aligned(
+    query: &CompareQuery,
+    keys: impl FnOnce(&TelemetryDictionary, &TelemetryDictionary) -> Vec<String>,
+) -> tide::Result<Compared> {
+    let alignment = match (query.offset, &query.event) {
+        (Some(_), Some(_)) => {
+            return Err(tide::Error::from_str(
+                StatusCode::BadRequest,
+                "sessions are aligned by either an offset or an event, not both",
+            ))
+        }
+        (Some(offset), None) => Alignment::Offset(offset),
+        (None, Some(key)) => Alignment::Event {
+            key: key.clone(),
+            value: query.value,
+        },
+        (None, None) => Alignment::Offset(0),
+    };
+
+    let (a_dictionary, a) = session(query.a).await?;
+    let (b_dictionary, b) = session(query.b).await?;
+
+    let keys = keys(a_dictionary, b_dictionary);
+    let common = common_keys(a_dictionary, b_dictionary);
+
+    let unknown = keys
+        .iter()
+        .chain(match &alignment {
+            Alignment::Event { key, .. } => Some(key),
+            Alignment::Offset(_) => None,
+        })
+        .filter(|&key| !common.contains(key))
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+
+    if !unknown.is_empty() {
+        return Err(tide::Error::from_str(
+            StatusCode::BadRequest,
+            format!(
+                "telemetry keys not in both sessions: {}",
+                unknown.join(", ")
+            ),
+        ));
+    }
+
+    let start = query.start.map_or(i64::MIN, |start| start.floor() as i64);
+    let end = query.end.map_or(i64::MAX, |end| end.ceil() as i64);
+    let (a_id, b_id) = (query.a, query.b);
+
+    // Sessions may have to be read back from disk
+    task::spawn_blocking(move || {
+        let (a, b) = (a.snapshot(), b.snapshot());
+
+        let offset = match alignment {
+            Alignment::Offset(offset) => offset,
+            Alignment::Event { key, value } => {
+                let missing = |session| {
+                    tide::Error::new(
+                        StatusCode::UnprocessableEntity,
+                        CompareError::MissingEvent {
+                            session,
+                            key: key.clone(),
+                        },
+                    )
+                };
+
+                let a_time = event_time(&a, &key, value).ok_or_else(|| missing(a_id))?;
+                let b_time = event_time(&b, &key, value).ok_or_else(|| missing(b_id))?;
+
+                a_time as i64 - b_time as i64
+            }
+        };
+
+        let out_of_range = |e| tide::Error::new(StatusCode::BadRequest, e);
+        let a = series(&a, a_id, &keys, 0, start..=end).map_err(out_of_range)?;
+        let b = series(&b, b_id, &keys, offset, start..=end).map_err(out_of_range)?;
+
+        Ok(Compared { offset, a, b })
+    })
+    .await
+}
+
+async fn session(id: SessionId) -> tide::Result<(&'static TelemetryDictionary, Arc<SessionData>)> {
+    SESSION_CATALOG
+        .read()
+        .await
+        .get(id)
+        .map(|entry| (entry.info.metadata.dictionary, entry.data.clone()))
+        .ok_or_else(|| tide::Error::new(StatusCode::NotFound, CatalogError::NotFound(id)))
+}
@@ -1,97 +1,618 @@
-use std::{collections::BTreeMap, time::Duration};
+use std::{
+    collections::BTreeMap,
+    io,
+    ops::Bound,
+    time::{Duration, Instant},
+};
 
 use anyhow::anyhow;
-use async_std::task;
-use crossfire::mpsc::unbounded_future;
+use async_std::{
+    channel::{self, Receiver, Sender as ChannelSender},
+    sync::Mutex,
+    task::{self, JoinHandle},
+};
+use futures_lite::{future, FutureExt};
+use lazy_static::lazy_static;
 use log::{debug, error, info};
 use serde::Deserialize;
-use tide::{sse::Sender, Body, Request, StatusCode};
+use tide::{sse::Sender, Body, Request, Response, StatusCode};
 
-use crate::{serial::get_serial_ports, State};
-
-use super::super::ingest::ingest;
+use crate::{
+    config::{config, QueuePolicy},
+    ingest::{ingest, IngestEvent, IngestLock, LINK_STATS},
+    queue::{self, EventReceiver},
+    relay::RELAY_PORT,
+    serial::get_serial_ports,
+    session::{session_data, SessionId, SessionMetadata, SESSION_CATALOG},
+    simulator::{SIMULATOR_PORT, SIMULATOR_PRODUCT},
+    source::{OpenedSource, SourceError, SourceParameters},
+    State,
+};
 
 pub async fn list_devices(_: Request<State>) -> tide::Result<Body> {
     let serial_ports = get_serial_ports()
         .await?
         .map(|port| (port.name, port.product))
+        .chain(
+            config()
+                .simulator
+                .enabled
+                .then(|| (SIMULATOR_PORT.to_string(), Some(&SIMULATOR_PRODUCT))),
+        )
+        .chain(
+            config()
+                .relay
+                .upstream
+                .is_some()
+                .then(|| (RELAY_PORT.to_string(), None)),
+        )
         .collect::<BTreeMap<_, _>>();
 
     Body::from_json(&serial_ports)
 }
 
-#[derive(Deserialize)]
-struct DeviceConnectQuery {
-    port: String,
-    timeout: Option<u64>,
-    baud: Option<u32>,
+/// The session being ingested into, or the latest session if nothing is connected
+pub async fn get_session(_: Request<State>) -> tide::Result<Body> {
+    let catalog = SESSION_CATALOG.read().await;
+
+    match catalog.live().or_else(|| catalog.iter().next_back()) {
+        Some(entry) => Body::from_json(&catalog.summary(entry)),
+        None => Body::from_json(&()),
+    }
+}
+
+/// The link statistics of every source ingested from since the server started
+pub async fn get_links(_: Request<State>) -> tide::Result<Body> {
+    Body::from_json(&*LINK_STATS.read().await)
+}
+
+/// How the telemetry streamed to a client is queued, defaulting to the
+/// configured `[stream]`
+#[derive(Debug, Deserialize)]
+struct StreamParameters {
+    /// the most telemetry packets queued for the client
+    queue: Option<usize>,
+    policy: Option<QueuePolicy>,
+}
+
+lazy_static! {
+    /// The streams being ingested for a client, keyed by their token
+    static ref LIVE_STREAMS: Mutex<BTreeMap<String, StreamState>> = Mutex::new(BTreeMap::new());
 }
 
+enum StreamState {
+    /// being forwarded to its client, which may have asked to be disconnected
+    /// once it goes away
+    Attached {
+        disconnecting: bool,
+        /// where a client resuming the stream before its connection is noticed
+        /// to have dropped asks for it to be handed over
+        superseded: ChannelSender<ChannelSender<LiveStream>>,
+    },
+    /// the client's connection dropped, and the device keeps being ingested
+    /// until it resumes the stream or the resume timeout passes
+    Detached(LiveStream),
+}
+
+/// A device being ingested for a client
+struct LiveStream {
+    /// an unguessable token for the stream, which only its client knows
+    token: String,
+    port_name: String,
+    rx: EventReceiver,
+    ingest_task: JoinHandle<io::Result<()>>,
+    /// the session the packets are stored in, once the ingest thread began it
+    session: Option<SessionId>,
+    /// when the client's connection dropped, if it is detached
+    detached: Option<Instant>,
+    /// where the stream is asked to be handed over while it is attached
+    takeovers: Receiver<ChannelSender<LiveStream>>,
+}
+
+/// Why a stream stopped being forwarded to its client
+enum Ended {
+    /// the client disconnected
+    Client,
+    /// the ingest thread stopped
+    Ingest,
+    /// another client resumed the stream, which is to be handed over to it
+    Superseded(ChannelSender<LiveStream>),
+}
+
+#[derive(Debug, Deserialize)]
+struct DisconnectQuery {
+    /// the token of the stream, sent to its client as a `stream` event
+    stream: String,
+}
+
+/// A new stream token, 128 bits from the operating system's random number
+/// generator, so that other clients cannot guess it
+fn stream_token() -> String {
+    let mut token = [0; 16];
+    getrandom::getrandom(&mut token).expect("failed to generate a stream token");
+
+    token.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The id of a telemetry event, which a client resumes its stream from
+fn event_id(token: &str, session: SessionId, running_us: u64) -> String {
+    format!("{}:{}:{}", token, session, running_us)
+}
+
+/// The token of a stream, along with the session and `running_us` of the last
+/// packet the client got, if it got any
+fn parse_event_id(id: &str) -> (&str, Option<(SessionId, u64)>) {
+    let mut parts = id.splitn(3, ':');
+    let token = parts.next().unwrap_or_default();
+
+    let position = (|| Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?)))();
+
+    (token, position)
+}
+
+/// Connects to the source described by the query, see [`SourceParameters`], and
+/// streams its telemetry as server sent events until the client disconnects.
+/// Telemetry waits in a bounded queue, see [`StreamParameters`], whose
+/// statistics are sent as `queue` events alongside the link statistics.
+///
+/// The stream starts with a `stream` event holding its token, which telemetry
+/// events carry in their id along with the session and `running_us` of their
+/// packet. If the client's connection drops, the device keeps being ingested
+/// for the resume timeout. A client that reconnects within it with the id of
+/// the last event it got as its `Last-Event-ID` is sent the packets it missed
+/// from the session's history before the stream carries on, and a client that
+/// connects to the same device takes the stream over. Clients that are done
+/// with a device disconnect it through [`device_disconnect`]. An `end` event is
+/// sent once the device is gone, after which the stream cannot be resumed.
+///
+/// A client may resume its stream before the server notices its connection
+/// dropped. The stream is then handed over to the new connection, and the old
+/// one is sent a `superseded` event.
 pub async fn device_connect(req: Request<State>, sender: Sender) -> tide::Result<()> {
-    let DeviceConnectQuery {
-        port: port_name,
-        timeout,
-        baud,
-    } = req.query()?;
-
-    if port_name.is_empty() {
-        return Err(tide::Error::new(
+    let last_event_id = req
+        .header("Last-Event-ID")
+        .map(|id| id.last().as_str().to_string());
+    let (token, position) = match &last_event_id {
+        Some(id) => parse_event_id(id),
+        None => ("", None),
+    };
+
+    let (mut stream, resume_from) = match attach(Some(token), None).await {
+        Some(stream) => {
+            info!("Client resumed its stream of device {}", stream.port_name);
+
+            (stream, position)
+        }
+        None => {
+            let mut parameters: SourceParameters = req.query()?;
+            parameters.authorize().map_err(source_error)?;
+            let port_name = parameters.name().ok();
+
+            match attach(None, port_name.as_deref()).await {
+                Some(stream) => {
+                    info!("Client took over the stream of device {}", stream.port_name);
+
+                    (stream, None)
+                }
+                None => (open_stream(&req, parameters).await?, None),
+            }
+        }
+    };
+
+    // A resumed client's position stays its last event id until it gets more
+    let id = match (&last_event_id, resume_from) {
+        (Some(id), Some(_)) => id.clone(),
+        _ => stream.token.clone(),
+    };
+    let started = sender
+        .send("stream", stream.token.as_str(), Some(id.as_str()))
+        .await;
+
+    let takeovers = stream.takeovers.clone();
+    let superseded = async {
+        match takeovers.recv().await {
+            Ok(handover) => Ok(Ended::Superseded(handover)),
+            Err(_) => future::pending().await,
+        }
+    };
+
+    let ended = match started {
+        Ok(()) => {
+            forward(&sender, &mut stream, resume_from)
+                .or(superseded)
+                .await
+        }
+        Err(_) => Ok(Ended::Client),
+    };
+
+    let (ended, disconnecting) = {
+        let mut streams = LIVE_STREAMS.lock().await;
+
+        // A client that resumed the stream as it ended still takes it over
+        match (ended, stream.takeovers.try_recv()) {
+            (Ok(Ended::Superseded(handover)), _) | (_, Ok(handover)) => {
+                (Ok(Ended::Superseded(handover)), false)
+            }
+            (ended, Err(_)) => match streams.remove(&stream.token) {
+                Some(StreamState::Attached { disconnecting, .. }) => (ended, disconnecting),
+                _ => (ended, false),
+            },
+        }
+    };
+
+    if let Ok(Ended::Superseded(handover)) = ended {
+        info!(
+            "Client's stream of device {} was resumed by another connection",
+            stream.port_name
+        );
+
+        let _ = sender.send("superseded", "", None).await;
+
+        // The other client gave up waiting for it
+        if let Err(e) = handover.send(stream).await {
+            close(e.into_inner()).await;
+        }
+
+        return Ok(());
+    }
+
+    match ended {
+        Ok(Ended::Client) if !disconnecting && config().stream.resume_timeout_s > 0.0 => {
+            detach(stream).await
+        }
+        _ => close(stream).await,
+    }
+
+    ended.map(|_| ())
+}
+
+/// Stops ingesting the stream whose token is in the query once its client goes
+/// away, rather than waiting for it to resume. Closing an event source looks
+/// the same to the server as a dropped connection, so clients ask for this
+/// before they close it.
+pub async fn device_disconnect(req: Request<State>) -> tide::Result<Response> {
+    let DisconnectQuery { stream: token } = req.query()?;
+
+    let detached = {
+        let mut streams = LIVE_STREAMS.lock().await;
+
+        match streams.get_mut(&token) {
+            Some(StreamState::Attached { disconnecting, .. }) => {
+                *disconnecting = true;
+
+                None
+            }
+            Some(StreamState::Detached(_)) => match streams.remove(&token) {
+                Some(StreamState::Detached(stream)) => Some(stream),
+                _ => None,
+            },
+            None => {
+                return Err(tide::Error::from_str(
+                    StatusCode::NotFound,
+                    "no such stream",
+                ))
+            }
+        }
+    };
+
+    if let Some(stream) = detached {
+        close(stream).await;
+    }
+
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+/// Attaches a new client to a detached stream, either the one with `token` or
+/// the one of the device named `port_name`, which is then given a new token. A
+/// stream with `token` that is still attached is taken over from its client.
+async fn attach(token: Option<&str>, port_name: Option<&str>) -> Option<LiveStream> {
+    let mut streams = LIVE_STREAMS.lock().await;
+
+    let token = match (token, port_name) {
+        (Some(token), _) => token.to_string(),
+        (None, Some(port_name)) => streams
+            .iter()
+            .find(|(_, state)| {
+                matches!(state, StreamState::Detached(stream) if stream.port_name == port_name)
+            })
+            .map(|(token, _)| token.clone())?,
+        (None, None) => return None,
+    };
+
+    let mut stream = match streams.get(&token) {
+        Some(StreamState::Detached(_)) => match streams.remove(&token) {
+            Some(StreamState::Detached(stream)) => stream,
+            _ => return None,
+        },
+        Some(StreamState::Attached { superseded, .. }) if port_name.is_none() => {
+            // Asked while the streams are locked, so that the client the stream
+            // is attached to sees the request if its stream ends meanwhile
+            let (handover, handed_over) = channel::bounded(1);
+            superseded.try_send(handover).ok()?;
+            drop(streams);
+
+            let stream = handed_over.recv().await.ok()?;
+            streams = LIVE_STREAMS.lock().await;
+
+            stream
+        }
+        _ => return None,
+    };
+
+    if port_name.is_some() {
+        // Whoever had the stream before can no longer resume it
+        stream.token = stream_token();
+    }
+
+    stream.detached = None;
+    attached(&mut streams, &mut stream);
+
+    Some(stream)
+}
+
+/// Marks a stream as being forwarded to a new client
+fn attached(streams: &mut BTreeMap<String, StreamState>, stream: &mut LiveStream) {
+    let (superseded, takeovers) = channel::bounded(1);
+    stream.takeovers = takeovers;
+
+    streams.insert(
+        stream.token.clone(),
+        StreamState::Attached {
+            disconnecting: false,
+            superseded,
+        },
+    );
+}
+
+fn source_error(err: SourceError) -> tide::Error {
+    match err {
+        SourceError::Invalid(reason) => tide::Error::new(StatusCode::BadRequest, anyhow!(reason)),
+        SourceError::Forbidden(reason) => tide::Error::new(StatusCode::Forbidden, anyhow!(reason)),
+        err => {
+            error!("{}", err);
+
+            tide::Error::new(StatusCode::ServiceUnavailable, err)
+        }
+    }
+}
+
+/// Opens the source described by the request and starts ingesting it
+async fn open_stream(
+    req: &Request<State>,
+    parameters: SourceParameters,
+) -> tide::Result<LiveStream> {
+    let stream: StreamParameters = req.query()?;
+
+    if stream.queue == Some(0) {
+        return Err(tide::Error::from_str(
             StatusCode::BadRequest,
-            anyhow!("device {} does not exist", port_name),
+            "the queue must hold at least one packet",
         ));
     }
 
-    // FIXME: not 1s for timeout?
-    // Assuming Pico SDK USB CDC so baud rate does not matter
-    match serialport::new(&port_name, baud.unwrap_or(0))
-        .timeout(Duration::from_millis(timeout.unwrap_or(1000)))
-        .open()
-    {
-        Ok(new_port) => {
-            info!("Connected to device {}", port_name);
-
-            let (tx, rx) = unbounded_future();
-
-            let ingest_task = task::spawn_blocking(move || ingest(tx, new_port));
-
-            loop {
-                let packet = match rx.recv().await {
-                    Ok(packet) => packet,
-                    Err(_) => {
-                        error!("Failed to get a packet from the ingest thread");
-                        break;
+    let lock = IngestLock::acquire().ok_or_else(|| {
+        tide::Error::from_str(StatusCode::Conflict, "another device is already connected")
+    })?;
+
+    let OpenedSource {
+        source,
+        name: port_name,
+        product,
+        serial_number,
+    } = parameters.open().await.map_err(source_error)?;
+
+    info!("Connected to device {}", port_name);
+
+    let session = SessionMetadata::new(port_name.clone(), product, serial_number);
+
+    let (tx, rx) = queue::bounded(
+        stream.queue.unwrap_or(config().stream.queue),
+        stream.policy.unwrap_or(config().stream.policy),
+    );
+
+    let ingest_task = {
+        let port_name = port_name.clone();
+
+        task::spawn_blocking(move || ingest(lock, tx, port_name, session, source, None))
+    };
+
+    let mut stream = LiveStream {
+        token: stream_token(),
+        port_name,
+        rx,
+        ingest_task,
+        session: None,
+        detached: None,
+        takeovers: channel::bounded(1).1,
+    };
+    attached(&mut *LIVE_STREAMS.lock().await, &mut stream);
+
+    Ok(stream)
+}
+
+/// Sends the events of a stream to its client, after replaying the packets of
+/// its session since `resume_from`
+async fn forward(
+    sender: &Sender,
+    stream: &mut LiveStream,
+    resume_from: Option<(SessionId, u64)>,
+) -> tide::Result<Ended> {
+    // The last packet replayed from history. Live packets up to it were queued
+    // while the client was away, and it already has them.
+    let mut replayed_until = None;
+
+    if let Some((session, after)) = resume_from {
+        let data = session_data(session).await.unwrap_or_default();
+
+        for packet in data.range((Bound::Excluded(after), Bound::Unbounded)) {
+            let id = event_id(&stream.token, session, packet.running_us);
+
+            if sender
+                .send(
+                    "telemetry",
+                    serde_json::to_string(&packet)?,
+                    Some(id.as_str()),
+                )
+                .await
+                .is_err()
+            {
+                info!("Client disconnected from event source");
+
+                return Ok(Ended::Client);
+            }
+
+            replayed_until = Some(packet.running_us);
+        }
+
+        debug!(
+            "Replayed session {} from {} to {:?}",
+            session, after, replayed_until
+        );
+    }
+
+    loop {
+        let event = match stream.rx.recv().await {
+            Ok(event) => event,
+            Err(_) => {
+                error!("Failed to get a packet from the ingest thread");
+
+                // The client would otherwise reconnect to a device that is gone
+                let _ = sender.send("end", "", None).await;
+
+                return Ok(Ended::Ingest);
+            }
+        };
+
+        let sent = match event {
+            IngestEvent::Session(session) => {
+                if stream.session != Some(session) {
+                    replayed_until = None;
+                }
+
+                stream.session = Some(session);
+
+                continue;
+            }
+            IngestEvent::Telemetry(packet) => {
+                if let Some(until) = replayed_until {
+                    if packet.running_us <= until {
+                        continue;
                     }
-                };
 
+                    replayed_until = None;
+                }
+
+                let id = stream
+                    .session
+                    .map(|session| event_id(&stream.token, session, packet.running_us));
+
+                sender
+                    .send("telemetry", serde_json::to_string(&packet)?, id.as_deref())
+                    .await
+            }
+            IngestEvent::Schema(report) => {
+                sender
+                    .send("schema", serde_json::to_string(&report)?, None)
+                    .await
+            }
+            IngestEvent::Firmware(firmware) => {
+                sender
+                    .send("firmware", serde_json::to_string(&firmware)?, None)
+                    .await
+            }
+            IngestEvent::Link(stats) => {
+                // Sent first, so the client has the latest queue statistics
+                // when it shows the link's
                 match sender
-                    .send("telemetry", serde_json::to_string(&packet)?, None)
+                    .send("queue", serde_json::to_string(&stream.rx.stats())?, None)
                     .await
                 {
-                    Ok(()) => {}
-                    Err(_) => {
-                        info!("Client disconnected from event source");
-                        break;
+                    Ok(()) => {
+                        sender
+                            .send("link", serde_json::to_string(&stats)?, None)
+                            .await
                     }
+                    Err(e) => Err(e),
                 }
             }
+        };
 
-            debug!("Disconnecting from device {}", port_name);
+        match sent {
+            Ok(()) => {}
+            Err(_) => {
+                info!("Client disconnected from event source");
 
-            if let Some(Err(err)) = ingest_task.cancel().await {
-                error!("Ingest task encountered an error: {}", err);
+                return Ok(Ended::Client);
             }
+        }
+    }
+}
 
-            info!("Disconnected from device {}", port_name);
+/// Keeps ingesting a stream whose client's connection dropped, closing it
+/// unless the client resumes it within the resume timeout
+async fn detach(mut stream: LiveStream) {
+    let timeout = Duration::from_secs_f64(config().stream.resume_timeout_s);
+    let detached = Instant::now();
+    let token = stream.token.clone();
 
-            Ok(())
-        }
-        Err(err) => {
-            error!("Failed to open serial port {}: {}", port_name, err);
+    debug!(
+        "Keeping device {} connected for {:?} in case the client resumes",
+        stream.port_name, timeout
+    );
+
+    stream.detached = Some(detached);
+    LIVE_STREAMS
+        .lock()
+        .await
+        .insert(token.clone(), StreamState::Detached(stream));
 
-            Err(tide::Error::new(
-                StatusCode::ServiceUnavailable,
-                anyhow!("failed to open device {}", port_name),
-            ))
+    task::spawn(async move {
+        task::sleep(timeout).await;
+
+        let expired = {
+            let mut streams = LIVE_STREAMS.lock().await;
+
+            // The client may have resumed and disconnected again since
+            let unresumed = matches!(
+                streams.get(&token),
+                Some(StreamState::Detached(stream)) if stream.detached == Some(detached)
+            );
+
+            if unresumed {
+                streams.remove(&token)
+            } else {
+                None
+            }
+        };
+
+        if let Some(StreamState::Detached(stream)) = expired {
+            info!(
+                "Client did not resume its stream of device {}",
+                stream.port_name
+            );
+
+            close(stream).await;
         }
+    });
+}
+
+/// Stops ingesting a stream
+async fn close(stream: LiveStream) {
+    let LiveStream {
+        port_name,
+        rx,
+        ingest_task,
+        ..
+    } = stream;
+
+    debug!("Disconnecting from device {}", port_name);
+
+    // The ingest thread stops once it notices the queue was closed
+    drop(rx);
+
+    if let Some(Err(err)) = ingest_task.cancel().await {
+        error!("Ingest task encountered an error: {}", err);
     }
+
+    info!("Disconnected from device {}", port_name);
 }
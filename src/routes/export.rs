@@ -0,0 +1,204 @@
+use std::io::{self, Write};
+
+use serde::{ser::SerializeMap, Deserialize, Serialize, Serializer};
+use tide::{Request, Response, StatusCode};
+
+use super::history::{stream_body, ContentEncoding};
+use crate::{
+    session::{active_dictionary, served_data},
+    telemetry::{Identifier, TelemetryPacket, TelemetryValue, TELEMETRY_TIME_FIELD},
+    State,
+};
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    /// comma separated telemetry keys, one column each
+    keys: String,
+    /// the first `running_us` to export
+    start: Option<u64>,
+    /// the last `running_us` to export
+    end: Option<u64>,
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ExportFormat {
+    Csv,
+    /// one JSON object per line
+    Jsonl,
+    /// a CBOR sequence, one map per packet
+    Cbor,
+}
+
+impl Default for ExportFormat {
+    fn default() -> Self {
+        ExportFormat::Csv
+    }
+}
+
+impl ExportFormat {
+    fn mime(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Jsonl => "application/x-ndjson",
+            ExportFormat::Cbor => "application/cbor-seq",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Jsonl => "jsonl",
+            ExportFormat::Cbor => "cbor",
+        }
+    }
+}
+
+/// Streams a table of whole packets, one row per packet and one column per key,
+/// as a download
+pub async fn export(req: Request<State>) -> tide::Result<Response> {
+    let query: ExportQuery = req.query()?;
+
+    let mut keys = Vec::<String>::new();
+    for key in query
+        .keys
+        .split(',')
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+    {
+        // Every row starts with the time anyway
+        if key != TELEMETRY_TIME_FIELD.name && !keys.iter().any(|existing| existing == key) {
+            keys.push(key.to_string());
+        }
+    }
+
+    if keys.is_empty() {
+        return Err(tide::Error::from_str(
+            StatusCode::BadRequest,
+            "no telemetry keys to export",
+        ));
+    }
+
+    let dictionary = active_dictionary().await;
+    let unknown = keys
+        .iter()
+        .filter(|key| dictionary.metadata(Identifier::from_key(key)).is_none())
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+
+    if !unknown.is_empty() {
+        return Err(tide::Error::from_str(
+            StatusCode::BadRequest,
+            format!("unknown telemetry keys: {}", unknown.join(", ")),
+        ));
+    }
+
+    let start = query.start.unwrap_or(0);
+    let end = query.end.unwrap_or(u64::MAX);
+
+    if start > end {
+        return Err(tide::Error::from_str(
+            StatusCode::BadRequest,
+            "the start of the range is after its end",
+        ));
+    }
+
+    let data = served_data().await.unwrap_or_default();
+    let format = query.format;
+
+    // Rows are encoded as they are sent, reading any that were spilled back
+    // from disk off of the executor
+    let body = stream_body(
+        ContentEncoding::Identity,
+        "an export".to_string(),
+        move |out| {
+            if let ExportFormat::Csv = format {
+                write_csv_header(out, &keys)?;
+            }
+
+            for packet in data.range(start..=end) {
+                write_row(out, format, &keys, &packet)?;
+            }
+
+            Ok(())
+        },
+    );
+
+    let filename = format!(
+        "export-{}-{}.{}",
+        start,
+        query
+            .end
+            .map_or_else(|| "end".to_string(), |end| end.to_string()),
+        query.format.extension()
+    );
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(body)
+        .content_type(query.format.mime())
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", filename),
+        )
+        .build())
+}
+
+fn write_csv_header(out: &mut dyn Write, keys: &[String]) -> io::Result<()> {
+    out.write_all(TELEMETRY_TIME_FIELD.name.as_bytes())?;
+
+    for key in keys {
+        write!(out, ",{}", key)?;
+    }
+
+    writeln!(out)
+}
+
+fn write_row(
+    out: &mut dyn Write,
+    format: ExportFormat,
+    keys: &[String],
+    packet: &TelemetryPacket,
+) -> io::Result<()> {
+    match format {
+        ExportFormat::Csv => {
+            write!(out, "{}", packet.running_us)?;
+
+            for key in keys {
+                match packet.values.get(key) {
+                    Some(TelemetryValue::Boolean(value)) => write!(out, ",{}", value)?,
+                    Some(TelemetryValue::Integer(value)) => write!(out, ",{}", value)?,
+                    Some(TelemetryValue::Float(value)) => write!(out, ",{}", value)?,
+                    None => write!(out, ",")?,
+                }
+            }
+
+            writeln!(out)
+        }
+        ExportFormat::Jsonl => {
+            serde_json::to_writer(&mut *out, &RowFields(keys, packet))?;
+
+            writeln!(out)
+        }
+        ExportFormat::Cbor => serde_cbor::to_writer(out, &RowFields(keys, packet))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+    }
+}
+
+/// A row as a map from key to value, with missing values as null
+struct RowFields<'r>(&'r [String], &'r TelemetryPacket);
+
+impl Serialize for RowFields<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let RowFields(keys, packet) = self;
+
+        let mut map = serializer.serialize_map(Some(keys.len() + 1))?;
+        map.serialize_entry(TELEMETRY_TIME_FIELD.name, &packet.running_us)?;
+        for key in keys.iter() {
+            map.serialize_entry(key, &packet.values.get(key))?;
+        }
+
+        map.end()
+    }
+}
@@ -1,34 +1,518 @@
-use serde::Deserialize;
-use serde_json::json;
-use tide::{Body, Request, Result};
+use std::{
+    io::{self, Write},
+    mem,
+    ops::Range,
+    pin::Pin,
+    task::{Context, Poll},
+};
 
-use crate::ingest::TIMESCALE_DATA;
+use async_std::{
+    channel::{self, Receiver, Sender},
+    io::{BufReader, Read},
+    stream::Stream,
+    task,
+};
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tide::{Body, Request, Response, StatusCode};
+
+use crate::objects::parse_session_key;
+use crate::session::{served_data, session_data};
+use crate::store::Snapshot;
+use crate::telemetry::TelemetryValue;
 use crate::State;
 
+/// How much of a response is encoded at a time while it is streamed
+const HISTORY_CHUNK_SIZE: usize = 16 * 1024;
+/// Encoded chunks waiting to be sent before encoding waits for the client
+const HISTORY_CHUNKS_QUEUED: usize = 4;
+
+/// The start of a CBOR array of indefinite length
+const CBOR_ARRAY: u8 = 0x9f;
+/// The start of a CBOR map of indefinite length
+const CBOR_MAP: u8 = 0xbf;
+/// The end of a CBOR array or map of indefinite length
+const CBOR_BREAK: u8 = 0xff;
+
 #[derive(Debug, Deserialize)]
 struct HistoryDatumQuery {
     start: f64,
     end: f64,
+    /// the encoding of the response, instead of the one the `Accept` header
+    /// asks for
+    format: Option<HistoryFormat>,
+    #[serde(default)]
+    layout: HistoryLayout,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum HistoryFormat {
+    Json,
+    Cbor,
+}
+
+impl HistoryFormat {
+    /// The formats in the order they are preferred, by their mime type
+    const OFFERED: [(&'static str, HistoryFormat); 2] = [
+        ("application/json", HistoryFormat::Json),
+        ("application/cbor", HistoryFormat::Cbor),
+    ];
+
+    fn mime(self) -> &'static str {
+        match self {
+            HistoryFormat::Json => "application/json",
+            HistoryFormat::Cbor => "application/cbor",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum HistoryLayout {
+    /// an array of Open MCT telemetry datums
+    Rows,
+    /// an object holding the `id` of the telemetry point along with arrays of
+    /// the `running_us`, `value` and, if the point has any, `raw` of every datum
+    Columns,
+}
+
+impl Default for HistoryLayout {
+    fn default() -> Self {
+        HistoryLayout::Rows
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) enum ContentEncoding {
+    Identity,
+    Gzip,
+    Deflate,
+}
+
+impl ContentEncoding {
+    /// The encodings in the order they are preferred
+    const OFFERED: [(&'static str, ContentEncoding); 3] = [
+        ("gzip", ContentEncoding::Gzip),
+        ("deflate", ContentEncoding::Deflate),
+        ("identity", ContentEncoding::Identity),
+    ];
+
+    fn name(self) -> Option<&'static str> {
+        match self {
+            ContentEncoding::Identity => None,
+            ContentEncoding::Gzip => Some("gzip"),
+            ContentEncoding::Deflate => Some("deflate"),
+        }
+    }
 }
 
-pub async fn get_datum(req: Request<State>) -> Result<Body> {
+/// A telemetry datum as Open MCT expects it
+#[derive(Debug, Serialize)]
+struct HistoryDatum<'k> {
+    id: &'k str,
+    value: Option<TelemetryValue>,
+    running_us: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw: Option<TelemetryValue>,
+}
+
+/// The history of a telemetry point within a range of `running_us`, encoded as
+/// JSON or CBOR as negotiated by the `Accept` header, compressed as negotiated
+/// by the `Accept-Encoding` header, and streamed as it is encoded
+pub async fn get_datum(req: Request<State>) -> tide::Result<Response> {
     let query: HistoryDatumQuery = req.query()?;
     let key = req.param("key")?;
 
     let start = query.start.floor().max(0.0) as u64;
     let end = query.end.ceil().max(0.0) as u64;
 
-    let timescale_data = TIMESCALE_DATA.read().await;
+    let format = match (query.format, req.header("Accept")) {
+        (Some(format), _) => format,
+        (None, None) => HistoryFormat::Json,
+        (None, Some(accept)) => negotiate(accept.last().as_str(), &HistoryFormat::OFFERED)
+            .ok_or_else(|| {
+                tide::Error::from_str(
+                    StatusCode::NotAcceptable,
+                    "history is available as application/json or application/cbor",
+                )
+            })?,
+    };
+
+    let encoding = req
+        .header("Accept-Encoding")
+        .and_then(|accept| negotiate(accept.last().as_str(), &ContentEncoding::OFFERED))
+        .unwrap_or(ContentEncoding::Identity);
+
+    // Telemetry points scoped to a session are served from that session, rather
+    // than the one currently served
+    let (timescale_data, value_key) = match parse_session_key(key) {
+        Some((id, Some(inner))) => (session_data(id).await, inner),
+        _ => (served_data().await, key),
+    };
+
+    let timescale_data = timescale_data.unwrap_or_default();
+    let (id, value_key) = (key.to_string(), value_key.to_string());
+    let layout = query.layout;
+
+    let body = stream_body(
+        encoding,
+        format!("the history of {}", key),
+        move |mut out| {
+            write_history(
+                &mut out,
+                &timescale_data,
+                start..end,
+                &id,
+                &value_key,
+                format,
+                layout,
+            )
+        },
+    );
+
+    let mut response = Response::builder(StatusCode::Ok)
+        .body(body)
+        .content_type(format.mime())
+        .header("Vary", "Accept, Accept-Encoding");
+
+    if let Some(name) = encoding.name() {
+        response = response.header("Content-Encoding", name);
+    }
+
+    Ok(response.build())
+}
+
+/// A response body written by `write`, compressed with `encoding` and streamed
+/// as it is written. Writing a large response takes a while, so it is done off
+/// of the executor, waiting whenever the client falls behind.
+pub(super) fn stream_body(
+    encoding: ContentEncoding,
+    description: String,
+    write: impl FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static,
+) -> Body {
+    let (sender, receiver) = channel::bounded(HISTORY_CHUNKS_QUEUED);
+
+    task::spawn_blocking(move || {
+        let mut out = Encoder::new(encoding, ChunkWriter::new(sender));
+
+        let written = write(&mut out)
+            .and_then(|()| out.finish())
+            .and_then(|mut chunks| chunks.flush());
+
+        match written {
+            Err(e) if e.kind() != io::ErrorKind::BrokenPipe => {
+                warn!("Failed to stream {}: {}", description, e)
+            }
+            _ => {}
+        }
+    });
+
+    let reader = ChunkReader {
+        chunks: receiver,
+        chunk: Vec::new(),
+        position: 0,
+    };
+
+    Body::from_reader(BufReader::new(reader), None)
+}
+
+/// Picks the offer a client prefers from an `Accept` style header, by quality
+/// and then by the order of the offers
+fn negotiate<T: Copy>(header: &str, offers: &[(&str, T)]) -> Option<T> {
+    let mut best: Option<(f32, usize)> = None;
+
+    for item in header.split(',') {
+        let mut parameters = item.split(';');
+        let name = parameters.next().unwrap_or_default().trim();
+        let quality = parameters
+            .filter_map(|parameter| parameter.split_once('='))
+            .find(|(name, _)| name.trim() == "q")
+            .map_or(Some(1.0), |(_, quality)| quality.trim().parse::<f32>().ok());
+
+        let quality = match quality {
+            Some(quality) if quality > 0.0 => quality,
+            _ => continue,
+        };
+
+        for (index, &(offer, _)) in offers.iter().enumerate() {
+            let matches = name == "*"
+                || name == "*/*"
+                || name.eq_ignore_ascii_case(offer)
+                || (name.ends_with("/*") && offer.starts_with(&name[..name.len() - 1]));
+
+            let better = best.map_or(true, |(best_quality, best_index)| {
+                quality > best_quality || (quality == best_quality && index < best_index)
+            });
+
+            if matches && better {
+                best = Some((quality, index));
+            }
+        }
+    }
+
+    best.map(|(_, index)| offers[index].1)
+}
+
+/// Encodes the datums of a telemetry point within a range
+fn write_history(
+    out: &mut impl Write,
+    data: &Snapshot,
+    range: Range<u64>,
+    id: &str,
+    key: &str,
+    format: HistoryFormat,
+    layout: HistoryLayout,
+) -> io::Result<()> {
+    match layout {
+        HistoryLayout::Rows => write_array(
+            out,
+            format,
+            data.range(range).map(|packet| HistoryDatum {
+                id,
+                value: packet.values.get(key).copied(),
+                running_us: packet.running_us,
+                raw: packet.raw.get(key).copied(),
+            }),
+        ),
+        HistoryLayout::Columns => {
+            // Each column is read in its own pass over the range, so that none
+            // of them have to be held on to
+            let mut has_raw = false;
+
+            start_map(out, format)?;
+            write_key(out, format, "id", true)?;
+            write_value(out, format, &id)?;
 
-    let data = timescale_data.range(start..end).map(|(running_us, packet)| {
-        let unfiltered_datum = serde_json::to_value(packet).expect("Failed to serialize packet as serde_json::Value, this should not be able to happen");
+            write_key(out, format, "running_us", false)?;
+            write_array(
+                out,
+                format,
+                data.range(range.clone()).map(|packet| {
+                    has_raw |= packet.raw.contains_key(key);
 
-        json!({
-            "id": key,
-            "value": unfiltered_datum[key],
-            "running_us": running_us,
+                    packet.running_us
+                }),
+            )?;
+
+            write_key(out, format, "value", false)?;
+            write_array(
+                out,
+                format,
+                data.range(range.clone())
+                    .map(|packet| packet.values.get(key).copied()),
+            )?;
+
+            if has_raw {
+                write_key(out, format, "raw", false)?;
+                write_array(
+                    out,
+                    format,
+                    data.range(range).map(|packet| packet.raw.get(key).copied()),
+                )?;
+            }
+
+            end_map(out, format)
+        }
+    }
+}
+
+fn write_value(
+    out: &mut impl Write,
+    format: HistoryFormat,
+    value: &impl Serialize,
+) -> io::Result<()> {
+    match format {
+        HistoryFormat::Json => Ok(serde_json::to_writer(out, value)?),
+        HistoryFormat::Cbor => {
+            serde_cbor::to_writer(out, value).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        }
+    }
+}
+
+/// Writes an array one element at a time, as a CBOR array of indefinite length
+fn write_array<T: Serialize>(
+    out: &mut impl Write,
+    format: HistoryFormat,
+    elements: impl Iterator<Item = T>,
+) -> io::Result<()> {
+    out.write_all(match format {
+        HistoryFormat::Json => b"[",
+        HistoryFormat::Cbor => &[CBOR_ARRAY],
+    })?;
+
+    for (index, element) in elements.enumerate() {
+        if format == HistoryFormat::Json && index > 0 {
+            out.write_all(b",")?;
+        }
+
+        write_value(out, format, &element)?;
+    }
+
+    out.write_all(match format {
+        HistoryFormat::Json => b"]",
+        HistoryFormat::Cbor => &[CBOR_BREAK],
+    })
+}
+
+fn start_map(out: &mut impl Write, format: HistoryFormat) -> io::Result<()> {
+    out.write_all(match format {
+        HistoryFormat::Json => b"{",
+        HistoryFormat::Cbor => &[CBOR_MAP],
+    })
+}
+
+fn write_key(
+    out: &mut impl Write,
+    format: HistoryFormat,
+    key: &str,
+    first: bool,
+) -> io::Result<()> {
+    if format == HistoryFormat::Json && !first {
+        out.write_all(b",")?;
+    }
+
+    write_value(out, format, &key)?;
+
+    match format {
+        HistoryFormat::Json => out.write_all(b":"),
+        HistoryFormat::Cbor => Ok(()),
+    }
+}
+
+fn end_map(out: &mut impl Write, format: HistoryFormat) -> io::Result<()> {
+    out.write_all(match format {
+        HistoryFormat::Json => b"}",
+        HistoryFormat::Cbor => &[CBOR_BREAK],
+    })
+}
+
+/// Compresses a response with the negotiated encoding
+enum Encoder {
+    Identity(ChunkWriter),
+    Gzip(GzEncoder<ChunkWriter>),
+    Deflate(ZlibEncoder<ChunkWriter>),
+}
+
+impl Encoder {
+    fn new(encoding: ContentEncoding, chunks: ChunkWriter) -> Self {
+        match encoding {
+            ContentEncoding::Identity => Encoder::Identity(chunks),
+            ContentEncoding::Gzip => Encoder::Gzip(GzEncoder::new(chunks, Compression::default())),
+            ContentEncoding::Deflate => {
+                Encoder::Deflate(ZlibEncoder::new(chunks, Compression::default()))
+            }
+        }
+    }
+
+    /// Writes the end of the compressed stream
+    fn finish(self) -> io::Result<ChunkWriter> {
+        match self {
+            Encoder::Identity(chunks) => Ok(chunks),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Deflate(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Identity(chunks) => chunks.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Deflate(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Identity(chunks) => chunks.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Deflate(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Sends what is written to it to the [`ChunkReader`] of the response a chunk
+/// at a time, blocking while the client is behind
+struct ChunkWriter {
+    chunks: Sender<Vec<u8>>,
+    chunk: Vec<u8>,
+}
+
+impl ChunkWriter {
+    fn new(chunks: Sender<Vec<u8>>) -> Self {
+        ChunkWriter {
+            chunks,
+            chunk: Vec::with_capacity(HISTORY_CHUNK_SIZE),
+        }
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.chunk.extend_from_slice(buf);
+
+        if self.chunk.len() >= HISTORY_CHUNK_SIZE {
+            self.flush()?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+
+        let chunk = mem::replace(&mut self.chunk, Vec::with_capacity(HISTORY_CHUNK_SIZE));
+
+        task::block_on(self.chunks.send(chunk)).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the client stopped reading the response",
+            )
         })
-    }).collect::<Vec<_>>();
+    }
+}
+
+/// Reads the chunks of a response as they are encoded
+struct ChunkReader {
+    chunks: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    /// how much of the chunk has been read
+    position: usize,
+}
+
+impl Read for ChunkReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+
+        while this.position == this.chunk.len() {
+            match Pin::new(&mut this.chunks).poll_next(cx) {
+                Poll::Ready(Some(chunk)) => {
+                    this.chunk = chunk;
+                    this.position = 0;
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let remaining = &this.chunk[this.position..];
+        let read = remaining.len().min(buf.len());
+
+        buf[..read].copy_from_slice(&remaining[..read]);
+        this.position += read;
 
-    Body::from_json(&data)
+        Poll::Ready(Ok(read))
+    }
 }
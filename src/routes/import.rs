@@ -0,0 +1,130 @@
+use async_std::{io::ReadExt, task};
+use serde::{Deserialize, Serialize};
+use tide::{Body, Request, StatusCode};
+
+use crate::{
+    config::config,
+    import::{import, ImportError, ImportedData, IMPORT_MAPPINGS},
+    schema::SchemaReport,
+    session::{SessionId, SessionMetadata, SESSION_CATALOG},
+    State,
+};
+
+#[derive(Debug, Deserialize)]
+struct ImportQuery {
+    /// the name of the mapping in the mappings file
+    mapping: String,
+    /// a name for the imported session in the catalog
+    name: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ImportSummary<'s> {
+    /// the id of the imported session in the catalog
+    id: SessionId,
+    session: &'s SessionMetadata,
+    packets: usize,
+    /// the `running_us` of the first and last imported packets
+    start: u64,
+    end: u64,
+    report: &'s SchemaReport,
+}
+
+/// The names of the mappings files can be imported with
+pub async fn list_mappings(_: Request<State>) -> tide::Result<Body> {
+    Body::from_json(&IMPORT_MAPPINGS.keys().collect::<Vec<_>>())
+}
+
+/// Imports the file in the request body as a new session, and serves it from
+/// `/history`
+pub async fn import_file(mut req: Request<State>) -> tide::Result<Body> {
+    let query: ImportQuery = req.query()?;
+
+    let mapping = IMPORT_MAPPINGS.get(&query.mapping).ok_or_else(|| {
+        tide::Error::from_str(
+            StatusCode::NotFound,
+            format!("no import mapping named {}", query.mapping),
+        )
+    })?;
+
+    let limit = config().server.max_import_bytes;
+    let too_large = || {
+        tide::Error::from_str(
+            StatusCode::PayloadTooLarge,
+            format!("data files are limited to {} bytes", limit),
+        )
+    };
+
+    if req.len().map_or(false, |length| length as u64 > limit) {
+        return Err(too_large());
+    }
+
+    // The length is not always known up front, so the body is cut off past it
+    let mut data = Vec::new();
+    req.take_body()
+        .take(limit + 1)
+        .read_to_end(&mut data)
+        .await?;
+
+    if data.len() as u64 > limit {
+        return Err(too_large());
+    }
+
+    let ImportedData {
+        packets,
+        firmware,
+        dictionary,
+        report,
+    } = task::spawn_blocking(move || import(mapping, &data))
+        .await
+        .map_err(|e| match e {
+            ImportError::Unreadable(_) => tide::Error::new(StatusCode::BadRequest, e),
+            ImportError::Invalid { .. } => tide::Error::new(StatusCode::UnprocessableEntity, e),
+        })?;
+
+    let mut session = SessionMetadata::new(format!("import:{}", query.mapping), None, None);
+    session.firmware = firmware;
+    session.dictionary = dictionary;
+    session.schema_version = dictionary.schema_version;
+    session.ended = Some(session.started);
+    let metadata = session.clone();
+
+    let count = packets.len();
+    let start = packets.keys().next().copied().unwrap_or(0);
+    let end = packets.keys().next_back().copied().unwrap_or(0);
+
+    let (id, mut writer) = {
+        let mut catalog = SESSION_CATALOG.write().await;
+        let (id, writer) = catalog.add(session);
+
+        if let Some(name) = query.name {
+            let entry = catalog
+                .get_mut(id)
+                .expect("the imported session was just added");
+            entry.info.name = name;
+        }
+
+        (id, writer)
+    };
+
+    // Writing a large file out takes a while, and spills it to disk if the
+    // retention limits say to
+    task::spawn_blocking(move || {
+        for (_, packet) in packets {
+            writer.insert(packet);
+        }
+    })
+    .await;
+
+    // Unless it was deleted while it was being written
+    let _ = SESSION_CATALOG.write().await.select(Some(id));
+
+    Body::from_json(&ImportSummary {
+        id,
+        session: &metadata,
+        packets: count,
+        start,
+        end,
+        report: &report,
+    })
+}
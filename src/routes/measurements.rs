@@ -1,16 +1,17 @@
 use tide::{Body, Request, Result};
 
-use crate::{
-    telemetry::{get_telemetry_composition, get_telemetry_metadata, Identifier},
-    State,
-};
+use crate::{session::active_dictionary, telemetry::Identifier, State};
 
 pub async fn all_measurements(_: Request<State>) -> Result<Body> {
-    Body::from_json(&get_telemetry_composition())
+    Body::from_json(&active_dictionary().await.composition())
 }
 
 pub async fn get_measurement(req: Request<State>) -> Result<Body> {
     let key = req.param("key")?;
 
-    Body::from_json(&get_telemetry_metadata(Identifier::from_key(key)))
+    Body::from_json(
+        &active_dictionary()
+            .await
+            .metadata(Identifier::from_key(key)),
+    )
 }
@@ -0,0 +1,66 @@
+use serde::Serialize;
+use tide::{Body, Request};
+
+use crate::{
+    config::{config, RetentionConfig},
+    queue::{self, QueueStats},
+    relay::{self, RelayUsage},
+    session::{SessionId, SESSION_CATALOG},
+    store::DataUsage,
+    State,
+};
+
+/// The memory used by the telemetry of the server's sessions, and the
+/// telemetry waiting to be sent to clients
+#[derive(Debug, Serialize)]
+struct Metrics {
+    /// an estimate of the memory used by the telemetry of every session
+    memory_bytes: usize,
+    /// the size of every spill file
+    spilled_bytes: u64,
+    retention: &'static RetentionConfig,
+    sessions: Vec<SessionMetrics>,
+    /// the telemetry packets waiting in every client's queue
+    queued_packets: usize,
+    /// the queue of every client of `/devices/connect`
+    queues: Vec<QueueStats>,
+    relay: RelayUsage,
+}
+
+#[derive(Debug, Serialize)]
+struct SessionMetrics {
+    id: SessionId,
+    /// true if the session is still being ingested into
+    live: bool,
+    #[serde(flatten)]
+    usage: DataUsage,
+}
+
+pub async fn get_metrics(_: Request<State>) -> tide::Result<Body> {
+    let catalog = SESSION_CATALOG.read().await;
+    let live = catalog.live().map(|entry| entry.info.id);
+
+    let mut sessions = Vec::new();
+    for entry in catalog.iter() {
+        sessions.push(SessionMetrics {
+            id: entry.info.id,
+            live: live == Some(entry.info.id),
+            usage: entry.data.snapshot().usage(),
+        });
+    }
+
+    let queues = queue::usage();
+
+    Body::from_json(&Metrics {
+        memory_bytes: sessions.iter().map(|session| session.usage.bytes).sum(),
+        spilled_bytes: sessions
+            .iter()
+            .map(|session| session.usage.spilled_bytes)
+            .sum(),
+        retention: &config().retention,
+        sessions,
+        queued_packets: queues.iter().map(|queue| queue.queued).sum(),
+        queues,
+        relay: relay::usage(),
+    })
+}
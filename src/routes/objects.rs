@@ -0,0 +1,177 @@
+use tide::{Body, Request, Response, Result, StatusCode};
+
+use crate::{
+    compare::common_keys,
+    objects::{
+        device_folder, difference_folder, difference_key, parse_difference_key, parse_session_key,
+        root_folder, session_composition, session_folder, session_key,
+        session_subsystem_composition, session_subsystem_folder, sessions_folder, subsystem_folder,
+        Subsystem, DEVICE_KEY, ROOT_KEY, SESSIONS_KEY,
+    },
+    session::{active_dictionary, SessionId, SESSION_CATALOG},
+    telemetry::Identifier,
+    State,
+};
+
+/// The root of the object tree, which tells the web app the namespace the
+/// server's objects live in
+pub async fn get_root(_req: Request<State>) -> Result<Response> {
+    Ok(Body::from_json(&root_folder())?.into())
+}
+
+pub async fn get_object(req: Request<State>) -> Result<Response> {
+    let key = req.param("key")?;
+    let dictionary = active_dictionary().await;
+
+    let body = match key {
+        ROOT_KEY => Body::from_json(&root_folder())?,
+        DEVICE_KEY => {
+            let name = match SESSION_CATALOG.read().await.served() {
+                Some(entry) => match &entry.info.metadata.firmware {
+                    Some(firmware) => format!("{} {}", firmware.name, firmware.version),
+                    None => entry
+                        .info
+                        .metadata
+                        .product
+                        .map(|product| product.description.to_string())
+                        .unwrap_or_else(|| entry.info.metadata.port.clone()),
+                },
+                None => "Flight Computer".to_string(),
+            };
+
+            Body::from_json(&device_folder(&name, dictionary))?
+        }
+        SESSIONS_KEY => {
+            let sessions = SESSION_CATALOG
+                .read()
+                .await
+                .iter()
+                .map(|entry| session_key(entry.info.id, None))
+                .collect::<Vec<_>>();
+
+            Body::from_json(&sessions_folder(&sessions))?
+        }
+        key => match (parse_session_key(key), parse_difference_key(key)) {
+            (Some((id, inner)), _) => match session_object(key, id, inner).await? {
+                Some(body) => body,
+                None => return Ok(not_found(key)),
+            },
+            (None, Some((a, b, offset, inner))) => {
+                match difference_object(key, a, b, offset, inner).await? {
+                    Some(body) => body,
+                    None => return Ok(not_found(key)),
+                }
+            }
+            (None, None) => match Subsystem::from_folder_key(key) {
+                Some(subsystem) => Body::from_json(&subsystem_folder(subsystem, dictionary))?,
+                None => match dictionary.metadata(Identifier::from_key(key)) {
+                    Some(object) => Body::from_json(object)?,
+                    None => return Ok(not_found(key)),
+                },
+            },
+        },
+    };
+
+    Ok(body.into())
+}
+
+/// An object scoped to a session: its folder, one of its subsystem folders or
+/// one of its telemetry points, described by the session's own dictionary
+async fn session_object(key: &str, id: SessionId, inner: Option<&str>) -> Result<Option<Body>> {
+    let catalog = SESSION_CATALOG.read().await;
+
+    let entry = match catalog.get(id) {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+    let dictionary = entry.info.metadata.dictionary;
+
+    let inner = match inner {
+        Some(inner) => inner,
+        None => {
+            let composition = session_composition(id, dictionary);
+
+            return Ok(Some(Body::from_json(&session_folder(
+                key,
+                &entry.info.name,
+                &composition,
+            ))?));
+        }
+    };
+
+    let body = match Subsystem::from_folder_key(inner) {
+        Some(subsystem) => {
+            let location = Identifier::from_key(&session_key(id, None)).to_string();
+            let composition = session_subsystem_composition(id, subsystem, dictionary);
+
+            Body::from_json(&session_subsystem_folder(
+                key,
+                subsystem,
+                &location,
+                &composition,
+            ))?
+        }
+        None => match dictionary.metadata(Identifier::from_key(inner)) {
+            Some(object) => {
+                let folder = session_key(id, Some(Subsystem::of_key(inner).key()));
+                let location = Identifier::from_key(&folder).to_string();
+
+                Body::from_json(&object.scoped(key, &location))?
+            }
+            None => return Ok(None),
+        },
+    };
+
+    Ok(Some(body))
+}
+
+/// The difference between two sessions of a telemetry point they have in
+/// common, or the folder holding every such difference, whose history is served
+/// by `/compare/difference`
+async fn difference_object(
+    key: &str,
+    a: SessionId,
+    b: SessionId,
+    offset: i64,
+    inner: Option<&str>,
+) -> Result<Option<Body>> {
+    let catalog = SESSION_CATALOG.read().await;
+
+    let (a, b) = match (catalog.get(a), catalog.get(b)) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return Ok(None),
+    };
+    let dictionary = a.info.metadata.dictionary;
+    let keys = common_keys(dictionary, b.info.metadata.dictionary);
+
+    let body = match inner {
+        Some(inner) if keys.iter().any(|key| key == inner) => {
+            let object = match dictionary.metadata(Identifier::from_key(inner)) {
+                Some(object) => object,
+                None => return Ok(None),
+            };
+            let folder = difference_key(a.info.id, b.info.id, offset, None);
+            let location = Identifier::from_key(&folder).to_string();
+
+            Body::from_json(&object.difference(key, &location))?
+        }
+        Some(_) => return Ok(None),
+        None => {
+            let name = format!("{} - {}", a.info.name, b.info.name);
+            let composition = keys
+                .iter()
+                .map(|inner| difference_key(a.info.id, b.info.id, offset, Some(inner)))
+                .collect::<Vec<_>>();
+
+            Body::from_json(&difference_folder(key, &name, &composition))?
+        }
+    };
+
+    Ok(Some(body))
+}
+
+fn not_found(key: &str) -> Response {
+    Response::builder(StatusCode::NotFound)
+        .body(format!("404: Object {} does not exist", key))
+        .build()
+}
@@ -0,0 +1,71 @@
+use serde::Deserialize;
+use serde_json::Value;
+use tide::{Body, Request, Response, Result, StatusCode};
+
+use crate::{
+    persistence::{PersistenceError, OBJECT_STORE},
+    State,
+};
+
+#[derive(Debug, Deserialize)]
+struct DeleteQuery {
+    rev: Option<u64>,
+}
+
+pub async fn get_object(req: Request<State>) -> Result<Response> {
+    let id = req.param("id")?;
+
+    respond(OBJECT_STORE.get(id).await, StatusCode::Ok)
+}
+
+pub async fn create_object(mut req: Request<State>) -> Result<Response> {
+    let object: Value = req.body_json().await?;
+    let id = req.param("id")?;
+
+    respond(OBJECT_STORE.create(id, object).await, StatusCode::Created)
+}
+
+pub async fn update_object(mut req: Request<State>) -> Result<Response> {
+    let object: Value = req.body_json().await?;
+    let id = req.param("id")?;
+
+    respond(OBJECT_STORE.update(id, object).await, StatusCode::Ok)
+}
+
+pub async fn delete_object(req: Request<State>) -> Result<Response> {
+    let DeleteQuery { rev } = req.query()?;
+    let id = req.param("id")?;
+
+    respond(
+        OBJECT_STORE.delete(id, rev).await.map(|()| Value::Null),
+        StatusCode::NoContent,
+    )
+}
+
+fn respond(
+    result: std::result::Result<Value, PersistenceError>,
+    success: StatusCode,
+) -> Result<Response> {
+    let (status, body) = match result {
+        Ok(Value::Null) => return Ok(Response::new(success)),
+        Ok(object) => (success, object),
+        // The client needs the current object to resolve the conflict
+        Err(PersistenceError::Conflict(current)) => (StatusCode::Conflict, current),
+        Err(e @ PersistenceError::NotFound) => {
+            return Err(tide::Error::new(StatusCode::NotFound, e))
+        }
+        Err(e @ PersistenceError::AlreadyExists) => {
+            return Err(tide::Error::new(StatusCode::Conflict, e))
+        }
+        Err(e @ PersistenceError::Invalid(_)) => {
+            return Err(tide::Error::new(StatusCode::BadRequest, e))
+        }
+        Err(e @ PersistenceError::Io(_)) => {
+            return Err(tide::Error::new(StatusCode::InternalServerError, e))
+        }
+    };
+
+    Ok(Response::builder(status)
+        .body(Body::from_json(&body)?)
+        .build())
+}
@@ -0,0 +1,7 @@
+use tide::{Body, Request, Result};
+
+use crate::{schema::SCHEMA_REPORT, State};
+
+pub async fn get_report(_: Request<State>) -> Result<Body> {
+    Body::from_json(&*SCHEMA_REPORT.read().await)
+}
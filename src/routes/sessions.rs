@@ -0,0 +1,135 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+use tide::{Body, Request, Response, StatusCode};
+
+use crate::{
+    session::{CatalogError, SessionId, SESSION_CATALOG},
+    State,
+};
+
+#[derive(Debug, Deserialize)]
+struct ListQuery {
+    /// only list sessions with this tag
+    tag: Option<String>,
+}
+
+/// Changes to a session, fields that are not given are left as they are
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SessionUpdate {
+    name: Option<String>,
+    tags: Option<BTreeSet<String>>,
+    notes: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Selection {
+    /// the session `/history` serves, or `null` to serve the latest session
+    id: Option<SessionId>,
+}
+
+pub async fn list_sessions(req: Request<State>) -> tide::Result<Body> {
+    let ListQuery { tag } = req.query()?;
+    let catalog = SESSION_CATALOG.read().await;
+
+    let mut sessions = Vec::new();
+    for entry in catalog.iter() {
+        if tag
+            .as_ref()
+            .map_or(true, |tag| entry.info.tags.contains(tag))
+        {
+            sessions.push(catalog.summary(entry));
+        }
+    }
+
+    Body::from_json(&sessions)
+}
+
+pub async fn get_session(req: Request<State>) -> tide::Result<Body> {
+    let id = session_id(&req)?;
+    let catalog = SESSION_CATALOG.read().await;
+
+    let entry = catalog
+        .get(id)
+        .ok_or(CatalogError::NotFound(id))
+        .map_err(not_found)?;
+
+    Body::from_json(&catalog.summary(entry))
+}
+
+/// Renames, tags or annotates a session
+pub async fn update_session(mut req: Request<State>) -> tide::Result<Body> {
+    let id = session_id(&req)?;
+    let SessionUpdate { name, tags, notes } = req.body_json().await?;
+
+    if name.as_deref().map_or(false, |name| name.trim().is_empty()) {
+        return Err(tide::Error::from_str(
+            StatusCode::BadRequest,
+            "session names must not be empty",
+        ));
+    }
+
+    let mut catalog = SESSION_CATALOG.write().await;
+
+    let entry = catalog
+        .get_mut(id)
+        .ok_or(CatalogError::NotFound(id))
+        .map_err(not_found)?;
+
+    if let Some(name) = name {
+        entry.info.name = name;
+    }
+    if let Some(tags) = tags {
+        entry.info.tags = tags;
+    }
+    if let Some(notes) = notes {
+        entry.info.notes = notes;
+    }
+
+    let entry = catalog
+        .get(id)
+        .ok_or(CatalogError::NotFound(id))
+        .map_err(not_found)?;
+
+    Body::from_json(&catalog.summary(entry))
+}
+
+pub async fn delete_session(req: Request<State>) -> tide::Result<Response> {
+    let id = session_id(&req)?;
+
+    match SESSION_CATALOG.write().await.remove(id) {
+        Ok(()) => Ok(Response::new(StatusCode::NoContent)),
+        Err(e @ CatalogError::NotFound(_)) => Err(not_found(e)),
+        Err(e @ CatalogError::Live(_)) => Err(tide::Error::new(StatusCode::Conflict, e)),
+    }
+}
+
+pub async fn get_selected(_: Request<State>) -> tide::Result<Body> {
+    Body::from_json(&Selection {
+        id: SESSION_CATALOG.read().await.selected(),
+    })
+}
+
+/// Chooses the session `/history` serves
+pub async fn select_session(mut req: Request<State>) -> tide::Result<Body> {
+    let selection: Selection = req.body_json().await?;
+
+    SESSION_CATALOG
+        .write()
+        .await
+        .select(selection.id)
+        .map_err(not_found)?;
+
+    Body::from_json(&selection)
+}
+
+fn session_id(req: &Request<State>) -> tide::Result<SessionId> {
+    req.param("id")?
+        .parse()
+        .map_err(|_| tide::Error::from_str(StatusCode::BadRequest, "session ids are integers"))
+}
+
+fn not_found(e: CatalogError) -> tide::Error {
+    tide::Error::new(StatusCode::NotFound, e)
+}
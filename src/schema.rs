@@ -0,0 +1,191 @@
+use std::{collections::BTreeMap, convert::TryFrom};
+
+use async_std::sync::RwLock;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use serde_cbor::Value;
+use ts_rs::{export, TS};
+
+use crate::telemetry::{
+    cbor_type_name, enumeration_value, FieldType, TelemetryDictionary, TELEMETRY_TIME_FIELD,
+};
+
+lazy_static! {
+    /// Compatibility report for the packets received in the current ingest session
+    pub static ref SCHEMA_REPORT: RwLock<SchemaReport> = RwLock::new(SchemaReport::default());
+}
+
+/// Tracks how the packets received from a device differ from the telemetry
+/// dictionary the server is advertising.
+#[derive(Debug, Clone, Serialize, TS)]
+pub struct SchemaReport {
+    /// true if every packet checked so far matched the dictionary exactly
+    pub compatible: bool,
+    /// the number of packets checked against the dictionary
+    pub packets: u64,
+    /// the number of packets that were not CBOR maps at all, or held integers
+    /// too large to decode
+    pub malformed: u64,
+    /// dictionary keys that were absent, with the number of packets they were missing from
+    pub missing: BTreeMap<String, u64>,
+    /// packet fields that the dictionary does not know about, with the number of packets
+    /// they were seen in
+    pub extra: BTreeMap<String, u64>,
+    /// dictionary keys whose field was sent with an unexpected type
+    pub mismatched: BTreeMap<String, TypeMismatch>,
+    /// enumerated dictionary keys that were sent a value outside of their
+    /// enumeration, with the number of packets they were invalid in
+    pub invalid: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Clone, Serialize, TS)]
+pub struct TypeMismatch {
+    /// the type the dictionary expects
+    pub expected: FieldType,
+    /// the type that was last received
+    pub found: String,
+    /// the number of packets the field had the wrong type in
+    pub count: u64,
+}
+
+export! {
+    (declare) SchemaReport, TypeMismatch => "./web/types/generated/schema.d.ts"
+}
+
+impl Default for SchemaReport {
+    fn default() -> Self {
+        SchemaReport {
+            compatible: true,
+            packets: 0,
+            malformed: 0,
+            missing: BTreeMap::new(),
+            extra: BTreeMap::new(),
+            mismatched: BTreeMap::new(),
+            invalid: BTreeMap::new(),
+        }
+    }
+}
+
+impl SchemaReport {
+    /// Checks a raw packet against a telemetry dictionary, recording any
+    /// differences. Returns true if this was the first packet of the session or
+    /// if it revealed a kind of mismatch that had not been seen before.
+    pub fn check(&mut self, dictionary: &TelemetryDictionary, packet: &Value) -> bool {
+        self.packets += 1;
+        let first = self.packets == 1;
+
+        let fields = match packet {
+            Value::Map(fields) => fields,
+            _ => {
+                self.malformed += 1;
+
+                return self.update_compatible() || first;
+            }
+        };
+
+        let mut new_issue = false;
+        let mut out_of_range = false;
+
+        let expected = dictionary
+            .values
+            .iter()
+            .filter_map(|object| {
+                Some((
+                    object.identifier().key,
+                    *object.field()?,
+                    object.enumerations(),
+                ))
+            })
+            .chain(std::iter::once((
+                TELEMETRY_TIME_FIELD.name,
+                TELEMETRY_TIME_FIELD,
+                None,
+            )));
+
+        for (key, field, enumerations) in expected {
+            match fields.get(&Value::Text(field.name.to_string())) {
+                None => {
+                    new_issue |= increment(&mut self.missing, key);
+                }
+                Some(value) if !field.ty.accepts(value) => {
+                    let mismatch =
+                        self.mismatched
+                            .entry(key.to_string())
+                            .or_insert_with(|| TypeMismatch {
+                                expected: field.ty,
+                                found: String::new(),
+                                count: 0,
+                            });
+
+                    new_issue |= mismatch.count == 0 || mismatch.found != cbor_type_name(value);
+
+                    mismatch.found = cbor_type_name(value).to_string();
+                    mismatch.count += 1;
+                }
+                Some(Value::Integer(value))
+                    if field.ty == FieldType::Integer && i64::try_from(*value).is_err() =>
+                {
+                    out_of_range = true;
+                }
+                Some(value) => {
+                    let valid = match (enumerations, enumeration_value(value)) {
+                        (Some(enumerations), Some(value)) => enumerations
+                            .iter()
+                            .any(|enumeration| enumeration.value() == value),
+                        _ => true,
+                    };
+
+                    if !valid {
+                        new_issue |= increment(&mut self.invalid, key);
+                    }
+                }
+            }
+        }
+
+        if out_of_range {
+            self.malformed += 1;
+        }
+
+        for name in fields.keys() {
+            let name = match name {
+                Value::Text(name) => name.clone(),
+                other => format!("<{}>", cbor_type_name(other)),
+            };
+
+            let known = name == TELEMETRY_TIME_FIELD.name
+                || dictionary
+                    .values
+                    .iter()
+                    .filter_map(|object| object.field())
+                    .any(|field| field.name == name);
+
+            if !known {
+                new_issue |= increment(&mut self.extra, &name);
+            }
+        }
+
+        self.update_compatible() || new_issue || first
+    }
+
+    /// Recomputes the compatibility flag, returning true if it changed
+    fn update_compatible(&mut self) -> bool {
+        let compatible = self.malformed == 0
+            && self.missing.is_empty()
+            && self.extra.is_empty()
+            && self.mismatched.is_empty()
+            && self.invalid.is_empty();
+
+        let changed = compatible != self.compatible;
+        self.compatible = compatible;
+
+        changed
+    }
+}
+
+/// Increments the counter for a key, returning true if it is new
+fn increment(counts: &mut BTreeMap<String, u64>, key: &str) -> bool {
+    let count = counts.entry(key.to_string()).or_insert(0);
+    *count += 1;
+
+    *count == 1
+}
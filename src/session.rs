@@ -0,0 +1,315 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_std::sync::RwLock;
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::{
+    config::config,
+    firmware::FirmwareInfo,
+    serial::PicoProduct,
+    store::{DataUsage, SessionData, SessionWriter, Snapshot},
+    telemetry::TelemetryDictionary,
+};
+
+lazy_static! {
+    /// Every session ingested or imported since the server started
+    pub static ref SESSION_CATALOG: RwLock<SessionCatalog> = RwLock::new(SessionCatalog::default());
+}
+
+pub type SessionId = u64;
+
+/// Describes a single connection to a device
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionMetadata {
+    /// the name of the port the device is connected to
+    pub port: String,
+    pub product: Option<PicoProduct>,
+    /// the USB serial number of the device, if it reported one
+    pub serial_number: Option<String>,
+    /// the time, in milliseconds since the UNIX epoch, at which the device was connected
+    pub started: u64,
+    /// the time, in milliseconds since the UNIX epoch, at which the device was
+    /// disconnected, if it has been
+    pub ended: Option<u64>,
+    /// the firmware the device identified itself as running
+    pub firmware: Option<FirmwareInfo>,
+    /// the schema version of the telemetry dictionary used to decode packets
+    pub schema_version: u32,
+    #[serde(skip)]
+    pub dictionary: &'static TelemetryDictionary,
+}
+
+impl SessionMetadata {
+    pub fn new(port: String, product: Option<PicoProduct>, serial_number: Option<String>) -> Self {
+        let dictionary = TelemetryDictionary::unidentified();
+
+        SessionMetadata {
+            port,
+            product,
+            serial_number,
+            started: unix_millis(),
+            ended: None,
+            firmware: None,
+            schema_version: dictionary.schema_version,
+            dictionary,
+        }
+    }
+
+    /// Records the firmware a device identified as and switches to the telemetry
+    /// dictionary for its schema, falling back to the latest known dictionary
+    pub fn identify(&mut self, firmware: FirmwareInfo) -> &'static TelemetryDictionary {
+        self.dictionary = TelemetryDictionary::for_schema(firmware.schema_version)
+            .unwrap_or_else(TelemetryDictionary::latest);
+        self.schema_version = self.dictionary.schema_version;
+        self.firmware = Some(firmware);
+
+        self.dictionary
+    }
+}
+
+/// The sessions known to the server, along with their telemetry
+#[derive(Default)]
+pub struct SessionCatalog {
+    sessions: BTreeMap<SessionId, CatalogEntry>,
+    next_id: SessionId,
+    /// the session being ingested into, if any
+    live: Option<SessionId>,
+    /// the session `/history` serves, if one was chosen over the latest
+    selected: Option<SessionId>,
+}
+
+pub struct CatalogEntry {
+    pub info: SessionInfo,
+    pub data: Arc<SessionData>,
+}
+
+/// A session as listed in the catalog
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: SessionId,
+    /// a name for the session, the port name unless it was renamed
+    pub name: String,
+    pub tags: BTreeSet<String>,
+    /// free form notes about the session
+    pub notes: String,
+    #[serde(flatten)]
+    pub metadata: SessionMetadata,
+    /// packets that could not be parsed as CBOR
+    pub malformed: u64,
+    /// packets that were parsed, but could not be decoded
+    pub dropped: u64,
+}
+
+/// A session along with the bounds of its telemetry
+#[derive(Debug, Serialize)]
+pub struct SessionSummary<'s> {
+    #[serde(flatten)]
+    pub info: &'s SessionInfo,
+    /// the `running_us` of the first and last packets of the session
+    pub first_us: Option<u64>,
+    pub last_us: Option<u64>,
+    pub packets: usize,
+    /// how much of the session is kept in memory and on disk
+    pub usage: DataUsage,
+    /// true if the session is still being ingested into
+    pub live: bool,
+    /// true if `/history` serves this session
+    pub served: bool,
+}
+
+#[derive(Debug)]
+pub enum CatalogError {
+    NotFound(SessionId),
+    /// the session is still being ingested into
+    Live(SessionId),
+}
+
+impl fmt::Display for CatalogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CatalogError::NotFound(id) => write!(f, "session {} does not exist", id),
+            CatalogError::Live(id) => write!(f, "session {} is still being ingested", id),
+        }
+    }
+}
+
+impl std::error::Error for CatalogError {}
+
+impl SessionCatalog {
+    fn next_id(&mut self) -> SessionId {
+        self.next_id += 1;
+
+        self.next_id
+    }
+
+    fn insert(&mut self, id: SessionId, metadata: SessionMetadata, data: Arc<SessionData>) {
+        self.sessions.insert(
+            id,
+            CatalogEntry {
+                info: SessionInfo {
+                    id,
+                    name: metadata.port.clone(),
+                    tags: BTreeSet::new(),
+                    notes: String::new(),
+                    metadata,
+                    malformed: 0,
+                    dropped: 0,
+                },
+                data,
+            },
+        );
+    }
+
+    /// Starts a session for a device that is being ingested from
+    pub fn begin(&mut self, metadata: SessionMetadata) -> (SessionId, SessionWriter) {
+        let (id, writer) = self.add(metadata);
+        self.live = Some(id);
+
+        (id, writer)
+    }
+
+    /// Adds a finished session, such as an imported one, whose packets are
+    /// written through the returned writer
+    pub fn add(&mut self, metadata: SessionMetadata) -> (SessionId, SessionWriter) {
+        let id = self.next_id();
+
+        // The start time keeps the spill files of separate runs of the server apart
+        let spill = config()
+            .paths
+            .spill
+            .join(format!("session-{}-{}.cbor", metadata.started, id));
+
+        let data = Arc::new(SessionData::default());
+        self.insert(id, metadata, data.clone());
+
+        (id, SessionWriter::retained(data, spill))
+    }
+
+    /// Marks a session as ended, if it has not ended already
+    pub fn end(&mut self, id: SessionId, ended: u64) {
+        if let Some(entry) = self.sessions.get_mut(&id) {
+            entry.info.metadata.ended.get_or_insert(ended);
+        }
+
+        if self.live == Some(id) {
+            self.live = None;
+        }
+    }
+
+    pub fn get(&self, id: SessionId) -> Option<&CatalogEntry> {
+        self.sessions.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: SessionId) -> Option<&mut CatalogEntry> {
+        self.sessions.get_mut(&id)
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &CatalogEntry> {
+        self.sessions.values()
+    }
+
+    pub fn remove(&mut self, id: SessionId) -> Result<(), CatalogError> {
+        if self.live == Some(id) {
+            return Err(CatalogError::Live(id));
+        }
+
+        self.sessions
+            .remove(&id)
+            .ok_or(CatalogError::NotFound(id))?;
+
+        if self.selected == Some(id) {
+            self.selected = None;
+        }
+
+        Ok(())
+    }
+
+    /// Chooses the session `/history` serves, or goes back to serving the
+    /// latest session if `None`
+    pub fn select(&mut self, id: Option<SessionId>) -> Result<(), CatalogError> {
+        if let Some(id) = id {
+            if !self.sessions.contains_key(&id) {
+                return Err(CatalogError::NotFound(id));
+            }
+        }
+
+        self.selected = id;
+
+        Ok(())
+    }
+
+    pub fn selected(&self) -> Option<SessionId> {
+        self.selected
+    }
+
+    /// The session being ingested into, if any
+    pub fn live(&self) -> Option<&CatalogEntry> {
+        self.live.and_then(|id| self.sessions.get(&id))
+    }
+
+    /// The session `/history` serves: the selected one, or else the live one,
+    /// or else the latest one
+    pub fn served(&self) -> Option<&CatalogEntry> {
+        self.selected
+            .or(self.live)
+            .and_then(|id| self.sessions.get(&id))
+            .or_else(|| self.sessions.values().next_back())
+    }
+
+    pub fn summary<'s>(&self, entry: &'s CatalogEntry) -> SessionSummary<'s> {
+        let data = entry.data.snapshot();
+        let id = entry.info.id;
+
+        SessionSummary {
+            info: &entry.info,
+            first_us: data.first_us(),
+            last_us: data.last_us(),
+            packets: data.len(),
+            usage: data.usage(),
+            live: self.live == Some(id),
+            served: self.served().map(|served| served.info.id) == Some(id),
+        }
+    }
+}
+
+/// The telemetry of the session `/history` serves
+pub async fn served_data() -> Option<Arc<Snapshot>> {
+    SESSION_CATALOG
+        .read()
+        .await
+        .served()
+        .map(|entry| entry.data.snapshot())
+}
+
+/// The telemetry of a single session
+pub async fn session_data(id: SessionId) -> Option<Arc<Snapshot>> {
+    SESSION_CATALOG
+        .read()
+        .await
+        .get(id)
+        .map(|entry| entry.data.snapshot())
+}
+
+/// The telemetry dictionary of the session `/history` serves, or the dictionary
+/// a new session starts with if there are no sessions yet
+pub async fn active_dictionary() -> &'static TelemetryDictionary {
+    SESSION_CATALOG
+        .read()
+        .await
+        .served()
+        .map(|entry| entry.info.metadata.dictionary)
+        .unwrap_or_else(TelemetryDictionary::unidentified)
+}
+
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,227 @@
+use std::{
+    f64::consts::PI,
+    io, thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use serde_cbor::Value;
+
+use crate::{
+    config::SimulatorConfig,
+    firmware::FirmwareInfo,
+    serial::PicoProduct,
+    source::{PacketError, Source},
+    telemetry::TelemetryDictionary,
+};
+
+/// The port name the simulator is listed under in `/devices`
+pub const SIMULATOR_PORT: &str = "simulator";
+
+pub static SIMULATOR_PRODUCT: PicoProduct = PicoProduct {
+    company: "pico-mct",
+    description: "Simulated flight computer",
+    link: "https://github.com/DusterTheFirst/pico-mct",
+};
+
+/// The length of the simulated flight profile, after which it starts over
+const FLIGHT_DURATION: f64 = 60.0;
+/// How long the simulated USB cable stays unplugged
+const UNPLUGGED_DURATION: f64 = 5.0;
+
+/// A simulated flight computer, producing the same packets as the real firmware
+/// at a steady rate
+pub struct Simulator {
+    config: SimulatorConfig,
+    rng: XorShift,
+    started: Instant,
+    /// the time since boot the next packet is due at
+    next_packet: Duration,
+    period: Duration,
+    /// set when the server asks the firmware to identify itself
+    identify: bool,
+    /// the time since boot the USB cable is next unplugged at
+    next_unplug: f64,
+}
+
+#[derive(Serialize)]
+struct SimulatedPacket {
+    running_us: u64,
+    tvc_x: f64,
+    tvc_z: f64,
+    angle: f64,
+    temperature: f64,
+    v_sys: f64,
+    v_bat: f64,
+    offset: i64,
+    v_bus_present: bool,
+    flight_mode: u8,
+    pyro_1: u8,
+    pyro_2: u8,
+    error: u8,
+}
+
+#[derive(Serialize)]
+struct Identification {
+    firmware: FirmwareInfo,
+}
+
+impl Simulator {
+    pub fn new(config: &SimulatorConfig) -> Self {
+        let seed = config.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_nanos() as u64)
+                .unwrap_or(0)
+        });
+
+        let mut rng = XorShift::new(seed);
+        let next_unplug = rng.exponential(config.usb_event_interval_s);
+
+        Simulator {
+            config: config.clone(),
+            rng,
+            started: Instant::now(),
+            next_packet: Duration::from_secs(0),
+            period: Duration::from_secs_f64(1.0 / config.rate_hz),
+            // Like the real firmware, identify on boot
+            identify: true,
+            next_unplug,
+        }
+    }
+
+    fn identification(&self) -> Identification {
+        Identification {
+            firmware: FirmwareInfo {
+                name: "pico-pilot-simulator".into(),
+                version: env!("CARGO_PKG_VERSION").into(),
+                git_hash: "simulated".into(),
+                schema_version: TelemetryDictionary::latest().schema_version,
+            },
+        }
+    }
+
+    fn packet(&mut self, time: Duration) -> SimulatedPacket {
+        let t = time.as_secs_f64();
+
+        // The cable is unplugged for a while every so often
+        if self.config.usb_event_interval_s > 0.0 && t >= self.next_unplug + UNPLUGGED_DURATION {
+            self.next_unplug = t + self.rng.exponential(self.config.usb_event_interval_s);
+        }
+        let usb_present = self.config.usb_event_interval_s <= 0.0 || t < self.next_unplug;
+
+        let flight_time = t % FLIGHT_DURATION;
+        let (flight_mode, pyro_1, pyro_2) = match flight_time {
+            t if t < 10.0 => (0, 0, 0),
+            t if t < 15.0 => (1, 1, 1),
+            t if t < 18.0 => (2, 1, 1),
+            t if t < 25.0 => (3, 1, 1),
+            t if t < 30.0 => (4, 3, 1),
+            t if t < 40.0 => (4, 3, 3),
+            _ => (5, 3, 3),
+        };
+
+        // The gimbal sweeps further while the motor is burning
+        let gimbal = 2.0 * PI * 0.5 * t;
+        let amplitude = if flight_mode == 2 { 4.0 } else { 1.5 };
+
+        // Discharges over half an hour
+        let v_bat = 12.6 - 2.1 * (t / 1800.0).min(1.0);
+        // The system bus falls back to the regulator when USB is unplugged
+        let v_sys = if usb_present { 5.0 } else { 3.3 };
+
+        SimulatedPacket {
+            running_us: time.as_micros() as u64,
+            tvc_x: amplitude * gimbal.sin() + self.noise(10.0),
+            tvc_z: amplitude * gimbal.cos() + self.noise(10.0),
+            angle: gimbal % (2.0 * PI),
+            temperature: 25.0
+                + 10.0 * (1.0 - (-t / 300.0).exp())
+                + 0.5 * (2.0 * PI * t / 120.0).sin()
+                + self.noise(30.0),
+            v_sys: v_sys + self.noise(5.5),
+            v_bat: v_bat + self.noise(20.0),
+            offset: (50.0 + self.noise(100.0)).round() as i64,
+            v_bus_present: usb_present,
+            flight_mode,
+            pyro_1,
+            pyro_2,
+            error: 0,
+        }
+    }
+
+    /// Gaussian noise, scaled to the range of the measurement
+    fn noise(&mut self, range: f64) -> f64 {
+        self.rng.gaussian() * self.config.noise * range
+    }
+}
+
+impl Source for Simulator {
+    fn next_packet(&mut self) -> Result<Value, PacketError> {
+        if self.identify {
+            self.identify = false;
+
+            return serde_cbor::value::to_value(self.identification())
+                .map_err(PacketError::Malformed);
+        }
+
+        loop {
+            let elapsed = self.started.elapsed();
+            if elapsed < self.next_packet {
+                thread::sleep(self.next_packet - elapsed);
+            }
+
+            let time = self.next_packet;
+            self.next_packet += self.period;
+
+            let packet = self.packet(time);
+
+            if self.rng.next_f64() >= self.config.dropout {
+                return serde_cbor::value::to_value(packet).map_err(PacketError::Malformed);
+            }
+        }
+    }
+
+    /// The only request the firmware understands is to identify itself
+    fn request(&mut self, _request: &[u8]) -> io::Result<()> {
+        self.identify = true;
+
+        Ok(())
+    }
+}
+
+/// xorshift64*, plenty random enough for noise
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // The state must never be zero
+        XorShift(seed | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+
+        self.0.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    /// Uniformly distributed in `[0, 1)`
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Standard normal distribution, using the Box-Muller transform
+    fn gaussian(&mut self) -> f64 {
+        let u = 1.0 - self.next_f64();
+        let v = self.next_f64();
+
+        (-2.0 * u.ln()).sqrt() * (2.0 * PI * v).cos()
+    }
+
+    /// Exponential distribution with the given mean
+    fn exponential(&mut self, mean: f64) -> f64 {
+        -mean * (1.0 - self.next_f64()).ln()
+    }
+}
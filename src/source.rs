@@ -0,0 +1,793 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream, UdpSocket},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use async_std::task;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_cbor::Value;
+
+use crate::{
+    config::config,
+    recording::RECORDING_FORMAT,
+    relay::{RelaySource, RELAY_PORT},
+    serial::{get_serial_ports, PicoProduct},
+    session::SessionMetadata,
+    simulator::{Simulator, SIMULATOR_PORT, SIMULATOR_PRODUCT},
+};
+
+/// The largest packet accepted in a single datagram
+const MAX_DATAGRAM_SIZE: usize = 65_507;
+/// The largest packet accepted from a byte stream, past which its bytes are
+/// taken to be line noise
+const MAX_PACKET_SIZE: usize = 65_536;
+/// How long a TCP source in listen mode waits for a client to connect
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a TCP source keeps trying to reconnect after losing its connection
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// The longest wait between attempts to reconnect a TCP source
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Something telemetry packets are read from
+pub trait Source: Send {
+    /// Reads the next packet, blocking until one arrives
+    fn next_packet(&mut self) -> Result<Value, PacketError>;
+
+    /// Sends a request to the device, for sources that can talk back to it
+    fn request(&mut self, _request: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The number of bytes received so far, for sources that count them
+    fn bytes_received(&self) -> u64 {
+        0
+    }
+
+    /// The number of times the source reconnected after losing its link
+    fn reconnects(&self) -> u64 {
+        0
+    }
+
+    /// A session the source moved on to since it was last asked, for sources
+    /// that mirror the sessions of another server
+    fn take_session(&mut self) -> Option<SessionMetadata> {
+        None
+    }
+}
+
+/// The kinds of sources that can be connected to through `/devices/connect`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    /// a serial port, or the simulator, given by `port`
+    Serial,
+    /// a file of CBOR packets or a recording, given by `path`
+    File,
+    /// a TCP connection to `address`, or from a client connecting to
+    /// `address` if `listen` is set
+    Tcp,
+    /// UDP datagrams sent to `address`, one packet per datagram
+    Udp,
+    /// packets piped into the server
+    Stdin,
+    /// the relay of another server at `address`
+    Relay,
+}
+
+impl FromStr for SourceKind {
+    type Err = String;
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        match kind {
+            "serial" => Ok(SourceKind::Serial),
+            "file" => Ok(SourceKind::File),
+            "tcp" => Ok(SourceKind::Tcp),
+            "udp" => Ok(SourceKind::Udp),
+            "stdin" => Ok(SourceKind::Stdin),
+            "relay" => Ok(SourceKind::Relay),
+            kind => Err(format!("unknown source {}", kind)),
+        }
+    }
+}
+
+impl Default for SourceKind {
+    fn default() -> Self {
+        SourceKind::Serial
+    }
+}
+
+/// The parameters of a source, as given to `/devices/connect`. Which of them are
+/// required depends on the kind of source.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SourceParameters {
+    #[serde(default)]
+    pub source: SourceKind,
+    pub port: Option<String>,
+    pub path: Option<PathBuf>,
+    pub address: Option<String>,
+    /// wait for a connection instead of connecting, for TCP sources
+    #[serde(default)]
+    pub listen: bool,
+    /// milliseconds without data after which a serial source is considered
+    /// lost, and a network source is reported idle
+    pub timeout: Option<u64>,
+    pub baud: Option<u32>,
+}
+
+/// A source that has been opened, along with what is known about the device
+/// behind it
+pub struct OpenedSource {
+    pub source: Box<dyn Source>,
+    /// a name for the source, the port name for serial ports
+    pub name: String,
+    pub product: Option<PicoProduct>,
+    pub serial_number: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum PacketError {
+    /// the source ended or failed, and will not produce any more packets
+    Closed(String),
+    /// a packet could not be parsed, but later packets may still be read
+    Malformed(serde_cbor::Error),
+    /// nothing arrived within the source's timeout, but the link is still up
+    /// and later packets may still be read
+    Idle,
+}
+
+impl From<serde_cbor::Error> for PacketError {
+    fn from(e: serde_cbor::Error) -> Self {
+        if e.is_io() || e.is_eof() {
+            PacketError::Closed(e.to_string())
+        } else {
+            PacketError::Malformed(e)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SourceError {
+    /// the parameters do not describe a source
+    Invalid(String),
+    /// clients of the server may not connect it to the source
+    Forbidden(String),
+    /// the source could not be opened
+    Unavailable(String, io::Error),
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceError::Invalid(reason) | SourceError::Forbidden(reason) => f.write_str(reason),
+            SourceError::Unavailable(name, e) => write!(f, "failed to open {}: {}", name, e),
+        }
+    }
+}
+
+impl std::error::Error for SourceError {}
+
+impl SourceParameters {
+    fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout.unwrap_or(config().device.timeout_ms))
+    }
+
+    fn required<'a, T: ?Sized>(
+        &self,
+        value: &'a Option<impl AsRef<T>>,
+        name: &str,
+    ) -> Result<&'a T, SourceError> {
+        value.as_ref().map(AsRef::as_ref).ok_or_else(|| {
+            SourceError::Invalid(format!("{:?} sources need a {}", self.source, name))
+        })
+    }
+
+    /// Checks that clients of the server may connect it to this source, see
+    /// [`SourcesConfig`](crate::config::SourcesConfig), resolving the path of
+    /// file sources within the recordings directory
+    pub fn authorize(&mut self) -> Result<(), SourceError> {
+        let sources = &config().sources;
+
+        match self.source {
+            SourceKind::File => {
+                let recordings = sources
+                    .recordings
+                    .as_ref()
+                    .ok_or_else(|| SourceError::Forbidden("file sources are not enabled".into()))?;
+                let path: &Path = self.required(&self.path, "path")?;
+
+                let recordings = recordings
+                    .canonicalize()
+                    .map_err(|e| SourceError::Unavailable(recordings.display().to_string(), e))?;
+                // Whether a path outside of the directory exists is none of
+                // the client's business either
+                let path = recordings.join(path).canonicalize().map_err(|_| {
+                    SourceError::Invalid(format!("no recording {}", path.display()))
+                })?;
+
+                if !path.starts_with(&recordings) {
+                    return Err(SourceError::Forbidden(format!(
+                        "{} is outside of the recordings directory",
+                        path.display()
+                    )));
+                }
+
+                self.path = Some(path);
+            }
+            SourceKind::Stdin if !sources.stdin => {
+                return Err(SourceError::Forbidden("stdin is not enabled".into()));
+            }
+            SourceKind::Tcp if self.listen => self.check_address(&sources.bind, "bind")?,
+            SourceKind::Udp => self.check_address(&sources.bind, "bind")?,
+            SourceKind::Tcp | SourceKind::Relay => {
+                self.check_address(&sources.connect, "connect to")?
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the address of the source is in an allow-list of addresses
+    /// and ports
+    fn check_address(&self, allowed: &[String], action: &str) -> Result<(), SourceError> {
+        let address: &str = self.required(&self.address, "address")?;
+        let port = address.rsplit(':').next();
+
+        if allowed
+            .iter()
+            .any(|entry| entry == address || Some(entry.as_str()) == port)
+        {
+            Ok(())
+        } else {
+            Err(SourceError::Forbidden(format!(
+                "not allowed to {} {}",
+                action, address
+            )))
+        }
+    }
+
+    /// The name the source is opened under, see [`OpenedSource::name`]
+    pub fn name(&self) -> Result<String, SourceError> {
+        let address = || self.required::<str>(&self.address, "address");
+
+        Ok(match self.source {
+            SourceKind::Serial => {
+                let port_name: &str = self.required(&self.port, "port")?;
+
+                match (port_name, &config().relay.upstream) {
+                    (RELAY_PORT, Some(upstream)) => format!("relay://{}", upstream),
+                    _ => port_name.to_string(),
+                }
+            }
+            SourceKind::File => {
+                let path: &Path = self.required(&self.path, "path")?;
+
+                format!("file:{}", path.display())
+            }
+            SourceKind::Tcp if self.listen => format!("tcp://{} (listening)", address()?),
+            SourceKind::Tcp => format!("tcp://{}", address()?),
+            SourceKind::Udp => format!("udp://{}", address()?),
+            SourceKind::Stdin => "stdin".into(),
+            SourceKind::Relay => format!("relay://{}", address()?),
+        })
+    }
+
+    pub async fn open(&self) -> Result<OpenedSource, SourceError> {
+        match self.source {
+            SourceKind::Serial => {
+                let port_name: &str = self.required(&self.port, "port")?;
+
+                if port_name.is_empty() {
+                    return Err(SourceError::Invalid("serial sources need a port".into()));
+                }
+
+                if let (RELAY_PORT, Some(upstream)) = (port_name, &config().relay.upstream) {
+                    return open_relay(upstream.clone()).await;
+                }
+
+                if port_name == SIMULATOR_PORT && config().simulator.enabled {
+                    return Ok(OpenedSource {
+                        source: Box::new(Simulator::new(&config().simulator)),
+                        name: port_name.to_string(),
+                        product: Some(SIMULATOR_PRODUCT),
+                        serial_number: None,
+                    });
+                }
+
+                // Assuming Pico SDK USB CDC so baud rate does not matter
+                let baud = self.baud.unwrap_or(config().device.baud);
+                let port = serialport::new(port_name, baud)
+                    .timeout(self.timeout())
+                    .open()
+                    .map_err(|e| SourceError::Unavailable(port_name.to_string(), e.into()))?;
+                let control = port
+                    .try_clone()
+                    .map_err(|e| SourceError::Unavailable(port_name.to_string(), e.into()))?;
+
+                let usb_port = get_serial_ports()
+                    .await
+                    .ok()
+                    .and_then(|mut ports| ports.find(|port| port.name == port_name));
+
+                Ok(OpenedSource {
+                    source: Box::new(StreamSource::new(port, Some(Box::new(control)))),
+                    name: port_name.to_string(),
+                    product: usb_port.as_ref().and_then(|port| port.product.copied()),
+                    serial_number: usb_port.and_then(|port| port.info.serial_number),
+                })
+            }
+            SourceKind::File => {
+                let path: &Path = self.required(&self.path, "path")?;
+                let name = self.name()?;
+
+                let file =
+                    File::open(path).map_err(|e| SourceError::Unavailable(name.clone(), e))?;
+
+                Ok(OpenedSource {
+                    source: Box::new(StreamSource::new(file, None).skip_recording_header()),
+                    name,
+                    product: None,
+                    serial_number: None,
+                })
+            }
+            SourceKind::Tcp if self.listen => {
+                let address: &str = self.required(&self.address, "address")?;
+                let name = self.name()?;
+                let timeout = self.timeout();
+
+                let listener = TcpListener::bind(address)
+                    .map_err(|e| SourceError::Unavailable(name.clone(), e))?;
+
+                let source = task::spawn_blocking(move || TcpServerSource::new(listener, timeout))
+                    .await
+                    .map_err(|e| SourceError::Unavailable(name.clone(), e))?;
+
+                Ok(OpenedSource {
+                    source: Box::new(source),
+                    name,
+                    product: None,
+                    serial_number: None,
+                })
+            }
+            SourceKind::Tcp => {
+                let address: &str = self.required(&self.address, "address")?;
+                let name = self.name()?;
+
+                let address = address.to_string();
+                let timeout = self.timeout();
+
+                let source = task::spawn_blocking(move || TcpClientSource::new(address, timeout))
+                    .await
+                    .map_err(|e| SourceError::Unavailable(name.clone(), e))?;
+
+                Ok(OpenedSource {
+                    source: Box::new(source),
+                    name,
+                    product: None,
+                    serial_number: None,
+                })
+            }
+            SourceKind::Udp => {
+                let address: &str = self.required(&self.address, "address")?;
+                let name = self.name()?;
+
+                let socket = UdpSocket::bind(address)
+                    .and_then(|socket| {
+                        socket.set_read_timeout(Some(self.timeout()))?;
+
+                        Ok(socket)
+                    })
+                    .map_err(|e| SourceError::Unavailable(name.clone(), e))?;
+
+                Ok(OpenedSource {
+                    source: Box::new(DatagramSource::new(socket)),
+                    name,
+                    product: None,
+                    serial_number: None,
+                })
+            }
+            SourceKind::Stdin => Ok(OpenedSource {
+                source: Box::new(StreamSource::new(io::stdin(), None)),
+                name: self.name()?,
+                product: None,
+                serial_number: None,
+            }),
+            SourceKind::Relay => {
+                let address: &str = self.required(&self.address, "address")?;
+
+                open_relay(address.to_string()).await
+            }
+        }
+    }
+}
+
+async fn open_relay(address: String) -> Result<OpenedSource, SourceError> {
+    let name = format!("relay://{}", address);
+
+    let source = task::spawn_blocking(move || RelaySource::connect(address))
+        .await
+        .map_err(|e| SourceError::Unavailable(name.clone(), e))?;
+
+    // The device is only known once the upstream server sends its session
+    Ok(OpenedSource {
+        source: Box::new(source),
+        name,
+        product: None,
+        serial_number: None,
+    })
+}
+
+/// Reads a CBOR sequence from a byte stream, such as a serial port, a TCP
+/// connection, a file or stdin
+pub struct StreamSource<R: Read> {
+    reader: CountingReader<R>,
+    bytes: Arc<AtomicU64>,
+    /// bytes read that do not make up a whole packet yet, kept across reads
+    /// that time out
+    buffer: Vec<u8>,
+    /// the other direction of the stream, if requests can be sent to the device
+    control: Option<Box<dyn Write + Send>>,
+    /// a packet read ahead of time that has not been handed out yet
+    peeked: Option<Result<Value, PacketError>>,
+    /// whether a read timing out leaves the source open, for links that may go
+    /// quiet for a while
+    idle_on_timeout: bool,
+}
+
+impl<R: Read> StreamSource<R> {
+    pub fn new(reader: R, control: Option<Box<dyn Write + Send>>) -> Self {
+        let bytes = Arc::new(AtomicU64::new(0));
+        let reader = CountingReader {
+            inner: reader,
+            bytes: bytes.clone(),
+        };
+
+        StreamSource {
+            reader,
+            bytes,
+            buffer: Vec::new(),
+            control,
+            peeked: None,
+            idle_on_timeout: false,
+        }
+    }
+
+    /// Reports reads that time out as [`PacketError::Idle`] instead of closing
+    /// the source, for network links where silence does not mean the device is
+    /// gone
+    pub fn idle_on_timeout(mut self) -> Self {
+        self.idle_on_timeout = true;
+
+        self
+    }
+
+    /// Skips the header of the stream if it is a recording, so that recordings
+    /// can be played back like any other capture
+    pub fn skip_recording_header(mut self) -> Self {
+        let first = self.read_packet();
+
+        let is_header = matches!(&first, Ok(Value::Map(fields))
+            if fields.get(&Value::Text("format".into())) == Some(&Value::Text(RECORDING_FORMAT.into())));
+
+        if !is_header {
+            self.peeked = Some(first);
+        }
+
+        self
+    }
+
+    /// Reads until the buffer holds a whole packet, or something other than
+    /// the start of one
+    fn read_packet(&mut self) -> Result<Value, PacketError> {
+        let mut chunk = [0; 4096];
+
+        loop {
+            if !self.buffer.is_empty() {
+                let mut packets = serde_cbor::Deserializer::from_slice(&self.buffer).into_iter();
+
+                match packets.next() {
+                    Some(Ok(packet)) => {
+                        let length = packets.byte_offset();
+                        self.buffer.drain(..length);
+
+                        return Ok(packet);
+                    }
+                    // The rest of the packet has not arrived yet
+                    Some(Err(e)) if e.is_eof() && self.buffer.len() < MAX_PACKET_SIZE => {}
+                    Some(Err(e)) => {
+                        // Skip past the bad bytes to find the next packet
+                        let length = (e.offset() as usize).clamp(1, self.buffer.len());
+                        self.buffer.drain(..length);
+
+                        return Err(PacketError::Malformed(e));
+                    }
+                    None => {}
+                }
+            }
+
+            match self.reader.read(&mut chunk) {
+                Ok(0) => return Err(PacketError::Closed("end of stream".into())),
+                Ok(read) => self.buffer.extend_from_slice(&chunk[..read]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if self.idle_on_timeout && is_timeout(&e) => return Err(PacketError::Idle),
+                Err(e) => return Err(PacketError::Closed(e.to_string())),
+            }
+        }
+    }
+}
+
+impl<R: Read + Send> Source for StreamSource<R> {
+    fn next_packet(&mut self) -> Result<Value, PacketError> {
+        match self.peeked.take() {
+            Some(packet) => packet,
+            None => self.read_packet(),
+        }
+    }
+
+    fn request(&mut self, request: &[u8]) -> io::Result<()> {
+        match &mut self.control {
+            Some(control) => control.write_all(request).and_then(|()| control.flush()),
+            None => Ok(()),
+        }
+    }
+
+    fn bytes_received(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    // Unix reports read timeouts as `WouldBlock`, Windows as `TimedOut`
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Counts the bytes read through it, as the reader itself is owned by the
+/// deserializer
+pub(crate) struct CountingReader<R> {
+    pub(crate) inner: R,
+    pub(crate) bytes: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.bytes.fetch_add(read as u64, Ordering::Relaxed);
+
+        Ok(read)
+    }
+}
+
+/// Accepts a TCP connection from the device, such as a radio bridge, accepting a
+/// new one whenever the link is lost
+pub struct TcpServerSource {
+    listener: TcpListener,
+    timeout: Duration,
+    stream: StreamSource<TcpStream>,
+    /// bytes received over previous connections
+    previous_bytes: u64,
+    reconnects: u64,
+}
+
+impl TcpServerSource {
+    fn new(listener: TcpListener, timeout: Duration) -> io::Result<Self> {
+        let stream = accept(&listener, timeout)?;
+
+        Ok(TcpServerSource {
+            listener,
+            timeout,
+            stream,
+            previous_bytes: 0,
+            reconnects: 0,
+        })
+    }
+}
+
+/// Waits for a client to connect, giving up after [`ACCEPT_TIMEOUT`]
+fn accept(listener: &TcpListener, timeout: Duration) -> io::Result<StreamSource<TcpStream>> {
+    let started = Instant::now();
+
+    listener.set_nonblocking(true)?;
+
+    let stream = loop {
+        match listener.accept() {
+            Ok((stream, address)) => {
+                info!("Accepted telemetry connection from {}", address);
+
+                break stream;
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if started.elapsed() > ACCEPT_TIMEOUT {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "no client connected",
+                    ));
+                }
+
+                thread::sleep(Duration::from_millis(50));
+            }
+            Err(e) => return Err(e),
+        }
+    };
+
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(timeout))?;
+    let control = stream.try_clone()?;
+
+    Ok(StreamSource::new(stream, Some(Box::new(control))).idle_on_timeout())
+}
+
+impl Source for TcpServerSource {
+    fn next_packet(&mut self) -> Result<Value, PacketError> {
+        match self.stream.next_packet() {
+            Err(PacketError::Closed(reason)) => {
+                warn!(
+                    "Lost telemetry connection ({}), waiting for a new one",
+                    reason
+                );
+
+                let stream = accept(&self.listener, self.timeout).map_err(|e| {
+                    PacketError::Closed(format!("{}, and no client reconnected: {}", reason, e))
+                })?;
+
+                self.previous_bytes += self.stream.bytes_received();
+                self.stream = stream;
+                self.reconnects += 1;
+
+                self.next_packet()
+            }
+            result => result,
+        }
+    }
+
+    fn request(&mut self, request: &[u8]) -> io::Result<()> {
+        self.stream.request(request)
+    }
+
+    fn bytes_received(&self) -> u64 {
+        self.previous_bytes + self.stream.bytes_received()
+    }
+
+    fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+}
+
+/// Connects to the device, such as a radio bridge, reconnecting whenever the
+/// link is lost
+pub struct TcpClientSource {
+    address: String,
+    timeout: Duration,
+    stream: StreamSource<TcpStream>,
+    /// bytes received over previous connections
+    previous_bytes: u64,
+    reconnects: u64,
+}
+
+impl TcpClientSource {
+    fn new(address: String, timeout: Duration) -> io::Result<Self> {
+        let stream = connect(&address, timeout)?;
+
+        Ok(TcpClientSource {
+            address,
+            timeout,
+            stream,
+            previous_bytes: 0,
+            reconnects: 0,
+        })
+    }
+
+    /// Connects again, backing off between attempts, giving up after
+    /// [`RECONNECT_TIMEOUT`]
+    fn reconnect(&self) -> io::Result<StreamSource<TcpStream>> {
+        let started = Instant::now();
+        let mut backoff = Duration::from_millis(100);
+
+        loop {
+            match connect(&self.address, self.timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) if started.elapsed() + backoff > RECONNECT_TIMEOUT => return Err(e),
+                Err(e) => {
+                    debug!("Failed to reconnect to {}, retrying: {}", self.address, e);
+
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+                }
+            }
+        }
+    }
+}
+
+fn connect(address: &str, timeout: Duration) -> io::Result<StreamSource<TcpStream>> {
+    let stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(timeout))?;
+    let control = stream.try_clone()?;
+
+    Ok(StreamSource::new(stream, Some(Box::new(control))).idle_on_timeout())
+}
+
+impl Source for TcpClientSource {
+    fn next_packet(&mut self) -> Result<Value, PacketError> {
+        match self.stream.next_packet() {
+            Err(PacketError::Closed(reason)) => {
+                warn!("Lost telemetry connection ({}), reconnecting", reason);
+
+                let stream = self.reconnect().map_err(|e| {
+                    PacketError::Closed(format!("{}, and failed to reconnect: {}", reason, e))
+                })?;
+
+                info!("Reconnected to {}", self.address);
+
+                self.previous_bytes += self.stream.bytes_received();
+                self.stream = stream;
+                self.reconnects += 1;
+
+                self.next_packet()
+            }
+            result => result,
+        }
+    }
+
+    fn request(&mut self, request: &[u8]) -> io::Result<()> {
+        self.stream.request(request)
+    }
+
+    fn bytes_received(&self) -> u64 {
+        self.previous_bytes + self.stream.bytes_received()
+    }
+
+    fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+}
+
+/// Reads packets from UDP datagrams, each holding exactly one packet. A corrupt
+/// datagram only loses the packet in it.
+pub struct DatagramSource {
+    socket: UdpSocket,
+    buffer: Vec<u8>,
+    bytes: u64,
+}
+
+impl DatagramSource {
+    pub fn new(socket: UdpSocket) -> Self {
+        DatagramSource {
+            socket,
+            buffer: vec![0; MAX_DATAGRAM_SIZE],
+            bytes: 0,
+        }
+    }
+}
+
+impl Source for DatagramSource {
+    fn next_packet(&mut self) -> Result<Value, PacketError> {
+        let length = self.socket.recv(&mut self.buffer).map_err(|e| {
+            if is_timeout(&e) {
+                PacketError::Idle
+            } else {
+                PacketError::Closed(e.to_string())
+            }
+        })?;
+        self.bytes += length as u64;
+
+        // A datagram cut short is a bad packet, not the end of the source
+        serde_cbor::from_slice(&self.buffer[..length]).map_err(PacketError::Malformed)
+    }
+
+    fn bytes_received(&self) -> u64 {
+        self.bytes
+    }
+}
@@ -0,0 +1,1219 @@
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, Seek, SeekFrom, Write},
+    mem,
+    ops::{Bound, RangeBounds},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{error, info, warn};
+use serde::Serialize;
+
+use crate::{
+    config::{config, Overflow, RetentionConfig},
+    telemetry::{TelemetryPacket, TelemetryValue},
+};
+
+/// The most packets written to a session before they are made visible to
+/// readers all at once
+const BATCH_SIZE: usize = 64;
+/// How often the session is updated while packets keep arriving. Packets left
+/// pending once they stop are written out by [`SessionWriter::flush_if_due`].
+const BATCH_INTERVAL: Duration = Duration::from_millis(50);
+/// The packets in each sealed chunk. Smaller batches are published as chunks
+/// of their own until there are enough of them to seal.
+const CHUNK_SIZE: usize = 4096;
+
+/// Once a retention limit is reached, packets are evicted until the store is
+/// this far under it, so that the spill file is written to in batches
+const RETENTION_SLACK: f64 = 0.9;
+
+/// How many packets are spilled between the entries of the index of a spill
+/// file, which reads seek to the start of their range with
+const SPILL_INDEX_INTERVAL: u64 = 1024;
+
+/// An estimate of the memory used by each entry of a `BTreeMap` beyond its key
+/// and value, from the nodes it is stored in
+const ENTRY_OVERHEAD: usize = 16;
+
+/// A run of packets, ordered by `running_us`
+type Chunk = Vec<TelemetryPacket>;
+
+/// The packets of a session. Packets are written through a [`SessionWriter`],
+/// which publishes them in batches as a new [`Snapshot`], so that readers never
+/// wait on ingest and ingest never waits on readers.
+#[derive(Default)]
+pub struct SessionData {
+    /// the latest snapshot, only locked to clone or replace it
+    current: Mutex<Arc<Snapshot>>,
+}
+
+/// The packets of a session at one point in time, which does not change as
+/// packets are added to the session
+#[derive(Default)]
+pub struct Snapshot {
+    /// runs of packets that do not overlap, ordered by `running_us`
+    chunks: Vec<Arc<Chunk>>,
+    spilled: Option<Spilled>,
+    usage: DataUsage,
+}
+
+/// How much of a session is in memory and on disk
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct DataUsage {
+    /// packets kept in memory
+    pub packets: usize,
+    /// an estimate of the memory used by the packets kept in memory
+    pub bytes: usize,
+    pub spilled_packets: u64,
+    pub spilled_bytes: u64,
+    pub discarded_packets: u64,
+}
+
+/// Adds packets to a session, batching them up so that the session is only
+/// updated every [`BATCH_SIZE`] packets or [`BATCH_INTERVAL`]. Pending packets
+/// are written out when the writer is dropped, and if the session keeps to the
+/// retention limits, the packets it kept in memory are spilled then too.
+pub struct SessionWriter {
+    data: Arc<SessionData>,
+    /// packets that are not yet visible to readers
+    pending: Vec<TelemetryPacket>,
+    /// when the session was last updated
+    last_flush: Instant,
+    /// the chunks of the latest snapshot
+    chunks: Vec<Arc<Chunk>>,
+    /// the number of leading chunks that are sealed
+    sealed: usize,
+    /// the packets of the chunks after the sealed ones, kept to seal them as
+    /// one chunk without copying them again
+    open: Vec<TelemetryPacket>,
+    spilled: Option<Spilled>,
+    usage: DataUsage,
+    /// where packets past the retention limits are spilled to, `None` if the
+    /// limits do not apply
+    spill_path: Option<PathBuf>,
+}
+
+/// A file packets of a session were spilled to, which is removed once no
+/// snapshot refers to it
+struct SpillFile {
+    path: PathBuf,
+}
+
+/// The packets of a session that were moved to disk
+#[derive(Clone)]
+struct Spilled {
+    file: Arc<SpillFile>,
+    packets: u64,
+    first_us: u64,
+    last_us: u64,
+    /// every [`SPILL_INDEX_INTERVAL`]th packet spilled, shared with snapshots
+    /// until more are spilled
+    index: Arc<Vec<IndexEntry>>,
+}
+
+/// Where a packet was spilled to
+#[derive(Clone, Copy)]
+struct IndexEntry {
+    running_us: u64,
+    /// the offset of its record in the spill file
+    offset: u64,
+    /// the number of packets spilled before it
+    record: u64,
+}
+
+/// Packets encoded to be appended to a spill file
+#[derive(Default)]
+struct SpillRecords {
+    bytes: Vec<u8>,
+    /// the entries for the index of the spill file, their offsets relative to
+    /// the start of `bytes`
+    index: Vec<IndexEntry>,
+}
+
+/// A packet as written to a spill file
+type SpillRecord = (
+    u64,
+    BTreeMap<String, TelemetryValue>,
+    BTreeMap<String, TelemetryValue>,
+);
+
+impl SessionData {
+    /// The packets of the session as of the last batch written
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.current
+            .lock()
+            .expect("session data lock was poisoned")
+            .clone()
+    }
+
+    fn publish(&self, snapshot: Snapshot) {
+        *self.current.lock().expect("session data lock was poisoned") = Arc::new(snapshot);
+    }
+}
+
+impl Snapshot {
+    /// The packets within a range of `running_us`, oldest first, reading those
+    /// that were spilled back from disk
+    pub fn range(
+        &self,
+        range: impl RangeBounds<u64>,
+    ) -> impl Iterator<Item = Cow<'_, TelemetryPacket>> + '_ {
+        let range = (owned(range.start_bound()), owned(range.end_bound()));
+
+        let spilled = self
+            .spilled
+            .as_ref()
+            .filter(|spilled| spilled.overlaps(range))
+            .map(|spilled| spilled.read(range))
+            .into_iter()
+            .flatten()
+            .skip_while(move |packet| before(range, packet.running_us))
+            .take_while(move |packet| !after(range, packet.running_us))
+            .map(Cow::Owned);
+
+        let first = self.chunks.partition_point(|chunk| {
+            chunk
+                .last()
+                .map_or(true, |packet| before(range, packet.running_us))
+        });
+
+        let stored = self.chunks[first..]
+            .iter()
+            .flat_map(move |chunk| {
+                let first = chunk.partition_point(|packet| before(range, packet.running_us));
+
+                chunk[first..].iter()
+            })
+            .take_while(move |packet| !after(range, packet.running_us))
+            .map(Cow::Borrowed);
+
+        spilled.chain(stored)
+    }
+
+    /// The number of packets, including those that were spilled
+    pub fn len(&self) -> usize {
+        self.usage.packets + self.usage.spilled_packets as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The `running_us` of the first packet, including those that were spilled
+    pub fn first_us(&self) -> Option<u64> {
+        self.spilled
+            .as_ref()
+            .map(|spilled| spilled.first_us)
+            .or_else(|| first_us(&self.chunks))
+    }
+
+    /// The `running_us` of the latest packet
+    pub fn last_us(&self) -> Option<u64> {
+        last_us(&self.chunks).or_else(|| self.spilled.as_ref().map(|spilled| spilled.last_us))
+    }
+
+    pub fn usage(&self) -> DataUsage {
+        self.usage
+    }
+}
+
+impl SessionWriter {
+    /// Writes to a session without limiting the packets it keeps in memory
+    pub fn new(data: Arc<SessionData>) -> Self {
+        SessionWriter::with_spill_path(data, None)
+    }
+
+    /// Writes to a session that keeps to the retention limits, spilling to
+    /// `spill_path` if configured to
+    pub fn retained(data: Arc<SessionData>, spill_path: PathBuf) -> Self {
+        SessionWriter::with_spill_path(data, Some(spill_path))
+    }
+
+    fn with_spill_path(data: Arc<SessionData>, spill_path: Option<PathBuf>) -> Self {
+        SessionWriter {
+            data,
+            pending: Vec::with_capacity(BATCH_SIZE),
+            last_flush: Instant::now(),
+            chunks: Vec::new(),
+            sealed: 0,
+            open: Vec::new(),
+            spilled: None,
+            usage: DataUsage::default(),
+            spill_path,
+        }
+    }
+
+    /// Stores a packet, replacing any packet with the same `running_us`. A
+    /// packet arriving after a quiet spell is made visible straight away.
+    pub fn insert(&mut self, packet: TelemetryPacket) {
+        self.pending.push(packet);
+
+        if self.pending.len() >= BATCH_SIZE {
+            self.flush();
+        } else {
+            self.flush_if_due();
+        }
+    }
+
+    /// Whether a packet goes back to or before the packets spilled to disk.
+    /// Spill files are only ever appended to, so such a packet can not be
+    /// stored with them, and is discarded if it is inserted anyway.
+    pub fn goes_back(&self, running_us: u64) -> bool {
+        self.spilled
+            .as_ref()
+            .map_or(false, |spilled| running_us <= spilled.last_us)
+    }
+
+    /// Makes pending packets visible to readers if the session has not been
+    /// updated for [`BATCH_INTERVAL`], to be called whenever the source is
+    /// read from, so that packets are not held back when the link goes quiet
+    pub fn flush_if_due(&mut self) {
+        if self.last_flush.elapsed() >= BATCH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    /// Makes every pending packet visible to readers
+    pub fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        self.last_flush = Instant::now();
+
+        let mut batch = mem::replace(&mut self.pending, Vec::with_capacity(BATCH_SIZE));
+        batch.sort_by_key(|packet| packet.running_us);
+
+        // Of the packets with the same `running_us`, the last one written is kept
+        batch.dedup_by(|later, earlier| {
+            let duplicate = later.running_us == earlier.running_us;
+            if duplicate {
+                mem::swap(later, earlier);
+            }
+
+            duplicate
+        });
+
+        if let Some(spilled) = &self.spilled {
+            let behind = batch.partition_point(|packet| packet.running_us <= spilled.last_us);
+
+            if behind > 0 {
+                warn!(
+                    "Discarding {} packets that go back before those spilled to disk",
+                    behind
+                );
+
+                batch.drain(..behind);
+                self.usage.discarded_packets += behind as u64;
+
+                if batch.is_empty() {
+                    self.publish();
+
+                    return;
+                }
+            }
+        }
+
+        let out_of_order = last_us(&self.chunks).map_or(false, |last| batch[0].running_us <= last);
+        if out_of_order {
+            batch = self.merge_overlapping(batch);
+        }
+
+        self.append(batch);
+
+        if self.spill_path.is_some() {
+            self.retain(&config().retention);
+        }
+
+        self.publish();
+    }
+
+    /// Makes the packets stored so far visible to readers
+    fn publish(&self) {
+        self.data.publish(Snapshot {
+            chunks: self.chunks.clone(),
+            spilled: self.spilled.clone(),
+            usage: self.usage,
+        });
+    }
+
+    /// Merges a batch that arrived out of order into the stored packets,
+    /// returning the packets left to append. Only the chunks the batch overlaps
+    /// are rewritten, along with the open chunks if it reaches them, so a batch
+    /// far older than the latest packet, such as after the firmware restarts,
+    /// costs no more than one that is only just late.
+    fn merge_overlapping(&mut self, batch: Vec<TelemetryPacket>) -> Vec<TelemetryPacket> {
+        let start = batch[0].running_us;
+        let end = batch[batch.len() - 1].running_us;
+
+        let first = self.chunks.partition_point(|chunk| {
+            chunk
+                .last()
+                .map_or(true, |packet| packet.running_us < start)
+        });
+        let last = self.chunks.partition_point(|chunk| {
+            chunk
+                .first()
+                .map_or(true, |packet| packet.running_us <= end)
+        });
+
+        if last > self.sealed {
+            // The open chunks are rewritten as the merged packets are appended
+            let taken = self.chunks.split_off(first);
+
+            if first < self.sealed {
+                self.sealed = first;
+                self.open.clear();
+            } else {
+                let open = self.chunks[self.sealed..]
+                    .iter()
+                    .map(|chunk| chunk.len())
+                    .sum();
+                self.open.truncate(open);
+            }
+
+            return self.merge(taken, batch);
+        }
+
+        // Sealed chunks are replaced in place by chunks of the merged packets
+        let taken = self.chunks.drain(first..last).collect::<Vec<_>>();
+        let merged = self.merge(taken, batch);
+
+        for packet in &merged {
+            self.usage.packets += 1;
+            self.usage.bytes += packet_bytes(packet);
+        }
+
+        let chunks = merged
+            .chunks(CHUNK_SIZE)
+            .map(|chunk| Arc::new(chunk.to_vec()))
+            .collect::<Vec<_>>();
+
+        self.sealed = self.sealed - (last - first) + chunks.len();
+        self.chunks.splice(first..first, chunks);
+
+        Vec::new()
+    }
+
+    /// Merges stored chunks with a batch, both ordered by `running_us`, taking
+    /// the packet from the batch where both have one. The packets of the chunks
+    /// are no longer counted as stored.
+    fn merge(
+        &mut self,
+        taken: Vec<Arc<Chunk>>,
+        batch: Vec<TelemetryPacket>,
+    ) -> Vec<TelemetryPacket> {
+        for packet in taken.iter().flat_map(|chunk| chunk.iter()) {
+            self.usage.packets -= 1;
+            self.usage.bytes -= packet_bytes(packet);
+        }
+
+        let mut stored = taken
+            .into_iter()
+            .flat_map(|chunk| Arc::try_unwrap(chunk).unwrap_or_else(|chunk| (*chunk).clone()))
+            .peekable();
+        let mut batch = batch.into_iter().peekable();
+
+        let mut merged = Vec::new();
+        loop {
+            let packet = match (stored.peek(), batch.peek()) {
+                (Some(old), Some(new)) if old.running_us < new.running_us => stored.next(),
+                (Some(old), Some(new)) if old.running_us == new.running_us => {
+                    stored.next();
+                    batch.next()
+                }
+                (_, Some(_)) => batch.next(),
+                (Some(_), None) => stored.next(),
+                (None, None) => break,
+            };
+
+            merged.extend(packet);
+        }
+
+        merged
+    }
+
+    /// Adds packets that all come after the stored packets
+    fn append(&mut self, packets: Vec<TelemetryPacket>) {
+        for packet in &packets {
+            self.usage.packets += 1;
+            self.usage.bytes += packet_bytes(packet);
+        }
+
+        let mut packets = packets.into_iter().peekable();
+
+        while packets.peek().is_some() {
+            let room = CHUNK_SIZE - self.open.len();
+            let chunk = packets.by_ref().take(room).collect::<Vec<_>>();
+
+            self.chunks.push(Arc::new(chunk.clone()));
+            self.open.extend(chunk);
+
+            if self.open.len() == CHUNK_SIZE {
+                // The chunks of each batch are replaced by a single sealed chunk
+                self.chunks.truncate(self.sealed);
+                self.chunks.push(Arc::new(mem::take(&mut self.open)));
+                self.sealed += 1;
+            }
+        }
+    }
+
+    /// Evicts the oldest packets while any retention limit is exceeded
+    fn retain(&mut self, retention: &RetentionConfig) {
+        let (first, last) = match (first_us(&self.chunks), last_us(&self.chunks)) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return,
+        };
+
+        if !exceeds(retention, &self.usage, last - first, 1.0) {
+            return;
+        }
+
+        let spill = retention.overflow == Overflow::Spill;
+
+        let (evicted, records, evicted_range) = self.front(spill, |usage, packet| {
+            exceeds(retention, usage, last - packet.running_us, RETENTION_SLACK)
+        });
+
+        if evicted == 0 {
+            return;
+        }
+
+        self.evict(evicted);
+
+        if !spill {
+            self.usage.discarded_packets += evicted;
+
+            return;
+        }
+
+        if let Err(e) = self.spill(&records, evicted, evicted_range) {
+            error!(
+                "Failed to spill {} packets to disk, discarding them: {}",
+                evicted, e
+            );
+
+            self.usage.discarded_packets += evicted;
+        }
+    }
+
+    /// Spills every packet kept in memory once the session has finished, as
+    /// nothing is watching it live any more. Packets are kept in memory if no
+    /// retention limit applies, if the limits discard packets instead, or if
+    /// they can not be spilled.
+    fn spill_finished(&mut self) {
+        let retention = &config().retention;
+        if self.spill_path.is_none()
+            || !retention.is_limited()
+            || retention.overflow != Overflow::Spill
+        {
+            return;
+        }
+
+        let (count, records, range) = self.front(true, |_, _| true);
+        if count == 0 {
+            return;
+        }
+
+        match self.spill(&records, count, range) {
+            Ok(()) => {
+                self.evict(count);
+                self.publish();
+            }
+            Err(e) => error!(
+                "Failed to spill the {} packets of a finished session, keeping them in memory: {}",
+                count, e
+            ),
+        }
+    }
+
+    /// Counts the packets from the front while `evict` holds for the oldest
+    /// packet left, given what would be left in memory, returning how many
+    /// there are, their spill records if `spill`, and the range of their
+    /// `running_us`
+    fn front(
+        &self,
+        spill: bool,
+        mut evict: impl FnMut(&DataUsage, &TelemetryPacket) -> bool,
+    ) -> (u64, SpillRecords, (u64, u64)) {
+        let mut usage = self.usage;
+        let mut records = SpillRecords::default();
+        let mut count = 0;
+        let mut range = (u64::MAX, 0);
+
+        let spilled = self.spilled.as_ref().map_or(0, |spilled| spilled.packets);
+
+        for packet in self.chunks.iter().flat_map(|chunk| chunk.iter()) {
+            if !evict(&usage, packet) {
+                break;
+            }
+
+            if spill {
+                let record = (packet.running_us, &packet.values, &packet.raw);
+
+                if (spilled + count) % SPILL_INDEX_INTERVAL == 0 {
+                    records.index.push(IndexEntry {
+                        running_us: packet.running_us,
+                        offset: records.bytes.len() as u64,
+                        record: spilled + count,
+                    });
+                }
+
+                if let Err(e) = serde_cbor::to_writer(&mut records.bytes, &record) {
+                    error!("Failed to encode packet to spill: {}", e);
+                }
+            }
+
+            usage.packets -= 1;
+            usage.bytes -= packet_bytes(packet);
+            range = (
+                range.0.min(packet.running_us),
+                range.1.max(packet.running_us),
+            );
+            count += 1;
+        }
+
+        (count, records, range)
+    }
+
+    /// Removes the oldest `count` packets from memory
+    fn evict(&mut self, mut count: u64) {
+        while count > 0 {
+            let front = (self.chunks[0].len() as u64).min(count) as usize;
+
+            for packet in &self.chunks[0][..front] {
+                self.usage.packets -= 1;
+                self.usage.bytes -= packet_bytes(packet);
+            }
+
+            self.evict_front(front);
+            count -= front as u64;
+        }
+    }
+
+    /// Removes packets from the front of the first chunk
+    fn evict_front(&mut self, count: usize) {
+        if count == self.chunks[0].len() {
+            self.chunks.remove(0);
+
+            if self.sealed > 0 {
+                self.sealed -= 1;
+            } else {
+                self.open.drain(..count);
+            }
+        } else {
+            self.chunks[0] = Arc::new(self.chunks[0][count..].to_vec());
+
+            if self.sealed == 0 {
+                self.open.drain(..count);
+            }
+        }
+    }
+
+    fn spill(
+        &mut self,
+        records: &SpillRecords,
+        packets: u64,
+        (first, last): (u64, u64),
+    ) -> io::Result<()> {
+        if self.spilled.is_none() {
+            match &self.spill_path {
+                Some(path) => {
+                    self.spilled = Some(Spilled {
+                        file: Arc::new(SpillFile::create(path.clone())?),
+                        packets: 0,
+                        first_us: first,
+                        last_us: last,
+                        index: Arc::new(Vec::new()),
+                    })
+                }
+                None => return Ok(()),
+            }
+        }
+
+        let spilled = match &mut self.spilled {
+            Some(spilled) => spilled,
+            None => return Ok(()),
+        };
+
+        // The file is only held open while writing, so that it can be removed
+        // on any platform once the session is gone
+        let mut file = OpenOptions::new().append(true).open(&spilled.file.path)?;
+        let offset = file.seek(SeekFrom::End(0))?;
+        file.write_all(&records.bytes)?;
+
+        Arc::make_mut(&mut spilled.index).extend(records.index.iter().map(|entry| IndexEntry {
+            offset: offset + entry.offset,
+            ..*entry
+        }));
+
+        spilled.packets += packets;
+        spilled.first_us = spilled.first_us.min(first);
+        spilled.last_us = spilled.last_us.max(last);
+
+        self.usage.spilled_packets += packets;
+        self.usage.spilled_bytes += records.bytes.len() as u64;
+
+        Ok(())
+    }
+}
+
+impl Drop for SessionWriter {
+    fn drop(&mut self) {
+        self.flush();
+        self.spill_finished();
+    }
+}
+
+impl SpillFile {
+    fn create(path: PathBuf) -> io::Result<Self> {
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+
+        File::create(&path)?;
+
+        info!("Spilling telemetry to {}", path.display());
+
+        Ok(SpillFile { path })
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            error!("Failed to remove spill file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+impl Spilled {
+    /// Whether any spilled packet could be within a range
+    fn overlaps(&self, range: (Bound<u64>, Bound<u64>)) -> bool {
+        !(before(range, self.last_us) || after(range, self.first_us))
+    }
+
+    /// Reads the spilled packets back from the last indexed packet before the
+    /// start of a range, stopping at the first that can not be read. Packets
+    /// spilled after this was taken are not read.
+    fn read(&self, range: (Bound<u64>, Bound<u64>)) -> impl Iterator<Item = TelemetryPacket> {
+        let path = self.file.path.clone();
+
+        let start = self
+            .index
+            .partition_point(|entry| before(range, entry.running_us))
+            .checked_sub(1)
+            .map(|entry| self.index[entry]);
+        let (offset, record) = start.map_or((0, 0), |entry| (entry.offset, entry.record));
+
+        let file = File::open(&path).and_then(|mut file| {
+            file.seek(SeekFrom::Start(offset))?;
+
+            Ok(file)
+        });
+        let file = match file {
+            Ok(file) => Some(file),
+            Err(e) => {
+                error!("Failed to open spill file {}: {}", path.display(), e);
+
+                None
+            }
+        };
+
+        file.into_iter()
+            .flat_map(|file| {
+                serde_cbor::Deserializer::from_reader(BufReader::new(file))
+                    .into_iter::<SpillRecord>()
+            })
+            .take((self.packets - record) as usize)
+            .scan((), move |_, record| match record {
+                Ok((running_us, values, raw)) => Some(TelemetryPacket {
+                    running_us,
+                    values,
+                    raw,
+                }),
+                Err(e) => {
+                    error!("Failed to read spill file {}: {}", path.display(), e);
+
+                    None
+                }
+            })
+    }
+}
+
+fn first_us(chunks: &[Arc<Chunk>]) -> Option<u64> {
+    chunks
+        .first()
+        .and_then(|chunk| chunk.first())
+        .map(|packet| packet.running_us)
+}
+
+fn last_us(chunks: &[Arc<Chunk>]) -> Option<u64> {
+    chunks
+        .last()
+        .and_then(|chunk| chunk.last())
+        .map(|packet| packet.running_us)
+}
+
+/// Whether the memory used by a session exceeds a fraction of any retention
+/// limit, `span_us` being the time between its first and last packets
+fn exceeds(retention: &RetentionConfig, usage: &DataUsage, span_us: u64, fraction: f64) -> bool {
+    retention
+        .max_duration_s
+        .map_or(false, |max| span_us as f64 / 1_000_000.0 > max * fraction)
+        || retention
+            .max_samples
+            .map_or(false, |max| usage.packets as f64 > max as f64 * fraction)
+        || retention
+            .max_bytes
+            .map_or(false, |max| usage.bytes as f64 > max as f64 * fraction)
+}
+
+fn owned(bound: Bound<&u64>) -> Bound<u64> {
+    match bound {
+        Bound::Included(&bound) => Bound::Included(bound),
+        Bound::Excluded(&bound) => Bound::Excluded(bound),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// Whether a `running_us` comes before the start of a range
+fn before(range: (Bound<u64>, Bound<u64>), running_us: u64) -> bool {
+    match range.0 {
+        Bound::Included(start) => running_us < start,
+        Bound::Excluded(start) => running_us <= start,
+        Bound::Unbounded => false,
+    }
+}
+
+/// Whether a `running_us` comes after the end of a range
+fn after(range: (Bound<u64>, Bound<u64>), running_us: u64) -> bool {
+    match range.1 {
+        Bound::Included(end) => running_us > end,
+        Bound::Excluded(end) => running_us >= end,
+        Bound::Unbounded => false,
+    }
+}
+
+/// An estimate of the memory used by a packet in the store
+fn packet_bytes(packet: &TelemetryPacket) -> usize {
+    let values = |values: &BTreeMap<String, TelemetryValue>| {
+        values
+            .keys()
+            .map(|key| {
+                key.len()
+                    + mem::size_of::<String>()
+                    + mem::size_of::<TelemetryValue>()
+                    + ENTRY_OVERHEAD
+            })
+            .sum::<usize>()
+    };
+
+    mem::size_of::<u64>()
+        + mem::size_of::<TelemetryPacket>()
+        + ENTRY_OVERHEAD
+        + values(&packet.values)
+        + values(&packet.raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, ops::Bound, path::PathBuf, sync::Arc, thread};
+
+    use super::{
+        packet_bytes, SessionData, SessionWriter, Snapshot, BATCH_INTERVAL, CHUNK_SIZE,
+        SPILL_INDEX_INTERVAL,
+    };
+    use crate::{
+        config::{Overflow, RetentionConfig},
+        telemetry::{TelemetryPacket, TelemetryValue},
+    };
+
+    fn packet(running_us: u64, x: i64) -> TelemetryPacket {
+        let mut values = BTreeMap::new();
+        values.insert("x".to_string(), TelemetryValue::Integer(x));
+
+        TelemetryPacket {
+            running_us,
+            values,
+            raw: BTreeMap::new(),
+        }
+    }
+
+    fn writer() -> (Arc<SessionData>, SessionWriter) {
+        let data = Arc::new(SessionData::default());
+
+        (data.clone(), SessionWriter::new(data))
+    }
+
+    /// Writes a packet for every `running_us`, with `x` set to it
+    fn write(writer: &mut SessionWriter, running_us: impl IntoIterator<Item = u64>) {
+        for running_us in running_us {
+            writer.insert(packet(running_us, running_us as i64));
+        }
+
+        writer.flush();
+    }
+
+    /// The `running_us` and `x` of every packet in a snapshot
+    fn contents(snapshot: &Snapshot) -> Vec<(u64, i64)> {
+        snapshot
+            .range(..)
+            .map(|packet| match packet.values["x"] {
+                TelemetryValue::Integer(x) => (packet.running_us, x),
+                value => panic!("x should be an integer, not {:?}", value),
+            })
+            .collect()
+    }
+
+    fn times(range: impl IntoIterator<Item = u64>) -> Vec<(u64, i64)> {
+        range
+            .into_iter()
+            .map(|running_us| (running_us, running_us as i64))
+            .collect()
+    }
+
+    /// Checks that the chunks are ordered and do not overlap, that the chunks
+    /// after the sealed ones hold exactly the open packets, and that the usage
+    /// counts the packets in memory
+    fn check(writer: &SessionWriter) {
+        let stored = writer
+            .chunks
+            .iter()
+            .flat_map(|chunk| chunk.iter())
+            .collect::<Vec<_>>();
+
+        assert!(writer.chunks.iter().all(|chunk| !chunk.is_empty()));
+        assert!(
+            stored
+                .windows(2)
+                .all(|pair| pair[0].running_us < pair[1].running_us),
+            "stored packets should be ordered by running_us"
+        );
+        assert!(writer.sealed <= writer.chunks.len());
+
+        let open = writer.chunks[writer.sealed..]
+            .iter()
+            .flat_map(|chunk| chunk.iter())
+            .map(|packet| packet.running_us)
+            .collect::<Vec<_>>();
+        let expected_open = writer
+            .open
+            .iter()
+            .map(|packet| packet.running_us)
+            .collect::<Vec<_>>();
+        assert_eq!(open, expected_open);
+
+        assert_eq!(writer.usage.packets, stored.len());
+        assert_eq!(
+            writer.usage.bytes,
+            stored
+                .iter()
+                .map(|packet| packet_bytes(packet))
+                .sum::<usize>()
+        );
+    }
+
+    fn spill_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "pico-mct-store-{}-{}.cbor",
+            std::process::id(),
+            name
+        ))
+    }
+
+    fn limit_samples(max_samples: usize, overflow: Overflow) -> RetentionConfig {
+        RetentionConfig {
+            max_duration_s: None,
+            max_samples: Some(max_samples),
+            max_bytes: None,
+            overflow,
+        }
+    }
+
+    #[test]
+    fn out_of_order_and_duplicate_running_us() {
+        let (data, mut writer) = writer();
+
+        write(&mut writer, (0..10).map(|i| i * 10));
+
+        // The last packet written with a `running_us` is kept, whether the
+        // duplicate is in the same batch or already stored
+        writer.insert(packet(50, 1));
+        writer.insert(packet(55, 55));
+        writer.insert(packet(50, 2));
+        writer.insert(packet(30, -30));
+        writer.insert(packet(5, 5));
+        writer.insert(packet(200, 200));
+        writer.flush();
+        check(&writer);
+
+        assert_eq!(
+            contents(&data.snapshot()),
+            vec![
+                (0, 0),
+                (5, 5),
+                (10, 10),
+                (20, 20),
+                (30, -30),
+                (40, 40),
+                (50, 2),
+                (55, 55),
+                (60, 60),
+                (70, 70),
+                (80, 80),
+                (90, 90),
+                (200, 200),
+            ]
+        );
+        assert_eq!(data.snapshot().usage().packets, 13);
+    }
+
+    #[test]
+    fn older_batches_only_rewrite_the_chunks_they_overlap() {
+        let (data, mut writer) = writer();
+        let start = 1_000_000;
+
+        write(&mut writer, start..start + 3 * CHUNK_SIZE as u64 + 10);
+        check(&writer);
+        assert_eq!(writer.sealed, 3);
+
+        let before = writer.chunks.clone();
+
+        // As if the firmware restarted, its `running_us` starting over
+        write(&mut writer, 0..64);
+        check(&writer);
+
+        assert!(Arc::ptr_eq(&writer.chunks[1], &before[0]));
+        assert!(before[1..]
+            .iter()
+            .zip(&writer.chunks[2..])
+            .all(|(before, after)| Arc::ptr_eq(before, after)));
+
+        // A batch within a sealed chunk only rewrites that chunk
+        let middle = start + CHUNK_SIZE as u64 + 100;
+        writer.insert(packet(middle, -1));
+        writer.insert(packet(middle + 1, -2));
+        writer.flush();
+        check(&writer);
+
+        assert!(Arc::ptr_eq(&writer.chunks[1], &before[0]));
+        assert!(!Arc::ptr_eq(&writer.chunks[2], &before[1]));
+        assert!(Arc::ptr_eq(&writer.chunks[3], &before[2]));
+
+        let snapshot = data.snapshot();
+        assert_eq!(snapshot.len(), 64 + 3 * CHUNK_SIZE + 10);
+        assert_eq!(
+            contents(&snapshot)
+                .into_iter()
+                .filter(|&(running_us, _)| running_us == middle || running_us == middle + 1)
+                .collect::<Vec<_>>(),
+            vec![(middle, -1), (middle + 1, -2)]
+        );
+    }
+
+    #[test]
+    fn partial_eviction_of_sealed_and_open_chunks() {
+        let (data, mut writer) = writer();
+        let total = CHUNK_SIZE as u64 + 100;
+
+        write(&mut writer, 0..total);
+        assert_eq!(writer.sealed, 1);
+
+        // Evicts part of the sealed chunk, down to 90% of the limit
+        writer.retain(&limit_samples(CHUNK_SIZE, Overflow::Discard));
+        writer.publish();
+        check(&writer);
+
+        let kept = (CHUNK_SIZE as f64 * 0.9) as u64;
+        assert_eq!(writer.sealed, 1);
+        assert_eq!(contents(&data.snapshot()), times(total - kept..total));
+        assert_eq!(data.snapshot().usage().discarded_packets, total - kept);
+
+        // Evicts the rest of the sealed chunk and part of the open one
+        writer.retain(&limit_samples(50, Overflow::Discard));
+        writer.publish();
+        check(&writer);
+
+        assert_eq!(writer.sealed, 0);
+        assert_eq!(contents(&data.snapshot()), times(total - 45..total));
+
+        // Writing carries on after the open packets that are left
+        write(&mut writer, total..total + 10);
+        check(&writer);
+        assert_eq!(contents(&data.snapshot()), times(total - 45..total + 10));
+    }
+
+    #[test]
+    fn spilled_packets_are_read_back() {
+        let (data, mut writer) = writer();
+        writer.spill_path = Some(spill_path("read-back"));
+
+        write(&mut writer, 0..1000);
+        writer.retain(&limit_samples(100, Overflow::Spill));
+        writer.publish();
+        check(&writer);
+
+        let snapshot = data.snapshot();
+        assert_eq!(snapshot.usage().packets, 90);
+        assert_eq!(snapshot.usage().spilled_packets, 910);
+        assert_eq!(snapshot.first_us(), Some(0));
+        assert_eq!(contents(&snapshot), times(0..1000));
+        assert_eq!(
+            snapshot.range(900..=920).count(),
+            21,
+            "ranges should span the spilled and stored packets"
+        );
+    }
+
+    #[test]
+    fn spilled_reads_seek_to_the_start_of_their_range() {
+        let (data, mut writer) = writer();
+        writer.spill_path = Some(spill_path("seek"));
+
+        write(&mut writer, 0..10_000);
+        writer.retain(&limit_samples(100, Overflow::Spill));
+        writer.publish();
+
+        let snapshot = data.snapshot();
+        let spilled = snapshot
+            .spilled
+            .as_ref()
+            .expect("packets should be spilled");
+        assert_eq!(spilled.packets, 9910);
+        assert_eq!(
+            spilled.index.len() as u64,
+            (9910 + SPILL_INDEX_INTERVAL - 1) / SPILL_INDEX_INTERVAL
+        );
+
+        // Reading starts from the last indexed packet before the range
+        let range = (Bound::Included(5000), Bound::Excluded(5010));
+        assert_eq!(
+            spilled.read(range).next().map(|packet| packet.running_us),
+            Some(4 * SPILL_INDEX_INTERVAL)
+        );
+        assert_eq!(spilled.read(range).count(), 9910 - 4096);
+
+        assert_eq!(
+            snapshot
+                .range(5000..5010)
+                .map(|packet| packet.running_us)
+                .collect::<Vec<_>>(),
+            (5000..5010).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            snapshot
+                .range(..=0)
+                .map(|packet| packet.running_us)
+                .collect::<Vec<_>>(),
+            vec![0]
+        );
+        assert_eq!(contents(&snapshot), times(0..10_000));
+    }
+
+    #[test]
+    fn batches_going_back_before_spilled_packets_are_discarded() {
+        let (data, mut writer) = writer();
+        writer.spill_path = Some(spill_path("going-back"));
+
+        write(&mut writer, 100..1100);
+        writer.retain(&limit_samples(100, Overflow::Spill));
+        writer.publish();
+
+        let last_spilled = 100 + 910 - 1;
+        assert!(writer.goes_back(0));
+        assert!(writer.goes_back(last_spilled));
+        assert!(!writer.goes_back(last_spilled + 1));
+
+        // A batch overlapping the spilled packets, and the stored ones, keeps
+        // only the packets after those spilled
+        writer.insert(packet(0, -1));
+        writer.insert(packet(500, -1));
+        writer.insert(packet(last_spilled, -1));
+        writer.insert(packet(last_spilled + 1, -1));
+        writer.insert(packet(2000, 2000));
+        writer.flush();
+        check(&writer);
+
+        let mut expected = times(100..1100);
+        expected[last_spilled as usize + 1 - 100].1 = -1;
+        expected.push((2000, 2000));
+
+        let snapshot = data.snapshot();
+        assert_eq!(contents(&snapshot), expected);
+        assert_eq!(snapshot.usage().discarded_packets, 3);
+        assert_eq!(snapshot.len(), 1001);
+
+        // As does a batch entirely before them
+        write(&mut writer, 0..64);
+        check(&writer);
+        assert_eq!(contents(&data.snapshot()), expected);
+        assert_eq!(data.snapshot().usage().discarded_packets, 3 + 64);
+    }
+
+    #[test]
+    fn snapshots_are_consistent_across_flushes() {
+        let (data, mut writer) = writer();
+        writer.spill_path = Some(spill_path("consistent"));
+
+        write(&mut writer, 0..100);
+        let first = data.snapshot();
+
+        // Packets merged into the chunks of an earlier snapshot, and chunks
+        // sealed since, do not change it
+        write(&mut writer, (50..60).chain(100..CHUNK_SIZE as u64 + 10));
+        check(&writer);
+        let second = data.snapshot();
+
+        assert_eq!(contents(&first), times(0..100));
+        assert_eq!(first.len(), 100);
+        assert_eq!(contents(&second), times(0..CHUNK_SIZE as u64 + 10));
+
+        // Nor do packets spilled since, even though they are appended to the
+        // same file the earlier snapshot reads from
+        writer.retain(&limit_samples(1000, Overflow::Spill));
+        writer.publish();
+        let third = data.snapshot();
+
+        write(
+            &mut writer,
+            CHUNK_SIZE as u64 + 10..CHUNK_SIZE as u64 + 2000,
+        );
+        writer.retain(&limit_samples(1000, Overflow::Spill));
+        writer.publish();
+        check(&writer);
+
+        assert_eq!(contents(&second), times(0..CHUNK_SIZE as u64 + 10));
+        assert_eq!(contents(&third), times(0..CHUNK_SIZE as u64 + 10));
+        assert_eq!(
+            contents(&data.snapshot()),
+            times(0..CHUNK_SIZE as u64 + 2000)
+        );
+        assert!(
+            third.usage().spilled_packets < data.snapshot().usage().spilled_packets,
+            "the last retention should have spilled more packets"
+        );
+    }
+
+    #[test]
+    fn quiet_links_are_not_held_back() {
+        let (data, mut writer) = writer();
+
+        // A packet after a quiet spell is visible straight away
+        thread::sleep(BATCH_INTERVAL);
+        writer.insert(packet(0, 0));
+        assert_eq!(data.snapshot().len(), 1);
+
+        // Packets left pending when the link goes quiet are written out once
+        // it is read from again
+        writer.insert(packet(1, 1));
+        thread::sleep(BATCH_INTERVAL);
+        writer.flush_if_due();
+        assert_eq!(data.snapshot().len(), 2);
+    }
+}
@@ -1,7 +1,19 @@
-use const_format::concatcp;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
+    fmt,
+};
+
 use derive_builder::Builder;
 use serde::{Deserialize, Serialize};
-use ts_rs::{export, TS};
+use ts_rs::TS;
+
+use crate::{
+    calibration::{CalibrationDefinition, CALIBRATIONS},
+    config::config,
+    derived::{dependencies_met, DerivedDefinition, DERIVED_DEFINITIONS},
+    objects::Subsystem,
+};
 
 /// Uniquely identifies a domain object.
 #[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
@@ -13,16 +25,25 @@ pub struct Identifier<'a> {
 }
 
 impl<'a> Identifier<'a> {
-    pub const NAMESPACE: &'static str = "dusterthefirst.pico-pilot";
+    /// The namespace all of the server's objects live in, as configured
+    pub fn namespace() -> &'static str {
+        &config().server.namespace
+    }
 
-    pub const fn from_key(key: &'a str) -> Identifier {
+    pub fn from_key(key: &'a str) -> Identifier {
         Identifier {
-            namespace: Self::NAMESPACE,
+            namespace: Self::namespace(),
             key,
         }
     }
 }
 
+impl fmt::Display for Identifier<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.namespace, self.key)
+    }
+}
+
 /// A domain object is an entity of relevance to a user's workflow, that
 /// should appear as a distinct and meaningful object within the user
 /// interface. Examples of domain objects are folders, telemetry sensors,
@@ -48,11 +69,202 @@ pub struct DomainObject<'a> {
     modified: Option<u64>,
     /// if present, this will be used by the default composition provider to
     /// load domain objects
-    composition: Option<&'a [Identifier<'a>]>,
+    composition: Option<Vec<Identifier<'a>>>,
     telemetry: Option<DomainObjectTelemetry<'a>>,
+
+    /// the field of the incoming packet this domain object is decoded from
+    #[serde(skip)]
+    field: Option<PacketField<'a>>,
+    /// the conversion from the raw packet value into engineering units
+    #[serde(skip)]
+    calibration: Option<&'a CalibrationDefinition>,
 }
 
-#[derive(Debug, Serialize)]
+impl<'a> DomainObject<'a> {
+    /// A folder containing other domain objects
+    pub fn folder(
+        key: &'a str,
+        name: &'a str,
+        location: &'a str,
+        composition: Vec<Identifier<'a>>,
+    ) -> Self {
+        DomainObject {
+            identifier: Identifier::from_key(key),
+            ty: "folder",
+            name,
+            creator: None,
+            location,
+            modified: None,
+            composition: Some(composition),
+            telemetry: None,
+            field: None,
+            calibration: None,
+        }
+    }
+
+    /// A copy of this object under another key and location, such as a
+    /// telemetry point scoped to a single session
+    pub fn scoped(&self, key: &'a str, location: &'a str) -> Self {
+        DomainObject {
+            identifier: Identifier::from_key(key),
+            ty: self.ty,
+            name: self.name,
+            creator: self.creator,
+            location,
+            modified: self.modified,
+            composition: None,
+            telemetry: self.telemetry.clone(),
+            field: self.field,
+            calibration: self.calibration,
+        }
+    }
+
+    /// The difference of this telemetry point between two sessions, under
+    /// another key and location. Differences are plain numbers in the units of
+    /// the engineering value, without a raw value or enumerations.
+    pub fn difference(&self, key: &'a str, location: &'a str) -> Self {
+        let units = self.telemetry.as_ref().and_then(|telemetry| {
+            telemetry
+                .values
+                .iter()
+                .find(|metadata| metadata.key == "value")?
+                .units
+        });
+
+        let mut value_metadata = ValueMetadataBuilder::default();
+        value_metadata
+            .key("value")
+            .name("Difference")
+            .format(FieldType::Float.format());
+
+        if let Some(units) = units {
+            value_metadata.units(units);
+        }
+
+        DomainObject {
+            identifier: Identifier::from_key(key),
+            ty: self.ty,
+            name: self.name,
+            creator: self.creator,
+            location,
+            modified: self.modified,
+            composition: None,
+            telemetry: Some(DomainObjectTelemetry::new(vec![
+                value_metadata.build().unwrap(),
+                *TELEMETRY_TIME,
+            ])),
+            field: None,
+            calibration: None,
+        }
+    }
+
+    pub fn identifier(&self) -> &Identifier<'a> {
+        &self.identifier
+    }
+
+    pub fn field(&self) -> Option<&PacketField<'a>> {
+        self.field.as_ref()
+    }
+
+    pub fn calibration(&self) -> Option<&'a CalibrationDefinition> {
+        self.calibration
+    }
+
+    /// The named states of an enumerated telemetry point
+    pub fn enumerations(&self) -> Option<&'a [TelemetryEnumeration<'a>]> {
+        self.telemetry
+            .as_ref()?
+            .values
+            .iter()
+            .find(|metadata| metadata.key == "value")?
+            .enumerations
+    }
+}
+
+/// Describes where a telemetry point lives in the packets sent by the device
+#[derive(Debug, Clone, Copy)]
+pub struct PacketField<'a> {
+    /// the name of the field in the incoming CBOR map
+    pub name: &'a str,
+    /// the type the field is expected to be encoded as
+    pub ty: FieldType,
+}
+
+impl<'a> PacketField<'a> {
+    pub const fn new(name: &'a str, ty: FieldType) -> Self {
+        PacketField { name, ty }
+    }
+}
+
+/// The wire types that a telemetry point can be encoded as
+#[derive(Debug, Serialize, Deserialize, TS, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    Float,
+    Integer,
+    Boolean,
+    /// an integer (or boolean) selecting one of a set of named states
+    Enumeration,
+}
+
+impl FieldType {
+    /// The Open MCT format identifier used to display values of this type
+    pub const fn format(self) -> &'static str {
+        match self {
+            FieldType::Float => "float",
+            FieldType::Integer => "integer",
+            FieldType::Boolean => "boolean",
+            FieldType::Enumeration => "enum",
+        }
+    }
+
+    /// Checks if a decoded CBOR value can be read as this type. Integers are
+    /// accepted where floats are expected since they widen losslessly.
+    pub fn accepts(self, value: &serde_cbor::Value) -> bool {
+        use serde_cbor::Value;
+
+        matches!(
+            (self, value),
+            (FieldType::Float, Value::Float(_))
+                | (FieldType::Float, Value::Integer(_))
+                | (FieldType::Integer, Value::Integer(_))
+                | (FieldType::Boolean, Value::Bool(_))
+                | (FieldType::Enumeration, Value::Integer(_))
+                | (FieldType::Enumeration, Value::Bool(_))
+        )
+    }
+}
+
+/// The state selected by a decoded CBOR value sent for an enumerated field
+pub fn enumeration_value(value: &serde_cbor::Value) -> Option<u32> {
+    use serde_cbor::Value;
+
+    match value {
+        Value::Integer(value) => u32::try_from(*value).ok(),
+        Value::Bool(value) => Some(*value as u32),
+        _ => None,
+    }
+}
+
+/// A human readable name for the type of a decoded CBOR value
+pub fn cbor_type_name(value: &serde_cbor::Value) -> &'static str {
+    use serde_cbor::Value;
+
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Integer(_) => "integer",
+        Value::Float(_) => "float",
+        Value::Bytes(_) => "bytes",
+        Value::Text(_) => "text",
+        Value::Array(_) => "array",
+        Value::Map(_) => "map",
+        Value::Tag(_, _) => "tag",
+        _ => "unknown",
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct DomainObjectTelemetry<'a> {
     values: Vec<ValueMetadata<'a>>,
 }
@@ -112,6 +324,16 @@ pub struct TelemetryEnumeration<'s> {
     string: &'s str,
 }
 
+impl<'s> TelemetryEnumeration<'s> {
+    pub const fn new(value: u32, string: &'s str) -> Self {
+        TelemetryEnumeration { value, string }
+    }
+
+    pub const fn value(&self) -> u32 {
+        self.value
+    }
+}
+
 /// Each telemetry value description has an object defining hints. Keys in this this object represent
 /// the hint itself, and the value represents the weight of that hint. A lower weight means the hint
 /// has a higher priority. For example, multiple values could be hinted for use as the y-axis of a plot
@@ -131,10 +353,14 @@ impl Default for ValueHint {
     }
 }
 
-const TELEMETRY_TYPE: &str = concatcp!(Identifier::NAMESPACE, ".telemetry");
+/// The field every packet carries its timestamp in
+pub const TELEMETRY_TIME_FIELD: PacketField<'static> =
+    PacketField::new("running_us", FieldType::Integer);
 
 // FIXME: less manual
 lazy_static::lazy_static! {
+    static ref TELEMETRY_TYPE: String = format!("{}.telemetry", Identifier::namespace());
+
     static ref TELEMETRY_TIME: ValueMetadata<'static> =  ValueMetadataBuilder::default()
         .hints(ValueHint::Domain(1))
         .key("uc_running_us")
@@ -143,100 +369,462 @@ lazy_static::lazy_static! {
         .min(0.0)
         .build()
         .unwrap();
-    pub static ref TELEMETRY_VALUES: Vec<DomainObject<'static>> = vec![
-        telemetry_domain_object("tvc.x", "TVC X Axis", ValueMetadataBuilder::default()
-            .format("float")
-            .units("degrees")
-            .min(-5.0)
-            .max(5.0)),
-        telemetry_domain_object("tvc.z", "TVC Z Axis", ValueMetadataBuilder::default()
-            .format("float")
-            .units("degrees")
-            .min(-5.0)
-            .max(5.0)),
-        telemetry_domain_object("tvc.angle", "TVC Angle [debug]", ValueMetadataBuilder::default()
-            .format("float")
-            .units("radians")
-            .min(0.0)
-            .max(std::f64::consts::PI * 2.0)),
-        telemetry_domain_object("proc.temp", "Processor Temperature", ValueMetadataBuilder::default()
-            .format("float")
-            .units("celsius")
-            .min(20.0)
-            .max(50.0)),
-        telemetry_domain_object("voltage.sys", "System Bus", ValueMetadataBuilder::default()
-            .format("float")
-            .units("volt")
-            .min(0.0)
-            .max(5.5)),
-        telemetry_domain_object("voltage.bat", "Battery", ValueMetadataBuilder::default()
-            .format("float")
-            .units("volt")
-            .min(0.0)
-            .max(20.0)),
-        telemetry_domain_object("proc.adc_offset", "ADC Offset", ValueMetadataBuilder::default()
-            .format("integer")
-            .min(0.0)
-            .max(100.0)),
-        telemetry_domain_object("usb.present", "USB Present", ValueMetadataBuilder::default()
-            .format("boolean"))
+    /// Every telemetry dictionary the server knows how to decode, in order of schema version
+    pub static ref TELEMETRY_DICTIONARIES: Vec<TelemetryDictionary> = vec![
+        TelemetryDictionary::new(1, core_measurements()),
+        TelemetryDictionary::new(2, core_measurements().into_iter().chain(vec![
+            enumerated_domain_object("flight.mode", "Flight Mode", PacketField::new("flight_mode", FieldType::Enumeration), FLIGHT_MODES),
+            enumerated_domain_object("pyro.1", "Pyro Channel 1", PacketField::new("pyro_1", FieldType::Enumeration), PYRO_STATES),
+            enumerated_domain_object("pyro.2", "Pyro Channel 2", PacketField::new("pyro_2", FieldType::Enumeration), PYRO_STATES),
+            enumerated_domain_object("proc.error", "Error Code", PacketField::new("error", FieldType::Enumeration), ERROR_CODES),
+        ]).collect()),
     ];
 }
 
-#[derive(Debug, Serialize, Deserialize, TS, Clone, Copy)]
+/// The schema version of firmware that predates the identification packet
+pub const UNIDENTIFIED_SCHEMA_VERSION: u32 = 1;
+
+static USB_STATES: &[TelemetryEnumeration<'static>] = &[
+    TelemetryEnumeration::new(0, "Unplugged"),
+    TelemetryEnumeration::new(1, "Plugged In"),
+];
+
+static FLIGHT_MODES: &[TelemetryEnumeration<'static>] = &[
+    TelemetryEnumeration::new(0, "Idle"),
+    TelemetryEnumeration::new(1, "Armed"),
+    TelemetryEnumeration::new(2, "Powered Ascent"),
+    TelemetryEnumeration::new(3, "Coast"),
+    TelemetryEnumeration::new(4, "Descent"),
+    TelemetryEnumeration::new(5, "Landed"),
+    TelemetryEnumeration::new(6, "Abort"),
+];
+
+static PYRO_STATES: &[TelemetryEnumeration<'static>] = &[
+    TelemetryEnumeration::new(0, "Safe"),
+    TelemetryEnumeration::new(1, "Armed"),
+    TelemetryEnumeration::new(2, "No Continuity"),
+    TelemetryEnumeration::new(3, "Fired"),
+];
+
+static ERROR_CODES: &[TelemetryEnumeration<'static>] = &[
+    TelemetryEnumeration::new(0, "None"),
+    TelemetryEnumeration::new(1, "IMU Failure"),
+    TelemetryEnumeration::new(2, "Barometer Failure"),
+    TelemetryEnumeration::new(3, "Brownout"),
+    TelemetryEnumeration::new(4, "Watchdog Reset"),
+    TelemetryEnumeration::new(5, "Telemetry Overrun"),
+];
+
+/// The telemetry points sent by every version of the firmware
+fn core_measurements() -> Vec<DomainObject<'static>> {
+    vec![
+        telemetry_domain_object(
+            "tvc.x",
+            "TVC X Axis",
+            PacketField::new("tvc_x", FieldType::Float),
+            ValueMetadataBuilder::default()
+                .units("degrees")
+                .min(-5.0)
+                .max(5.0),
+        ),
+        telemetry_domain_object(
+            "tvc.z",
+            "TVC Z Axis",
+            PacketField::new("tvc_z", FieldType::Float),
+            ValueMetadataBuilder::default()
+                .units("degrees")
+                .min(-5.0)
+                .max(5.0),
+        ),
+        telemetry_domain_object(
+            "tvc.angle",
+            "TVC Angle [debug]",
+            PacketField::new("angle", FieldType::Float),
+            ValueMetadataBuilder::default()
+                .units("radians")
+                .min(0.0)
+                .max(std::f64::consts::PI * 2.0),
+        ),
+        telemetry_domain_object(
+            "proc.temp",
+            "Processor Temperature",
+            PacketField::new("temperature", FieldType::Float),
+            ValueMetadataBuilder::default()
+                .units("celsius")
+                .min(20.0)
+                .max(50.0),
+        ),
+        telemetry_domain_object(
+            "voltage.sys",
+            "System Bus",
+            PacketField::new("v_sys", FieldType::Float),
+            ValueMetadataBuilder::default()
+                .units("volt")
+                .min(0.0)
+                .max(5.5),
+        ),
+        telemetry_domain_object(
+            "voltage.bat",
+            "Battery",
+            PacketField::new("v_bat", FieldType::Float),
+            ValueMetadataBuilder::default()
+                .units("volt")
+                .min(0.0)
+                .max(20.0),
+        ),
+        telemetry_domain_object(
+            "proc.adc_offset",
+            "ADC Offset",
+            PacketField::new("offset", FieldType::Integer),
+            ValueMetadataBuilder::default().min(0.0).max(100.0),
+        ),
+        enumerated_domain_object(
+            "usb.present",
+            "USB Present",
+            PacketField::new("v_bus_present", FieldType::Enumeration),
+            USB_STATES,
+        ),
+    ]
+}
+
+/// The set of telemetry points sent by a given version of the firmware's packet schema
+#[derive(Debug)]
+pub struct TelemetryDictionary {
+    /// the schema version reported by firmware that sends packets in this layout
+    pub schema_version: u32,
+    pub values: Vec<DomainObject<'static>>,
+}
+
+impl TelemetryDictionary {
+    /// Creates a dictionary from the telemetry points sent by the firmware, adding
+    /// every derived telemetry point that can be computed from them
+    fn new(schema_version: u32, mut values: Vec<DomainObject<'static>>) -> Self {
+        let mut available = values
+            .iter()
+            .map(|object| object.identifier.key)
+            .collect::<BTreeSet<_>>();
+
+        for definition in DERIVED_DEFINITIONS.iter() {
+            if dependencies_met(definition, &available) {
+                available.insert(definition.key.as_str());
+                values.push(derived_domain_object(definition));
+            }
+        }
+
+        TelemetryDictionary {
+            schema_version,
+            values,
+        }
+    }
+
+    /// The dictionary matching a firmware schema version, if the server knows it
+    pub fn for_schema(schema_version: u32) -> Option<&'static TelemetryDictionary> {
+        TELEMETRY_DICTIONARIES
+            .iter()
+            .find(|dictionary| dictionary.schema_version == schema_version)
+    }
+
+    /// The dictionary for firmware that does not identify itself
+    pub fn unidentified() -> &'static TelemetryDictionary {
+        Self::for_schema(UNIDENTIFIED_SCHEMA_VERSION)
+            .expect("a telemetry dictionary must be defined for unidentified firmware")
+    }
+
+    /// The newest dictionary, used when the firmware's schema version is unknown
+    pub fn latest() -> &'static TelemetryDictionary {
+        TELEMETRY_DICTIONARIES
+            .iter()
+            .max_by_key(|dictionary| dictionary.schema_version)
+            .expect("at least one telemetry dictionary must be defined")
+    }
+
+    pub fn composition(&self) -> Vec<&Identifier<'static>> {
+        self.values
+            .iter()
+            .map(|DomainObject { identifier, .. }| identifier)
+            .collect()
+    }
+
+    pub fn metadata(&self, identifier: Identifier) -> Option<&DomainObject<'static>> {
+        self.values
+            .iter()
+            .find(|object| object.identifier == identifier)
+    }
+
+    /// Decodes a raw packet using this dictionary's field layout. Fields that are
+    /// missing, of the wrong type or out of range are left out of the packet, as the
+    /// [`SchemaReport`](crate::schema::SchemaReport) keeps track of them.
+    pub fn decode(&self, packet: &serde_cbor::Value) -> Option<TelemetryPacket> {
+        use serde_cbor::Value;
+
+        let fields = match packet {
+            Value::Map(fields) => fields,
+            _ => return None,
+        };
+
+        let field = |name: &str| fields.get(&Value::Text(name.to_string()));
+
+        let running_us = match field(TELEMETRY_TIME_FIELD.name) {
+            Some(Value::Integer(running_us)) if *running_us >= 0 => *running_us as u64,
+            _ => return None,
+        };
+
+        let mut packet = TelemetryPacket {
+            running_us,
+            values: BTreeMap::new(),
+            raw: BTreeMap::new(),
+        };
+
+        for object in &self.values {
+            let packet_field = match object.field() {
+                Some(packet_field) => packet_field,
+                None => continue,
+            };
+
+            let value = match (packet_field.ty, field(packet_field.name)) {
+                (FieldType::Float, Some(Value::Float(value))) => TelemetryValue::Float(*value),
+                (FieldType::Float, Some(Value::Integer(value))) => {
+                    TelemetryValue::Float(*value as f64)
+                }
+                (FieldType::Integer, Some(Value::Integer(value))) => match i64::try_from(*value) {
+                    Ok(value) => TelemetryValue::Integer(value),
+                    Err(_) => continue,
+                },
+                (FieldType::Boolean, Some(Value::Bool(value))) => TelemetryValue::Boolean(*value),
+                (FieldType::Enumeration, Some(value)) => {
+                    let state = match enumeration_value(value) {
+                        Some(state) => state,
+                        None => continue,
+                    };
+
+                    let valid = object.enumerations().map_or(true, |enumerations| {
+                        enumerations
+                            .iter()
+                            .any(|enumeration| enumeration.value == state)
+                    });
+
+                    if !valid {
+                        continue;
+                    }
+
+                    TelemetryValue::Integer(state as i64)
+                }
+                _ => continue,
+            };
+
+            let key = object.identifier.key.to_string();
+
+            match (object.calibration(), value.as_f64()) {
+                (Some(definition), Some(raw)) => {
+                    packet.values.insert(
+                        key.clone(),
+                        TelemetryValue::Float(definition.calibration.apply(raw)),
+                    );
+                    packet.raw.insert(key, value);
+                }
+                _ => {
+                    packet.values.insert(key, value);
+                }
+            }
+        }
+
+        Some(packet)
+    }
+
+    /// Encodes a packet back into the raw layout it was decoded from, sending
+    /// calibrated values as the device sent them. Telemetry the device does not
+    /// send, such as derived telemetry, is left out.
+    pub fn encode(&self, packet: &TelemetryPacket) -> serde_cbor::Value {
+        use serde_cbor::Value;
+
+        let mut fields = BTreeMap::new();
+        fields.insert(
+            Value::Text(TELEMETRY_TIME_FIELD.name.to_string()),
+            Value::Integer(packet.running_us.into()),
+        );
+
+        for object in &self.values {
+            let packet_field = match object.field() {
+                Some(packet_field) => packet_field,
+                None => continue,
+            };
+
+            let key = object.identifier.key;
+            let value = match packet.raw.get(key).or_else(|| packet.values.get(key)) {
+                Some(TelemetryValue::Boolean(value)) => Value::Bool(*value),
+                Some(TelemetryValue::Integer(value)) => Value::Integer((*value).into()),
+                Some(TelemetryValue::Float(value)) => Value::Float(*value),
+                None => continue,
+            };
+
+            fields.insert(Value::Text(packet_field.name.to_string()), value);
+        }
+
+        Value::Map(fields)
+    }
+}
+
+/// A packet of telemetry, decoded according to a [`TelemetryDictionary`]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TelemetryPacket {
     pub running_us: u64,
-    #[serde(rename(serialize = "tvc.x"))]
-    pub tvc_x: f64,
-    #[serde(rename(serialize = "tvc.z"))]
-    pub tvc_z: f64,
-    #[serde(rename(serialize = "tvc.angle"))]
-    pub angle: f64,
-    #[serde(rename(serialize = "proc.temp"))]
-    pub temperature: f64,
-    #[serde(rename(serialize = "voltage.sys"))]
-    pub v_sys: f64,
-    #[serde(rename(serialize = "voltage.bat"))]
-    pub v_bat: f64,
-    #[serde(rename(serialize = "proc.adc_offset"))]
-    pub offset: u16,
-    #[serde(rename(serialize = "usb.present"))]
-    pub v_bus_present: bool,
-}
-
-export! {
-    (declare)  TelemetryPacket => "./web/types/generated/ingest.d.ts"
+    /// the telemetry values in this packet, keyed by their dictionary key
+    #[serde(flatten)]
+    pub values: BTreeMap<String, TelemetryValue>,
+    /// the values of calibrated telemetry points as they were sent by the device
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub raw: BTreeMap<String, TelemetryValue>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum TelemetryValue {
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+}
+
+impl TelemetryValue {
+    /// The value as a number, if it is numeric
+    pub fn as_f64(self) -> Option<f64> {
+        match self {
+            TelemetryValue::Boolean(_) => None,
+            TelemetryValue::Integer(value) => Some(value as f64),
+            TelemetryValue::Float(value) => Some(value),
+        }
+    }
 }
 
 fn telemetry_domain_object<'a>(
     key: &'a str,
     name: &'a str,
+    field: PacketField<'a>,
     value_metadata: &mut ValueMetadataBuilder<'a>,
 ) -> DomainObject<'a> {
+    let calibration = CALIBRATIONS.get(key);
+
+    let values = match calibration {
+        Some(definition) => {
+            let raw = value_metadata
+                .key("raw")
+                .name("Raw")
+                .hints(ValueHint::Range(2))
+                .format(field.ty.format())
+                .build()
+                .unwrap();
+
+            // A fresh builder, so that no raw bound is carried over untransformed
+            let mut engineering = ValueMetadataBuilder::default();
+            engineering
+                .key("value")
+                .name("Value")
+                .hints(ValueHint::Range(1))
+                .format(FieldType::Float.format());
+
+            if let Some(units) = definition.units.as_deref().or(raw.units) {
+                engineering.units(units);
+            }
+
+            // Assumes that calibrations are monotonic over the raw range, so a
+            // decreasing calibration swaps the bounds
+            let calibrate = |raw: f64| definition.calibration.apply(raw);
+            let bounds = match (raw.min.map(calibrate), raw.max.map(calibrate)) {
+                (Some(min), Some(max)) => (Some(min.min(max)), Some(min.max(max))),
+                (Some(bound), None) | (None, Some(bound)) => {
+                    let raw_bound = raw.min.or(raw.max).unwrap_or_default();
+                    let increasing = calibrate(raw_bound + 1.0) >= bound;
+
+                    if increasing == raw.min.is_some() {
+                        (Some(bound), None)
+                    } else {
+                        (None, Some(bound))
+                    }
+                }
+                (None, None) => (None, None),
+            };
+
+            if let Some(min) = bounds.0 {
+                engineering.min(min);
+            }
+
+            if let Some(max) = bounds.1 {
+                engineering.max(max);
+            }
+
+            vec![engineering.build().unwrap(), raw, *TELEMETRY_TIME]
+        }
+        None => vec![
+            value_metadata
+                .key("value")
+                .name("Value")
+                .format(field.ty.format())
+                .build()
+                .unwrap(),
+            *TELEMETRY_TIME,
+        ],
+    };
+
     DomainObject {
         composition: None,
         creator: None,
         identifier: Identifier::from_key(key),
-        location: concatcp!(Identifier::NAMESPACE, ":avionics"),
+        location: Subsystem::of_key(key).location(),
         modified: None,
-        ty: TELEMETRY_TYPE,
+        ty: &TELEMETRY_TYPE,
         name,
-        telemetry: Some(DomainObjectTelemetry::new(vec![
-            value_metadata.key("value").name("Value").build().unwrap(),
-            *TELEMETRY_TIME,
-        ])),
+        telemetry: Some(DomainObjectTelemetry::new(values)),
+        field: Some(field),
+        calibration,
     }
 }
 
-pub fn get_telemetry_composition() -> Vec<&'static Identifier<'static>> {
-    TELEMETRY_VALUES
-        .iter()
-        .map(|DomainObject { identifier, .. }| identifier)
-        .collect()
+fn enumerated_domain_object<'a>(
+    key: &'a str,
+    name: &'a str,
+    field: PacketField<'a>,
+    enumerations: &'a [TelemetryEnumeration<'a>],
+) -> DomainObject<'a> {
+    telemetry_domain_object(
+        key,
+        name,
+        field,
+        ValueMetadataBuilder::default().enumerations(enumerations),
+    )
 }
 
-pub fn get_telemetry_metadata(identifier: Identifier) -> Option<&DomainObject<'static>> {
-    TELEMETRY_VALUES
-        .iter()
-        .find(|object| object.identifier == identifier)
+fn derived_domain_object(definition: &DerivedDefinition) -> DomainObject {
+    let mut value_metadata = ValueMetadataBuilder::default();
+
+    value_metadata
+        .key("value")
+        .name("Value")
+        .format(FieldType::Float.format());
+
+    if let Some(units) = &definition.units {
+        value_metadata.units(units.as_str());
+    }
+
+    if let Some(min) = definition.min {
+        value_metadata.min(min);
+    }
+
+    if let Some(max) = definition.max {
+        value_metadata.max(max);
+    }
+
+    DomainObject {
+        composition: None,
+        creator: None,
+        identifier: Identifier::from_key(&definition.key),
+        location: Subsystem::of_key(&definition.key).location(),
+        modified: None,
+        ty: &TELEMETRY_TYPE,
+        name: &definition.name,
+        telemetry: Some(DomainObjectTelemetry::new(vec![
+            value_metadata.build().unwrap(),
+            *TELEMETRY_TIME,
+        ])),
+        field: None,
+        calibration: None,
+    }
 }
@@ -1,13 +1,23 @@
-import { telemetry_server } from "./constants.js";
+import { configure_namespace, telemetry_server } from "./constants.js";
 import { disconnect, refresh_port_listing } from "./ingest/connect.js";
 import { HistoricalTelemetryPlugin } from "./plugins/historical-telemetry.js";
 import { PicoPilotPlugin } from "./plugins/pico-pilot.js";
 import { RealtimeTelemetryPlugin } from "./plugins/realtime-telemetry.js";
 import { RunningUSTimeSystem } from "./plugins/running-us-time-system.js";
+import { ServerPersistencePlugin } from "./plugins/server-persistence.js";
 
 window.onload = async () => {
+    try {
+        /** @type {DomainObject} */
+        const root = await (await fetch(`${telemetry_server}/objects`)).json();
+
+        configure_namespace(root.identifier.namespace);
+    } catch (e) {
+        console.warn("Failed to fetch the server's namespace, using the default", e);
+    }
+
     openmct.setAssetPath("./openmct/");
-    openmct.install(openmct.plugins.LocalStorage());
+    openmct.install(ServerPersistencePlugin());
     openmct.install(openmct.plugins.MyItems());
     openmct.install(openmct.plugins.SummaryWidget());
     openmct.install(openmct.plugins.Espresso());
@@ -1,4 +1,20 @@
-export const namespace = "dusterthefirst.pico-pilot";
-export const telemetry_type = `${namespace}.telemetry`;
+// The namespace is configurable on the server, these are replaced by
+// `configure_namespace` before any plugin is installed
+export let namespace = "dusterthefirst.pico-pilot";
+export let telemetry_type = `${namespace}.telemetry`;
 
-export const telemetry_server = "http://localhost:13705";
+// The app is normally served by the ingest server itself. When served from
+// elsewhere, point it at the server with `?server=http://localhost:13705`
+export const telemetry_server =
+    new URLSearchParams(window.location.search).get("server") ??
+    window.location.origin;
+
+/**
+ * Adopts the namespace the server's objects live in
+ *
+ * @param {string} server_namespace
+ */
+export function configure_namespace(server_namespace) {
+    namespace = server_namespace;
+    telemetry_type = `${namespace}.telemetry`;
+}
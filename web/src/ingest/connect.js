@@ -12,8 +12,20 @@ let refresh_abort_controller = new AbortController();
  */
 export function disconnect(elements) {
     if (event_source !== undefined) {
+        // Closing the event source looks the same to the server as a dropped
+        // connection, which it keeps ingesting the device for
+        if (stream_token !== undefined) {
+            fetch(
+                `${telemetry_server}/devices/disconnect?stream=${encodeURIComponent(
+                    stream_token
+                )}`,
+                { method: "POST" }
+            ).catch(() => {});
+        }
+
         event_source.close();
         event_source = undefined;
+        stream_token = undefined;
 
         event_source_closed(elements);
     }
@@ -116,6 +128,12 @@ export async function refresh_port_listing(elements) {
 /** @type {EventSource | undefined} */
 let event_source;
 
+/**
+ * The token of the stream the server is sending, for disconnecting it
+ * @type {string | undefined}
+ */
+let stream_token;
+
 /**
  * @param {string} port
  * @param {PortControlElements} elements
@@ -135,6 +153,9 @@ export function subscribe_to_events(port, elements) {
     if (event_source !== undefined) {
         const sse = event_source;
 
+        /** @type {QueueStats | undefined} */
+        let queue;
+
         sse.addEventListener("open", () => {
             disconnect_button.style.display = "";
             connect_button.style.display = "none";
@@ -144,6 +165,9 @@ export function subscribe_to_events(port, elements) {
 
             dismiss();
         });
+        sse.addEventListener("stream", (event) => {
+            stream_token = event.data;
+        });
         sse.addEventListener("telemetry", (event) => {
             /** @type {TelemetryPacket} */
             const packet = JSON.parse(event.data);
@@ -152,8 +176,61 @@ export function subscribe_to_events(port, elements) {
 
             // console.log("recv", packet);
         });
+        sse.addEventListener("firmware", (event) => {
+            /** @type {FirmwareInfo} */
+            const firmware = JSON.parse(event.data);
+
+            indicator.text(`${port} (${firmware.name} ${firmware.version})`);
+        });
+        sse.addEventListener("queue", (event) => {
+            /** @type {QueueStats} */
+            queue = JSON.parse(event.data);
+        });
+        sse.addEventListener("link", (event) => {
+            /** @type {LinkStats} */
+            const stats = JSON.parse(event.data);
+
+            let description = `${stats.rate.toFixed(1)} packets/s, ${stats.malformed} malformed, ${stats.dropped} dropped, ${stats.reconnects} reconnects`;
+
+            // Only worth mentioning once this browser has fallen behind
+            if (queue !== undefined && (queue.dropped > 0 || queue.coalesced > 0)) {
+                description += `, ${queue.dropped} skipped and ${queue.coalesced} merged to keep up`;
+            }
+
+            indicator.description(description);
+        });
+        sse.addEventListener("schema", (event) => {
+            /** @type {SchemaReport} */
+            const report = JSON.parse(event.data);
+
+            if (!report.compatible) {
+                openmct.notifications.alert(
+                    `Device telemetry does not match the server's dictionary: ${describe_schema_report(report)}`
+                );
+            }
+        });
 
+        sse.addEventListener("end", () => {
+            sse.close();
+
+            event_source_closed(elements);
+        });
+        // The stream was resumed by a newer connection, which carries on with it
+        sse.addEventListener("superseded", () => {
+            sse.close();
+
+            event_source_closed(elements);
+        });
         sse.addEventListener("error", () => {
+            // The browser reconnects on its own, and the server replays what
+            // was missed since the last telemetry event
+            if (sse.readyState === EventSource.CONNECTING) {
+                indicator.text(`${port} (reconnecting)`);
+                indicator.statusClass("s-status-warning");
+
+                return;
+            }
+
             sse.close();
 
             event_source_closed(elements);
@@ -161,6 +238,39 @@ export function subscribe_to_events(port, elements) {
     }
 }
 
+/**
+ * @param {SchemaReport} report
+ */
+function describe_schema_report(report) {
+    const problems = [];
+
+    const missing = Object.keys(report.missing);
+    if (missing.length > 0) {
+        problems.push(`missing ${missing.join(", ")}`);
+    }
+
+    const extra = Object.keys(report.extra);
+    if (extra.length > 0) {
+        problems.push(`unexpected ${extra.join(", ")}`);
+    }
+
+    for (const key in report.mismatched) {
+        const mismatch = report.mismatched[key];
+
+        if (mismatch !== undefined) {
+            problems.push(
+                `${key} is ${mismatch.found}, expected ${mismatch.expected}`
+            );
+        }
+    }
+
+    if (report.malformed > 0) {
+        problems.push(`${report.malformed} malformed packets`);
+    }
+
+    return problems.join("; ");
+}
+
 /**
  * @param {PortControlElements} _
  */
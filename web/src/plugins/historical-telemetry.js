@@ -1,5 +1,8 @@
 import { telemetry_server, telemetry_type } from "../constants.js";
 
+/** The prefix of the keys of the difference between two sessions */
+const difference_prefix = "difference-";
+
 /** @returns {OpenMCTPlugin} */
 export function HistoricalTelemetryPlugin() {
     return (openmct) => {
@@ -7,17 +10,30 @@ export function HistoricalTelemetryPlugin() {
             supportsRequest: (domainObject) =>
                 domainObject.type === telemetry_type,
             request: async (domainObject, options) => {
+                const key = domainObject.identifier.key;
+
+                // Differences between sessions are not recorded, but worked
+                // out by the server as they are requested
+                if (key.startsWith(difference_prefix)) {
+                    return request_difference(openmct, key, options);
+                }
+
+                // Columns are much smaller than a datum per value, which adds
+                // up over long ranges
                 const response = await fetch(
-                    `${telemetry_server}/history/${domainObject.identifier.key}?start=${options.start}&end=${options.end}`
+                    `${telemetry_server}/history/${key}?start=${options.start}&end=${options.end}&layout=columns`
                 );
 
                 if (response.ok) {
-                    /** @type {TelemetryDatum[]} */
-                    const json = await response.json();
+                    /** @type {HistoryColumns} */
+                    const columns = await response.json();
 
-                    console.dir(json.length);
-
-                    return json;
+                    return columns.running_us.map((running_us, i) => ({
+                        id: columns.id,
+                        value: columns.value[i],
+                        raw: columns.raw?.[i] ?? undefined,
+                        running_us,
+                    }));
                 } else {
                     openmct.notifications.error(
                         `Failed to get telemetry history, Server returned: ${response.status}: ${response.statusText}`,
@@ -29,3 +45,27 @@ export function HistoricalTelemetryPlugin() {
         });
     };
 }
+
+/**
+ * Requests the difference between two sessions of a telemetry point, which the
+ * key of the object names
+ *
+ * @param {MCT} openmct
+ * @param {string} key
+ * @param {TelemetryRequest} options
+ */
+async function request_difference(openmct, key, options) {
+    const response = await fetch(
+        `${telemetry_server}/compare/difference/${encodeURIComponent(key)}?start=${options.start}&end=${options.end}`
+    );
+
+    if (response.ok) {
+        return await response.json();
+    } else {
+        openmct.notifications.error(
+            `Failed to get the difference between sessions, Server returned: ${response.status}: ${response.statusText}`,
+            { autoDismissTimeout: 10000 }
+        );
+        return [];
+    }
+}
@@ -49,6 +49,7 @@ export function push_telemetry(packet) {
                 fn({
                     id: key,
                     value: packet[key],
+                    raw: packet.raw?.[key],
                     running_us: packet.running_us,
                 });
             });
@@ -0,0 +1,102 @@
+import { telemetry_server } from "../constants.js";
+
+/**
+ * Stores user created objects (My Items, layouts, plots, ...) on the ingest
+ * server so that they are shared between everyone using it.
+ *
+ * @param {string} namespace the namespace of the objects to persist
+ * @returns {OpenMCTPlugin}
+ */
+export function ServerPersistencePlugin(namespace = "") {
+    return (openmct) => {
+        /** @param {Identifier} identifier */
+        const object_url = (identifier) =>
+            `${telemetry_server}/persistence/${encodeURIComponent(
+                openmct.objects.makeKeyString(identifier)
+            )}`;
+
+        /**
+         * @param {Response} response
+         * @param {DomainObject} domainObject
+         */
+        const check_response = async (response, domainObject) => {
+            if (response.status === 409) {
+                openmct.notifications.error(
+                    `"${domainObject.name}" was changed by someone else, reload to see their changes`
+                );
+
+                return false;
+            }
+
+            if (!response.ok) {
+                openmct.notifications.error(
+                    `Failed to save "${domainObject.name}", Server returned: ${response.status}: ${response.statusText}`
+                );
+
+                return false;
+            }
+
+            // Keep the revision up to date so the next save is not a conflict
+            const stored = await response.json();
+            domainObject._rev = stored._rev;
+
+            return true;
+        };
+
+        openmct.objects.addProvider(namespace, {
+            get: async (identifier) => {
+                const response = await fetch(object_url(identifier));
+
+                if (response.status === 404) {
+                    return undefined;
+                }
+
+                if (!response.ok) {
+                    throw new Error(
+                        `Failed to load object, Server returned: ${response.status}: ${response.statusText}`
+                    );
+                }
+
+                return await response.json();
+            },
+            create: async (domainObject) => {
+                const response = await fetch(
+                    object_url(domainObject.identifier),
+                    {
+                        method: "POST",
+                        headers: { "Content-Type": "application/json" },
+                        body: JSON.stringify(domainObject),
+                    }
+                );
+
+                return await check_response(response, domainObject);
+            },
+            update: async (domainObject) => {
+                const response = await fetch(
+                    object_url(domainObject.identifier),
+                    {
+                        method: "PUT",
+                        headers: { "Content-Type": "application/json" },
+                        body: JSON.stringify(domainObject),
+                    }
+                );
+
+                return await check_response(response, domainObject);
+            },
+            delete: async (domainObject) => {
+                // Objects that were never saved have no revision to check
+                const revision =
+                    domainObject._rev === undefined
+                        ? ""
+                        : `?rev=${domainObject._rev}`;
+
+                const response = await fetch(
+                    `${object_url(domainObject.identifier)}${revision}`,
+                    { method: "DELETE" }
+                );
+
+                return response.ok;
+            },
+        });
+    };
+}
@@ -1,3 +1,27 @@
 declare interface EventSourceEventMap {
     telemetry: MessageEvent<string>;
+    schema: MessageEvent<string>;
+    firmware: MessageEvent<string>;
+    link: MessageEvent<string>;
+    queue: MessageEvent<string>;
+    end: MessageEvent<string>;
 }
+
+declare type TelemetryValue = number | boolean;
+
+/** A packet of telemetry, keyed by the telemetry dictionary's keys */
+declare type TelemetryPacket = {
+    running_us: number;
+    /** Values of calibrated telemetry points as sent by the device */
+    raw?: { [key: string]: TelemetryValue };
+    [key: string]: TelemetryValue | { [key: string]: TelemetryValue } | undefined;
+};
+
+/** The history of a telemetry point, as `/history/:key?layout=columns` returns it */
+declare type HistoryColumns = {
+    id: string;
+    running_us: number[];
+    value: (TelemetryValue | null)[];
+    /** only present if the telemetry point has raw values */
+    raw?: (TelemetryValue | null)[];
+};
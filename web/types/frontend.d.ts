@@ -9,3 +9,8 @@ declare interface PortControlElements {
 declare type RealtimeTelemetrySubscribers = {
     [A in keyof TelemetryPacket]?: Set<(data: TelemetryDatum) => void>;
 };
+
+declare interface DomainObject {
+    /** the revision of objects persisted on the ingest server */
+    _rev?: number;
+}